@@ -0,0 +1,670 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+use crate::admin::AdminCommand;
+use crate::journal;
+use crate::ledger::EXCHANGE;
+use crate::marketdata;
+use crate::session::SessionTable;
+use crate::MThread;
+
+/// The funding policy of accounts that log on without being provisioned. There always is one,
+/// it can be redefined like any other.
+pub(crate) const DEFAULT_POLICY: &str = "default";
+
+// entitlements
+/// may send orders, without it an account only gets market data and its account updates
+pub(crate) const TRADE: u8 = 1;
+/// may send hidden orders, which an account also earns by providing liquidity
+pub(crate) const HIDDEN: u8 = 2;
+/// may move money to other accounts
+pub(crate) const TRANSFER: u8 = 4;
+
+const ENTITLEMENTS: &[(&str, u8)] = &[("trade", TRADE), ("hidden", HIDDEN), ("transfer", TRANSFER)];
+
+/// What limits can be set on, each caps a single movement of money.
+pub(crate) const LIMITS: &[&str] = &["deposit", "withdrawal", "transfer"];
+
+/// What an account starts out with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Policy {
+    pub money: f64,
+    pub position: isize,
+}
+
+/// What an account is there for, decides its entitlements unless it was provisioned with
+/// more.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Role {
+    /// market data only
+    Observer,
+    Trader,
+    MarketMaker,
+    /// run by the exchange itself, never gets the flag
+    House,
+}
+
+impl Role {
+    const ALL: [(Role, &'static str); 4] = [
+        (Role::Observer, "observer"),
+        (Role::Trader, "trader"),
+        (Role::MarketMaker, "market_maker"),
+        (Role::House, "house"),
+    ];
+
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .find(|(_, n)| *n == name)
+            .map(|(role, _)| *role)
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        // UNWRAP: every role is in there
+        Self::ALL.iter().find(|(role, _)| *role == self).unwrap().1
+    }
+
+    pub(crate) fn from_u8(v: u8) -> Option<Self> {
+        Self::ALL.get(v as usize).map(|(role, _)| *role)
+    }
+
+    pub(crate) fn to_u8(self) -> u8 {
+        // UNWRAP: every role is in there
+        Self::ALL
+            .iter()
+            .position(|(role, _)| *role == self)
+            .unwrap() as u8
+    }
+
+    /// The entitlements that come with the role.
+    pub(crate) fn entitlements(self) -> u8 {
+        match self {
+            Role::Observer => 0,
+            Role::Trader => TRADE,
+            Role::MarketMaker => TRADE | HIDDEN,
+            Role::House => TRADE | HIDDEN | TRANSFER,
+        }
+    }
+}
+
+/// Parses an entitlement by its name.
+pub(crate) fn entitlement(name: &str) -> Option<u8> {
+    ENTITLEMENTS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, e)| *e)
+}
+
+/// The names of the entitlements in `entitlements`.
+pub(crate) fn entitlement_names(entitlements: u8) -> Vec<&'static str> {
+    ENTITLEMENTS
+        .iter()
+        .filter(|(_, e)| entitlements & e != 0)
+        .map(|(n, _)| *n)
+        .collect()
+}
+
+/// A trading account. It is identified by the name it logs on with and outlives the
+/// connections that trade on it, a trader that reconnects finds its money and position where
+/// it left them.
+///
+/// The exchange lists a single instrument, `position`, `cost_basis` and `realized_pnl` are
+/// the account's in it. A position that didn't come from a fill (funding, adjustments) has
+/// no cost.
+#[derive(Debug)]
+pub(crate) struct Account {
+    /// the logon name, stable across reconnects
+    pub id: String,
+    /// the funding policy it was opened with
+    pub policy: String,
+    pub role: Role,
+    pub entitlements: u8,
+    pub money: f64,
+    /// earned hidden orders by providing liquidity
+    pub is_market_maker: bool,
+    pub net_liquidity_contribution: isize,
+    pub position: isize,
+    /// average price of the open position, 0 while flat
+    pub cost_basis: f64,
+    /// what closing positions made or lost against their cost basis
+    pub realized_pnl: f64,
+    /// cycles with at least one session
+    pub cycles_present: isize,
+    /// the exchange closed it, nobody logs on to it anymore
+    pub closed: bool,
+}
+
+impl Account {
+    pub fn new(id: &str, policy: (&str, Policy), role: Role, entitlements: u8) -> Self {
+        Self {
+            id: id.to_string(),
+            policy: policy.0.to_string(),
+            role,
+            entitlements,
+            money: policy.1.money,
+            is_market_maker: false,
+            net_liquidity_contribution: 0,
+            position: policy.1.position,
+            cost_basis: 0.0,
+            realized_pnl: 0.0,
+            cycles_present: 0,
+            closed: false,
+        }
+    }
+
+    /// Books a fill of `amount` (negative for a sale) at `price`. Buying into a long or
+    /// selling into a short averages the price into the cost basis, trading against the
+    /// position realizes the difference to it, whatever is left over opens a new position at
+    /// `price`.
+    pub fn fill(&mut self, amount: isize, price: f64) {
+        self.money -= amount as f64 * price;
+        if amount == 0 {
+            return;
+        }
+        let position = self.position + amount;
+        if self.position == 0 || self.position.signum() == amount.signum() {
+            self.cost_basis = (self.cost_basis * self.position.unsigned_abs() as f64
+                + price * amount.unsigned_abs() as f64)
+                / position.unsigned_abs() as f64;
+        } else {
+            let closed = amount.unsigned_abs().min(self.position.unsigned_abs()) as f64;
+            self.realized_pnl += closed * (price - self.cost_basis) * self.position.signum() as f64;
+            if position == 0 {
+                self.cost_basis = 0.0;
+            } else if position.signum() != self.position.signum() {
+                self.cost_basis = price;
+            }
+        }
+        self.position = position;
+    }
+
+    /// What the open position made or lost if it was closed at `mark`, nothing without one.
+    pub fn unrealized_pnl(&self, mark: Option<f64>) -> f64 {
+        mark.map_or(0.0, |mark| self.position as f64 * (mark - self.cost_basis))
+    }
+
+    /// Money plus the open position at `mark`, at its cost without one.
+    pub fn equity(&self, mark: Option<f64>) -> f64 {
+        self.money + self.position as f64 * mark.unwrap_or(self.cost_basis)
+    }
+
+    pub fn may_trade(&self) -> bool {
+        self.entitlements & TRADE != 0
+    }
+
+    pub fn may_hide(&self) -> bool {
+        self.is_market_maker || self.entitlements & HIDDEN != 0
+    }
+
+    pub fn may_transfer(&self) -> bool {
+        self.entitlements & TRANSFER != 0
+    }
+
+    /// `[0x21][money f64][net_liquidity_contribution isize][position isize][cost_basis f64]
+    /// [realized_pnl f64][unrealized_pnl f64][equity f64]`, the last two marked at `mark`.
+    pub fn to_bytes(&self, mark: Option<f64>) -> [u8; 57] {
+        let mut ret = [0; 57];
+
+        ret[0] = 0x21;
+
+        ret[1..9].copy_from_slice(&self.money.to_le_bytes()[..]);
+        ret[9..17].copy_from_slice(&self.net_liquidity_contribution.to_le_bytes()[..]);
+        ret[17..25].copy_from_slice(&self.position.to_le_bytes()[..]);
+        ret[25..33].copy_from_slice(&self.cost_basis.to_le_bytes()[..]);
+        ret[33..41].copy_from_slice(&self.realized_pnl.to_le_bytes()[..]);
+        ret[41..49].copy_from_slice(&self.unrealized_pnl(mark).to_le_bytes()[..]);
+        ret[49..57].copy_from_slice(&self.equity(mark).to_le_bytes()[..]);
+
+        ret
+    }
+}
+
+/// Money that moved other than through a trade. `None` is the exchange, money from it is a
+/// deposit, money to it a withdrawal.
+#[derive(Debug)]
+pub(crate) struct Transfer {
+    pub timestamp: u64,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub amount: f64,
+}
+
+/// `[0x22][amount f64][counterparty]`, what an account hears about a transfer. The amount is
+/// negative for money that left, the counterparty is `[len u8]` followed by its id.
+fn transfer_bytes(amount: f64, counterparty: &str) -> Vec<u8> {
+    let mut res = vec![0x22];
+    res.extend_from_slice(&amount.to_le_bytes()[..]);
+    res.push(counterparty.len() as u8);
+    res.extend_from_slice(counterparty.as_bytes());
+    res
+}
+
+/// Every account the engine knows and the sessions trading on them. A session is one live
+/// address of a logged on peer, whatever transport it came through, an account can have any
+/// number of them at once. Private traffic about an account (fills, account updates) goes to
+/// all of them, the responses to an order only to the one that sent it.
+///
+/// Accounts are provisioned up front with a funding policy, a role and entitlements, see
+/// `provisioning`. One that logs on without is opened as a trader with the default policy.
+#[derive(Debug)]
+pub(crate) struct AccountTable {
+    pub accounts: BTreeMap<String, Account>,
+    /// every live address and the id of the account it trades on
+    pub sessions: BTreeMap<SocketAddr, String>,
+    /// the funding policies by name
+    pub policies: BTreeMap<String, Policy>,
+    /// where open positions are marked, set at the end of every cycle
+    pub mark: Option<f64>,
+    /// the most a single deposit, withdrawal or transfer may move, no limit if there is none
+    pub limits: BTreeMap<String, f64>,
+    /// the transfers since the audit trail and the ledger last took them
+    pub transfers: Vec<Transfer>,
+}
+
+pub(crate) type Accounts = MThread<AccountTable>;
+
+impl Default for AccountTable {
+    fn default() -> Self {
+        let default = Policy {
+            money: 10000.0,
+            position: 0,
+        };
+        Self {
+            accounts: BTreeMap::new(),
+            sessions: BTreeMap::new(),
+            policies: [(DEFAULT_POLICY.to_string(), default)].into(),
+            mark: None,
+            limits: BTreeMap::new(),
+            transfers: Vec::new(),
+        }
+    }
+}
+
+impl AccountTable {
+    /// Starts a session of `addr` on the account `id`, opening the account if there is none.
+    /// `false` if the account is closed.
+    pub(crate) fn logon(&mut self, addr: SocketAddr, id: &str) -> bool {
+        if self.accounts.get(id).is_some_and(|account| account.closed) {
+            return false;
+        }
+        if !self.accounts.contains_key(id) {
+            // UNWRAP: the default policy can't go away
+            let policy = self.policies[DEFAULT_POLICY];
+            let account = Account::new(
+                id,
+                (DEFAULT_POLICY, policy),
+                Role::Trader,
+                Role::Trader.entitlements(),
+            );
+            self.accounts.insert(id.to_string(), account);
+        }
+        self.sessions.insert(addr, id.to_string());
+        true
+    }
+
+    /// Opens the account `id` funded by `policy` if there is none, gives it `role` and the
+    /// entitlements of the role plus `entitlements` either way. An account that exists keeps
+    /// its money and position.
+    pub(crate) fn provision(
+        &mut self,
+        id: &str,
+        policy: &str,
+        role: Role,
+        entitlements: u8,
+    ) -> Result<&Account, &'static str> {
+        let funding = *self.policies.get(policy).ok_or("no such policy")?;
+        let entitlements = role.entitlements() | entitlements;
+        let account = self
+            .accounts
+            .entry(id.to_string())
+            .or_insert_with(|| Account::new(id, (policy, funding), role, entitlements));
+        account.role = role;
+        account.entitlements = entitlements;
+        Ok(account)
+    }
+
+    /// Moves `amount` from the account `from` to the account `to`, `None` being the exchange.
+    /// Every session of both hears about it.
+    pub(crate) fn transfer(
+        &mut self,
+        out: &mut SessionTable,
+        from: Option<&str>,
+        to: Option<&str>,
+        amount: f64,
+    ) -> Result<(), &'static str> {
+        let kind = match (from, to) {
+            (None, Some(_)) => "deposit",
+            (Some(_), None) => "withdrawal",
+            (Some(from), Some(to)) if from != to => "transfer",
+            _ => return Err("money has to go somewhere else"),
+        };
+        if !(amount > 0.0 && amount.is_finite()) {
+            return Err("amount has to be positive");
+        }
+        if self.limits.get(kind).is_some_and(|max| amount > *max) {
+            return Err("amount is over the limit");
+        }
+        if [from, to]
+            .into_iter()
+            .flatten()
+            .any(|id| !self.accounts.contains_key(id))
+        {
+            return Err("no such account");
+        }
+        if from.is_some_and(|from| self.accounts[from].money < amount) {
+            return Err("not enough money");
+        }
+
+        for (id, amount, counterparty) in [(from, -amount, to), (to, amount, from)] {
+            if let Some(id) = id {
+                // UNWRAP: checked above
+                self.accounts.get_mut(id).unwrap().money += amount;
+                let msg = transfer_bytes(amount, counterparty.unwrap_or(EXCHANGE));
+                self.send(out, id, &msg);
+            }
+        }
+        self.transfers.push(Transfer {
+            timestamp: marketdata::now(),
+            from: from.map(str::to_string),
+            to: to.map(str::to_string),
+            amount,
+        });
+        Ok(())
+    }
+
+    /// Ends the session of `addr`, the account stays.
+    pub(crate) fn logout(&mut self, addr: &SocketAddr) {
+        self.sessions.remove(addr);
+    }
+
+    /// Ends every session on the account `id` and returns their addresses, the account stays.
+    pub(crate) fn logout_account(&mut self, id: &str) -> Vec<SocketAddr> {
+        let addrs = self.addrs(id);
+        self.sessions.retain(|_, account| account != id);
+        addrs
+    }
+
+    /// Ends every session, the engine restarted and none of their logons made it over.
+    pub(crate) fn logout_all(&mut self) {
+        self.sessions.clear();
+    }
+
+    /// The session of `from` moved to `to`.
+    pub(crate) fn rebind(&mut self, from: SocketAddr, to: SocketAddr) {
+        if let Some(id) = self.sessions.remove(&from) {
+            self.sessions.insert(to, id);
+        }
+    }
+
+    /// The id of the account `addr` trades on.
+    pub(crate) fn id(&self, addr: &SocketAddr) -> Option<String> {
+        self.sessions.get(addr).cloned()
+    }
+
+    /// The account `addr` trades on.
+    pub(crate) fn of(&self, addr: &SocketAddr) -> Option<&Account> {
+        self.accounts.get(self.sessions.get(addr)?)
+    }
+
+    /// Every session of the account `id`.
+    pub(crate) fn addrs(&self, id: &str) -> Vec<SocketAddr> {
+        self.sessions
+            .iter()
+            .filter(|(_, account)| *account == id)
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    /// Whether anyone trades on the account `id` right now.
+    pub(crate) fn present(&self, id: &str) -> bool {
+        self.sessions.values().any(|account| account == id)
+    }
+
+    /// Sends private traffic about the account `id` to every one of its sessions.
+    pub(crate) fn send(&self, out: &mut SessionTable, id: &str, payload: &[u8]) {
+        for addr in self.addrs(id) {
+            out.send(addr, payload);
+        }
+    }
+
+    /// Closes the account `id` for good. It stays as it is, provisioning and all, but its
+    /// sessions end and it can't be logged on to again. Returns the addresses of the sessions.
+    pub(crate) fn close(&mut self, id: &str) -> Vec<SocketAddr> {
+        if let Some(account) = self.accounts.get_mut(id) {
+            account.closed = true;
+        }
+        self.logout_account(id)
+    }
+}
+
+/// Reads the `provisioning` file into the admin commands that set it up, the engine runs
+/// them at startup like any other. One entry per line:
+///
+/// ```text
+/// policy default money 10000
+/// policy house money 1000000000 position 0
+/// account bot house house
+/// account alice default trader hidden
+/// limit transfer 1000
+/// ```
+///
+/// A policy gives the money and the position an account starts with, `position` is optional.
+/// An account is `account <id> <policy> <role> [entitlement ...]`, roles are `observer`,
+/// `trader`, `market_maker` and `house`, entitlements `trade`, `hidden` and `transfer` on top
+/// of the ones of the role. Policies have to come before the accounts that use them. A limit
+/// caps every single `deposit`, `withdrawal` or `transfer`.
+pub(crate) fn provisioning(config: &str) -> Option<Vec<AdminCommand>> {
+    let mut commands = Vec::new();
+    for line in config
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.iter().any(|word| word.len() > journal::MAX_STR) {
+            return None;
+        }
+        let command = match words.as_slice() {
+            ["policy", name, "money", money, rest @ ..] => {
+                let position = match rest {
+                    [] => 0,
+                    ["position", position] => position.parse().ok()?,
+                    _ => return None,
+                };
+                AdminCommand::Policy {
+                    name: name.to_string(),
+                    money: money.parse().ok()?,
+                    position,
+                }
+            }
+            ["account", id, policy, role, entitlements @ ..] => AdminCommand::Provision {
+                account: id.to_string(),
+                policy: policy.to_string(),
+                role: Role::parse(role)?,
+                entitlements: entitlements
+                    .iter()
+                    .try_fold(0, |all, name| Some(all | entitlement(name)?))?,
+            },
+            ["limit", kind, max] if LIMITS.contains(kind) => AdminCommand::Limit {
+                kind: kind.to_string(),
+                max: Some(max.parse().ok()?),
+            },
+            _ => return None,
+        };
+        commands.push(command);
+    }
+    Some(commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trader(money: f64) -> Account {
+        let policy = Policy { money, position: 0 };
+        Account::new(
+            "alice",
+            (DEFAULT_POLICY, policy),
+            Role::Trader,
+            Role::Trader.entitlements(),
+        )
+    }
+
+    #[test]
+    fn fills_average_into_the_cost_basis() {
+        let mut account = trader(10000.0);
+        account.fill(10, 100.0);
+        account.fill(10, 110.0);
+        assert_eq!(account.position, 20);
+        assert_eq!(account.cost_basis, 105.0);
+        assert_eq!(account.money, 7900.0);
+        assert_eq!(account.realized_pnl, 0.0);
+
+        assert_eq!(account.unrealized_pnl(Some(110.0)), 100.0);
+        assert_eq!(account.unrealized_pnl(None), 0.0);
+        assert_eq!(account.equity(Some(110.0)), 10100.0);
+        assert_eq!(account.equity(None), 10000.0);
+    }
+
+    #[test]
+    fn fills_against_the_position_realize() {
+        let mut account = trader(10000.0);
+        account.fill(20, 105.0);
+
+        // part of the long
+        account.fill(-5, 120.0);
+        assert_eq!((account.position, account.cost_basis), (15, 105.0));
+        assert_eq!(account.realized_pnl, 75.0);
+
+        // through it into a short, opened at the fill price
+        account.fill(-20, 90.0);
+        assert_eq!((account.position, account.cost_basis), (-5, 90.0));
+        assert_eq!(account.realized_pnl, 75.0 - 225.0);
+        assert_eq!(account.unrealized_pnl(Some(100.0)), -50.0);
+
+        // covering the short makes money below the basis
+        account.fill(5, 80.0);
+        assert_eq!((account.position, account.cost_basis), (0, 0.0));
+        assert_eq!(account.realized_pnl, -150.0 + 50.0);
+        assert_eq!(account.money, 10000.0 + account.realized_pnl);
+
+        account.fill(0, 80.0);
+        assert_eq!(account.money, 9900.0);
+    }
+
+    #[test]
+    fn positions_without_fills_have_no_cost() {
+        let mut account = trader(10000.0);
+        account.position = 10;
+        assert_eq!(account.unrealized_pnl(Some(50.0)), 500.0);
+        account.fill(-4, 50.0);
+        assert_eq!(account.realized_pnl, 200.0);
+        assert_eq!(account.cost_basis, 0.0);
+    }
+
+    fn table() -> AccountTable {
+        let mut accounts = AccountTable::default();
+        accounts.logon(SocketAddr::from(([127, 0, 0, 1], 4000)), "alice");
+        accounts.logon(SocketAddr::from(([127, 0, 0, 1], 4001)), "bob");
+        accounts
+    }
+
+    #[test]
+    fn closed_accounts_stay_closed() {
+        let mut accounts = table();
+        accounts
+            .provision("bot", DEFAULT_POLICY, Role::Observer, 0)
+            .unwrap();
+        accounts.logon(SocketAddr::from(([127, 0, 0, 1], 4002)), "bot");
+        accounts.accounts.get_mut("bot").unwrap().money = 5.0;
+
+        let addrs = accounts.close("bot");
+        assert_eq!(addrs, [SocketAddr::from(([127, 0, 0, 1], 4002))]);
+        assert!(!accounts.present("bot"));
+        assert!(!accounts.logon(SocketAddr::from(([127, 0, 0, 1], 4003)), "bot"));
+        assert!(!accounts.present("bot"));
+
+        let bot = &accounts.accounts["bot"];
+        assert!(bot.closed);
+        assert_eq!((bot.role, bot.money), (Role::Observer, 5.0));
+    }
+
+    #[test]
+    fn transfers_stay_within_the_limits() {
+        let mut accounts = table();
+        let mut out = SessionTable::offline();
+        accounts.limits.insert("transfer".to_string(), 100.0);
+        accounts.limits.insert("withdrawal".to_string(), 50.0);
+
+        let over = accounts.transfer(&mut out, Some("alice"), Some("bob"), 100.5);
+        assert_eq!(over, Err("amount is over the limit"));
+        assert!(accounts
+            .transfer(&mut out, Some("alice"), Some("bob"), 100.0)
+            .is_ok());
+        let over = accounts.transfer(&mut out, Some("bob"), None, 60.0);
+        assert_eq!(over, Err("amount is over the limit"));
+        // deposits aren't limited
+        assert!(accounts.transfer(&mut out, None, Some("bob"), 1e6).is_ok());
+
+        assert_eq!(accounts.accounts["alice"].money, 9900.0);
+        assert_eq!(accounts.accounts["bob"].money, 10100.0 + 1e6);
+        let moved: Vec<_> = accounts
+            .transfers
+            .iter()
+            .map(|t| (t.from.as_deref(), t.to.as_deref(), t.amount))
+            .collect();
+        assert_eq!(
+            moved,
+            [
+                (Some("alice"), Some("bob"), 100.0),
+                (None, Some("bob"), 1e6)
+            ]
+        );
+    }
+
+    #[test]
+    fn transfers_that_cant_happen() {
+        let mut accounts = table();
+        let mut out = SessionTable::offline();
+        let mut transfer = |from, to, amount| accounts.transfer(&mut out, from, to, amount);
+
+        assert_eq!(
+            transfer(Some("alice"), Some("alice"), 1.0),
+            Err("money has to go somewhere else")
+        );
+        assert_eq!(
+            transfer(None, None, 1.0),
+            Err("money has to go somewhere else")
+        );
+        for amount in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert_eq!(
+                transfer(Some("alice"), Some("bob"), amount),
+                Err("amount has to be positive")
+            );
+        }
+        assert_eq!(
+            transfer(Some("alice"), Some("carol"), 1.0),
+            Err("no such account")
+        );
+        assert_eq!(
+            transfer(Some("alice"), Some("bob"), 10000.5),
+            Err("not enough money")
+        );
+        assert!(accounts.transfers.is_empty());
+        assert_eq!(accounts.accounts["alice"].money, 10000.0);
+    }
+
+    #[test]
+    fn limits_from_provisioning() {
+        let commands = provisioning("limit transfer 250\nlimit deposit 1e6\n").unwrap();
+        assert!(matches!(
+            &commands[..],
+            [
+                AdminCommand::Limit { kind, max: Some(max) },
+                AdminCommand::Limit { .. },
+            ] if kind == "transfer" && *max == 250.0
+        ));
+        assert!(provisioning("limit fee 1").is_none());
+        assert!(provisioning("limit transfer lots").is_none());
+    }
+}
@@ -0,0 +1,527 @@
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Sender};
+use std::time::Duration;
+
+use crate::account::{self, Account, AccountTable, Accounts, Policy, Role};
+use crate::audit::Audit;
+use crate::crypto;
+use crate::http::{self, Request};
+use crate::journal;
+use crate::json::Json;
+use crate::logon::Logons;
+use crate::session::SessionTable;
+use crate::FLOATING_TO_FIXED_OFF;
+use crate::{BookEntry, CnclResponse, Order, OrderBook, OrderResponse};
+
+/// Only reachable from the box itself, on top of the token.
+pub(crate) const ADMIN_ADDR: &str = "127.0.0.1:14554";
+
+/// How long a request waits for the engine, a cycle boundary can hold it up for a while.
+/// Requests that take longer are answered with 202, the command still runs and its outcome
+/// is in the audit log under the returned correlation id.
+const ENGINE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The part of the admin api that needs the order book or changes accounts, run by the engine
+/// between orders so it ends up in the journal in the order it happened.
+#[derive(Debug)]
+pub(crate) enum AdminCommand {
+    Book,
+    Cancel(isize),
+    Halt,
+    Resume,
+    Adjust {
+        account: String,
+        money: f64,
+        position: isize,
+    },
+    /// logs out every session on the account, the account and what it holds stay
+    Kick(String),
+    /// defines or redefines a funding policy, accounts opened with it before keep what they got
+    Policy {
+        name: String,
+        money: f64,
+        position: isize,
+    },
+    /// opens a funded account or changes the role and entitlements of one
+    Provision {
+        account: String,
+        policy: String,
+        role: Role,
+        /// on top of the ones of the role
+        entitlements: u8,
+    },
+    /// sets the limit of one of `account::LIMITS`, `None` lifts it
+    Limit {
+        kind: String,
+        max: Option<f64>,
+    },
+    /// a deposit, or a withdrawal if `amount` is negative
+    Cash {
+        account: String,
+        amount: f64,
+    },
+}
+
+/// Runs `cmd` on the engine side. Canceled orders are reported to their owner with the
+/// unsolicited form of the cancel response, which carries the order id.
+pub(crate) fn execute(
+    cmd: AdminCommand,
+    order_book: &mut OrderBook,
+    accounts: &Accounts,
+    halted: &mut bool,
+    out: &mut SessionTable,
+) -> (u16, Json) {
+    match cmd {
+        AdminCommand::Book => (200, book_json(order_book, *halted)),
+        AdminCommand::Cancel(order_id) => match order_book.remove(order_id) {
+            Some(entry) => {
+                let res = OrderResponse::Cncl(CnclResponse {
+                    cancled: true,
+                    order_id,
+                });
+                accounts.get().send(out, &entry.account, &res.to_bytes());
+                (200, entry_json(&entry))
+            }
+            None => (404, error("no such order")),
+        },
+        AdminCommand::Halt | AdminCommand::Resume => {
+            *halted = matches!(cmd, AdminCommand::Halt);
+            (200, Json::object().with("halted", *halted))
+        }
+        AdminCommand::Adjust {
+            account,
+            money,
+            position,
+        } => {
+            let mut lock = accounts.get();
+            match lock.accounts.get_mut(&account) {
+                Some(a) => {
+                    a.money += money;
+                    a.position += position;
+                    (200, account_json(&lock, &lock.accounts[&account]))
+                }
+                None => (404, error("no such account")),
+            }
+        }
+        AdminCommand::Kick(account) => {
+            let mut lock = accounts.get();
+            if !lock.accounts.contains_key(&account) {
+                return (404, error("no such account"));
+            }
+            for addr in lock.logout_account(&account) {
+                out.send(addr, &[0x69]);
+                out.forget(&addr);
+            }
+            (200, account_json(&lock, &lock.accounts[&account]))
+        }
+        AdminCommand::Policy {
+            name,
+            money,
+            position,
+        } => {
+            let policy = Policy { money, position };
+            accounts.get().policies.insert(name.clone(), policy);
+            (200, policy_json(&name, &policy))
+        }
+        AdminCommand::Provision {
+            account,
+            policy,
+            role,
+            entitlements,
+        } => {
+            let mut lock = accounts.get();
+            match lock.provision(&account, &policy, role, entitlements) {
+                Ok(_) => (200, account_json(&lock, &lock.accounts[&account])),
+                Err(reason) => (404, error(reason)),
+            }
+        }
+        AdminCommand::Limit { kind, max } => {
+            let mut lock = accounts.get();
+            match max {
+                Some(max) => lock.limits.insert(kind, max),
+                None => lock.limits.remove(&kind),
+            };
+            (200, limits_json(&lock))
+        }
+        AdminCommand::Cash { account, amount } => {
+            let mut lock = accounts.get();
+            if !lock.accounts.contains_key(&account) {
+                return (404, error("no such account"));
+            }
+            let moved = if amount < 0.0 {
+                lock.transfer(out, Some(&account), None, -amount)
+            } else {
+                lock.transfer(out, None, Some(&account), amount)
+            };
+            match moved {
+                Ok(()) => (200, account_json(&lock, &lock.accounts[&account])),
+                Err(reason) => (400, error(reason)),
+            }
+        }
+    }
+}
+
+fn error(reason: &str) -> Json {
+    Json::object().with("error", reason)
+}
+
+pub(crate) fn account_json(accounts: &AccountTable, account: &Account) -> Json {
+    let entitlements: Vec<Json> = account::entitlement_names(account.entitlements)
+        .into_iter()
+        .map(Json::from)
+        .collect();
+    let sessions: Vec<Json> = accounts
+        .addrs(&account.id)
+        .iter()
+        .map(|addr| addr.to_string().into())
+        .collect();
+    Json::object()
+        .with("id", account.id.as_str())
+        .with("policy", account.policy.as_str())
+        .with("role", account.role.name())
+        .with("entitlements", entitlements)
+        .with("sessions", sessions)
+        .with("money", account.money)
+        .with("position", account.position)
+        .with("cost_basis", account.cost_basis)
+        .with("realized_pnl", account.realized_pnl)
+        .with("unrealized_pnl", account.unrealized_pnl(accounts.mark))
+        .with("equity", account.equity(accounts.mark))
+        .with("is_market_maker", account.is_market_maker)
+        .with(
+            "net_liquidity_contribution",
+            account.net_liquidity_contribution,
+        )
+        .with("cycles_present", account.cycles_present)
+        .with("closed", account.closed)
+}
+
+/// Every account the engine knows, whether someone trades on it right now or not.
+pub(crate) fn accounts_json(accounts: &AccountTable) -> Json {
+    Json::Array(
+        accounts
+            .accounts
+            .values()
+            .map(|account| account_json(accounts, account))
+            .collect(),
+    )
+}
+
+fn policy_json(name: &str, policy: &Policy) -> Json {
+    Json::object()
+        .with("name", name)
+        .with("money", policy.money)
+        .with("position", policy.position)
+}
+
+pub(crate) fn policies_json(accounts: &AccountTable) -> Json {
+    Json::Array(
+        accounts
+            .policies
+            .iter()
+            .map(|(name, policy)| policy_json(name, policy))
+            .collect(),
+    )
+}
+
+pub(crate) fn limits_json(accounts: &AccountTable) -> Json {
+    account::LIMITS.iter().fold(Json::object(), |limits, kind| {
+        limits.with(kind, accounts.limits.get(*kind).copied())
+    })
+}
+
+fn entry_json(entry: &BookEntry) -> Json {
+    Json::object()
+        .with("order_id", entry.id)
+        .with("account", entry.account.as_str())
+        .with("price", (entry.id >> 24) as f64 / FLOATING_TO_FIXED_OFF)
+        .with("amount", entry.amount)
+        .with("cycles_present", entry.cycles_present)
+}
+
+pub(crate) fn book_json(order_book: &OrderBook, halted: bool) -> Json {
+    let side = |levels: &mut dyn Iterator<Item = (&isize, &Vec<BookEntry>)>| {
+        Json::Array(
+            levels
+                .filter(|(_, entries)| !entries.is_empty())
+                .map(|(price, entries)| {
+                    Json::object()
+                        .with("price", *price as f64 / FLOATING_TO_FIXED_OFF)
+                        .with("orders", entries.iter().map(entry_json).collect::<Vec<_>>())
+                })
+                .collect(),
+        )
+    };
+
+    Json::object()
+        .with("halted", halted)
+        .with("bids", side(&mut order_book.bids.iter().rev()))
+        .with("asks", side(&mut order_book.asks.iter()))
+}
+
+/// HTTP admin api, every request needs `Authorization: Bearer <token>`.
+///
+/// - `GET /accounts`
+/// - `POST /accounts/<id>/adjust` with `{"money": 10.5, "position": -3}`, both deltas
+/// - `POST /accounts/<id>/deposit` and `POST /accounts/<id>/withdraw` with `{"amount": 100}`,
+///   within the limits and a withdrawal only of what the account has
+/// - `PUT /accounts/<id>` with `{"policy": "default", "role": "trader", "entitlements":
+///   ["hidden"]}` opens the account funded by the policy, or changes the role and
+///   entitlements of one that is open already. `policy` defaults to `default`, `role` to
+///   `trader`, `entitlements` are on top of the role's
+/// - `DELETE /accounts/<id>/sessions` kicks every session on the account, the account stays
+/// - `GET /policies`
+/// - `PUT /policies/<name>` with `{"money": 10000, "position": 0}`, `position` is optional
+/// - `GET /limits`
+/// - `PUT /limits/<deposit|withdrawal|transfer>` with `{"max": 1000}`, `DELETE` lifts it
+/// - `GET /book`
+/// - `DELETE /orders/<order id>`
+/// - `POST /halt` and `POST /resume`, while halted new orders are rejected and nothing
+///   crosses, cancels still go through
+pub(crate) fn listen(
+    listener: TcpListener,
+    token: String,
+    logons: Logons,
+    accounts: Accounts,
+    audit: Audit,
+    order_sender: Sender<(SocketAddr, Order, u64)>,
+) {
+    for stream in listener.incoming().flatten() {
+        let token = token.clone();
+        let logons = logons.clone();
+        let accounts = accounts.clone();
+        let (audit, order_sender) = (audit.clone(), order_sender.clone());
+        std::thread::spawn(move || {
+            connection(stream, &token, logons, accounts, audit, order_sender)
+        });
+    }
+}
+
+fn authorized(req: &Request, token: &str) -> bool {
+    let presented = req
+        .header("authorization")
+        .and_then(|auth| auth.strip_prefix("Bearer "))
+        .unwrap_or("");
+    // compare the MACs, so the comparison takes the same time whatever the prefix
+    crypto::verify(
+        &crypto::hmac(b"admin", &[presented.as_bytes()]),
+        &crypto::hmac(b"admin", &[token.as_bytes()]),
+    )
+}
+
+fn connection(
+    stream: TcpStream,
+    token: &str,
+    logons: Logons,
+    accounts: Accounts,
+    audit: Audit,
+    order_sender: Sender<(SocketAddr, Order, u64)>,
+) {
+    let addr = match stream.peer_addr() {
+        Ok(addr) => addr,
+        Err(_) => return,
+    };
+    stream.set_read_timeout(Some(ENGINE_TIMEOUT));
+    let mut reader = BufReader::new(stream);
+
+    // the token stays out of the audit trail, the request line and body are enough
+    let req = Request::read(&mut reader);
+    let logged = req.as_ref().map_or(Vec::new(), |req| {
+        let mut logged = format!("{} {} ", req.method, req.path).into_bytes();
+        logged.extend_from_slice(&req.body);
+        logged
+    });
+    let id = audit.get().inbound("admin", addr, &logged);
+
+    let (status, body) = match req {
+        None => {
+            audit.get().dropped(id, "malformed request");
+            (400, error("malformed request"))
+        }
+        Some(req) if !authorized(&req, token) || token.is_empty() => {
+            audit.get().dropped(id, "missing or wrong admin token");
+            (401, error("missing or wrong admin token"))
+        }
+        Some(req) => route(&req, addr, &logons, &accounts, &order_sender, id),
+    };
+
+    let reason = match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        _ => "Service Unavailable",
+    };
+    let body = format!("{}\n", body);
+    let response = format!("{} {}", status, body);
+    audit
+        .get()
+        .outbound(id, "admin", addr, response.trim_end().as_bytes());
+    http::respond(
+        reader.get_mut(),
+        status,
+        reason,
+        "application/json",
+        body.as_bytes(),
+    );
+}
+
+fn route(
+    req: &Request,
+    addr: SocketAddr,
+    logons: &Logons,
+    accounts: &Accounts,
+    order_sender: &Sender<(SocketAddr, Order, u64)>,
+    id: u64,
+) -> (u16, Json) {
+    let path: Vec<&str> = req.path.split('/').filter(|p| !p.is_empty()).collect();
+    if path.iter().any(|p| p.len() > journal::MAX_STR) {
+        return (400, error("names are at most 255 bytes"));
+    }
+
+    let engine = |cmd: AdminCommand| {
+        let (reply, response) = channel();
+        if order_sender
+            .send((addr, Order::Admin(cmd, reply), id))
+            .is_err()
+        {
+            return (503, error("engine is gone"));
+        }
+        // the command is queued already and runs once the engine gets to it, answering with
+        // an error would invite a retry that applies it twice
+        response.recv_timeout(ENGINE_TIMEOUT).unwrap_or_else(|_| {
+            let pending = Json::object()
+                .with("status", "pending")
+                .with("request", id.to_string());
+            (202, pending)
+        })
+    };
+
+    match (req.method.as_str(), path.as_slice()) {
+        ("GET", ["accounts"]) => (200, accounts_json(&accounts.get())),
+        ("POST", ["accounts", account, "adjust"]) => {
+            let adjust = match std::str::from_utf8(&req.body).ok().and_then(Json::parse) {
+                Some(adjust) => adjust,
+                None => return (400, error("body has to be a JSON object")),
+            };
+            let money = adjust.get("money").map(Json::as_f64);
+            let position = adjust.get("position").map(Json::as_isize);
+            if money == Some(None) || position == Some(None) {
+                return (400, error("money has to be a number, position an integer"));
+            }
+
+            engine(AdminCommand::Adjust {
+                account: account.to_string(),
+                money: money.flatten().unwrap_or(0.0),
+                position: position.flatten().unwrap_or(0),
+            })
+        }
+        ("PUT", ["accounts", account]) => {
+            let provision = match std::str::from_utf8(&req.body).ok().and_then(Json::parse) {
+                Some(provision) => provision,
+                None => return (400, error("body has to be a JSON object")),
+            };
+            let text = |key: &str, default: &'static str| match provision.get(key) {
+                Some(value) => value.as_str().map(str::to_string),
+                None => Some(default.to_string()),
+            };
+            let policy = match text("policy", account::DEFAULT_POLICY) {
+                Some(policy) if policy.len() <= journal::MAX_STR => policy,
+                _ => return (400, error("policy has to be a string of at most 255 bytes")),
+            };
+            let role = match text("role", "trader").as_deref().and_then(Role::parse) {
+                Some(role) => role,
+                None => return (400, error("unknown role")),
+            };
+            let entitlements = match provision.get("entitlements") {
+                None => Some(0),
+                Some(Json::Array(names)) => names.iter().try_fold(0, |all, name| {
+                    Some(all | name.as_str().and_then(account::entitlement)?)
+                }),
+                Some(_) => None,
+            };
+            let entitlements = match entitlements {
+                Some(entitlements) => entitlements,
+                None => return (400, error("unknown entitlement")),
+            };
+
+            engine(AdminCommand::Provision {
+                account: account.to_string(),
+                policy,
+                role,
+                entitlements,
+            })
+        }
+        ("POST", ["accounts", account, kind @ ("deposit" | "withdraw")]) => {
+            let cash = match std::str::from_utf8(&req.body).ok().and_then(Json::parse) {
+                Some(cash) => cash,
+                None => return (400, error("body has to be a JSON object")),
+            };
+            let amount = match cash.get("amount").and_then(Json::as_f64) {
+                Some(amount) if amount > 0.0 => amount,
+                _ => return (400, error("amount has to be a positive number")),
+            };
+
+            engine(AdminCommand::Cash {
+                account: account.to_string(),
+                amount: if *kind == "deposit" { amount } else { -amount },
+            })
+        }
+        ("DELETE", ["accounts", account, "sessions"]) => {
+            let kicked = engine(AdminCommand::Kick(account.to_string()));
+            // a slow engine still gets to the kick, the logons have to end either way
+            logons.get().end_account(account);
+            kicked
+        }
+        ("GET", ["policies"]) => (200, policies_json(&accounts.get())),
+        ("PUT", ["policies", name]) => {
+            let policy = match std::str::from_utf8(&req.body).ok().and_then(Json::parse) {
+                Some(policy) => policy,
+                None => return (400, error("body has to be a JSON object")),
+            };
+            let money = policy.get("money").and_then(Json::as_f64);
+            let position = policy.get("position").map(Json::as_isize);
+            let (money, position) = match (money, position) {
+                (Some(money), Some(Some(position))) => (money, position),
+                (Some(money), None) => (money, 0),
+                _ => return (400, error("money has to be a number, position an integer")),
+            };
+
+            engine(AdminCommand::Policy {
+                name: name.to_string(),
+                money,
+                position,
+            })
+        }
+        ("GET", ["limits"]) => (200, limits_json(&accounts.get())),
+        (method @ ("PUT" | "DELETE"), ["limits", kind]) if account::LIMITS.contains(kind) => {
+            let max = if method == "PUT" {
+                let limit = match std::str::from_utf8(&req.body).ok().and_then(Json::parse) {
+                    Some(limit) => limit,
+                    None => return (400, error("body has to be a JSON object")),
+                };
+                match limit.get("max").and_then(Json::as_f64) {
+                    Some(max) if max >= 0.0 => Some(max),
+                    _ => return (400, error("max has to be a number")),
+                }
+            } else {
+                None
+            };
+
+            engine(AdminCommand::Limit {
+                kind: kind.to_string(),
+                max,
+            })
+        }
+        ("GET", ["book"]) => engine(AdminCommand::Book),
+        ("DELETE", ["orders", order_id]) => match order_id.parse() {
+            Ok(order_id) => engine(AdminCommand::Cancel(order_id)),
+            Err(_) => (400, error("bad order id")),
+        },
+        ("POST", ["halt"]) => engine(AdminCommand::Halt),
+        ("POST", ["resume"]) => engine(AdminCommand::Resume),
+        _ => (404, error("not found")),
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+
+use crate::account::{AccountTable, Transfer};
+use crate::ledger::EXCHANGE;
+use crate::marketdata::{self, BookEvent};
+use crate::{MThread, Order};
+
+/// Where the audit trail goes, next to the journal.
+pub(crate) const AUDIT: &str = "audit";
+
+/// Transports whose messages are text, everything else is logged as hex.
+const TEXT: &[&str] = &["fix", "ws", "admin", "config"];
+
+/// Append-only trail of everything that goes in and out of the exchange and of what the
+/// engine did in between, for post-mortems. One line per entry:
+///
+/// ```text
+/// <timestamp> <correlation id> IN <transport> <addr> <message>
+/// <timestamp> <correlation id> DROP <reason>
+/// <timestamp> <correlation id> CLOSED <transport> <addr>
+/// <timestamp> <correlation id> ORDER <addr> <order>
+/// <timestamp> <correlation id> EVENT <book event>
+/// <timestamp> <correlation id> TRANSFER <from account> <to account> <amount>
+/// <timestamp> <correlation id> BALANCE <account> <money> -> <money> <position> -> <position>
+/// <timestamp> <correlation id> CYCLE <expired addrs>
+/// <timestamp> <correlation id> OUT <transport> <addr> <message>
+/// ```
+///
+/// Timestamps are ns since the epoch. Every inbound message, a closed connection and every
+/// cycle get a correlation id of their own, it follows the message through the engine to the
+/// book events, balance changes and outbound messages it caused. Market data a throttle held
+/// back goes out under 0. Binary messages are hex, text ones quoted. Book events are the ones
+/// of the order events feed with fixed point prices, an account that appears or goes away has
+/// `-` for its balance. Deposits come from and withdrawals go to `exchange`. A rejected order
+/// is the reject message that went out for it.
+#[derive(Debug)]
+pub(crate) struct AuditLog {
+    file: File,
+    /// the last correlation id handed out
+    last: u64,
+}
+
+pub(crate) type Audit = MThread<AuditLog>;
+
+/// Money and position of every account, to tell what changed.
+pub(crate) type Balances = BTreeMap<String, (f64, isize)>;
+
+pub(crate) fn balances(accounts: &AccountTable) -> Balances {
+    accounts
+        .accounts
+        .iter()
+        .map(|(id, account)| (id.clone(), (account.money, account.position)))
+        .collect()
+}
+
+fn render(transport: &str, msg: &[u8]) -> String {
+    if TEXT.contains(&transport) {
+        format!("{:?}", String::from_utf8_lossy(msg))
+    } else {
+        msg.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl AuditLog {
+    pub(crate) fn open(path: &str) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file, last: 0 })
+    }
+
+    fn write(&mut self, id: u64, entry: &str) {
+        let line = format!("{} {} {}\n", marketdata::now(), id, entry);
+        // UNWRAP: an exchange that can't account for what it did shouldn't go on
+        self.file.write_all(line.as_bytes()).unwrap();
+    }
+
+    /// A fresh correlation id.
+    pub(crate) fn correlate(&mut self) -> u64 {
+        self.last += 1;
+        self.last
+    }
+
+    /// Logs a message as it came in, before anything looked at it. Returns its correlation id.
+    pub(crate) fn inbound(&mut self, transport: &str, addr: SocketAddr, msg: &[u8]) -> u64 {
+        let id = self.correlate();
+        let entry = format!("IN {} {} {}", transport, addr, render(transport, msg));
+        self.write(id, &entry);
+        id
+    }
+
+    /// The message `id` went no further.
+    pub(crate) fn dropped(&mut self, id: u64, reason: &str) {
+        self.write(id, &format!("DROP {}", reason));
+    }
+
+    /// The connection of `addr` is gone. Returns the correlation id of what follows from it.
+    pub(crate) fn closed(&mut self, transport: &str, addr: SocketAddr) -> u64 {
+        let id = self.correlate();
+        self.write(id, &format!("CLOSED {} {}", transport, addr));
+        id
+    }
+
+    pub(crate) fn outbound(&mut self, id: u64, transport: &str, addr: SocketAddr, msg: &[u8]) {
+        let entry = format!("OUT {} {} {}", transport, addr, render(transport, msg));
+        self.write(id, &entry);
+    }
+
+    /// The engine took `order` off its queue.
+    pub(crate) fn order(&mut self, id: u64, addr: SocketAddr, order: &Order) {
+        self.write(id, &format!("ORDER {} {:?}", addr, order));
+    }
+
+    /// The end of a cycle. Returns the correlation id of everything the cycle does.
+    pub(crate) fn cycle(&mut self, expired: &[SocketAddr]) -> u64 {
+        let id = self.correlate();
+        self.write(id, &format!("CYCLE {:?}", expired));
+        id
+    }
+
+    /// What the book did to individual orders.
+    pub(crate) fn events(&mut self, id: u64, events: &[(u64, BookEvent)]) {
+        for (_, event) in events {
+            self.write(id, &format!("EVENT {:?}", event));
+        }
+    }
+
+    /// Money that moved other than through a trade.
+    pub(crate) fn transfers(&mut self, id: u64, transfers: &[Transfer]) {
+        for transfer in transfers {
+            let entry = format!(
+                "TRANSFER {} {} {}",
+                transfer.from.as_deref().unwrap_or(EXCHANGE),
+                transfer.to.as_deref().unwrap_or(EXCHANGE),
+                transfer.amount
+            );
+            self.write(id, &entry);
+        }
+    }
+
+    /// Every account whose money or position differs from `before`.
+    pub(crate) fn balances(&mut self, id: u64, before: &Balances, accounts: &AccountTable) {
+        let after = balances(accounts);
+        let fmt = |balance: Option<&(f64, isize)>| {
+            balance.map_or(("-".to_string(), "-".to_string()), |(money, position)| {
+                (money.to_string(), position.to_string())
+            })
+        };
+        for account in before
+            .keys()
+            .chain(after.keys().filter(|a| !before.contains_key(*a)))
+        {
+            let (old, new) = (before.get(account), after.get(account));
+            if old == new {
+                continue;
+            }
+            let ((old_money, old_position), (new_money, new_position)) = (fmt(old), fmt(new));
+            let entry = format!(
+                "BALANCE {} {} -> {} {} -> {}",
+                account, old_money, new_money, old_position, new_position
+            );
+            self.write(id, &entry);
+        }
+    }
+}
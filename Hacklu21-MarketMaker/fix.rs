@@ -0,0 +1,1587 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::account::Accounts;
+use crate::audit::Audit;
+use crate::logon::Logons;
+use crate::session::{Sessions, StreamMsg};
+use crate::tcp::{Slot, LOGON_TIMEOUT};
+use crate::{accept_logon, parse_order, MThread, Order};
+
+pub(crate) const FIX_ADDR: &str = "0.0.0.0:14552";
+
+const BEGIN_STRING: &str = "FIX.4.4";
+/// our `SenderCompID`, counterparties have to address us with it as `TargetCompID`
+const COMP_ID: &str = "MARKETMAKER";
+/// the only instrument traded on this exchange
+const SYMBOL: &str = "MM";
+/// sequence numbers and sent messages of every counterparty live in here
+const STORE_DIR: &str = "fix";
+const MAX_MESSAGE: usize = 4096;
+
+const TAG_AVG_PX: u32 = 6;
+const TAG_BEGIN_SEQ_NO: u32 = 7;
+const TAG_CL_ORD_ID: u32 = 11;
+const TAG_CUM_QTY: u32 = 14;
+const TAG_END_SEQ_NO: u32 = 16;
+const TAG_EXEC_ID: u32 = 17;
+const TAG_LAST_PX: u32 = 31;
+const TAG_LAST_QTY: u32 = 32;
+const TAG_MSG_SEQ_NUM: u32 = 34;
+const TAG_MSG_TYPE: u32 = 35;
+const TAG_NEW_SEQ_NO: u32 = 36;
+const TAG_ORDER_ID: u32 = 37;
+const TAG_ORDER_QTY: u32 = 38;
+const TAG_ORD_STATUS: u32 = 39;
+const TAG_ORD_TYPE: u32 = 40;
+const TAG_ORIG_CL_ORD_ID: u32 = 41;
+const TAG_POSS_DUP_FLAG: u32 = 43;
+const TAG_PRICE: u32 = 44;
+const TAG_SENDER_COMP_ID: u32 = 49;
+const TAG_SENDING_TIME: u32 = 52;
+const TAG_SIDE: u32 = 54;
+const TAG_SYMBOL: u32 = 55;
+const TAG_TARGET_COMP_ID: u32 = 56;
+const TAG_TEXT: u32 = 58;
+const TAG_ENCRYPT_METHOD: u32 = 98;
+const TAG_HEART_BT_INT: u32 = 108;
+const TAG_TEST_REQ_ID: u32 = 112;
+const TAG_ORIG_SENDING_TIME: u32 = 122;
+const TAG_GAP_FILL_FLAG: u32 = 123;
+const TAG_RESET_SEQ_NUM_FLAG: u32 = 141;
+const TAG_EXEC_TYPE: u32 = 150;
+const TAG_LEAVES_QTY: u32 = 151;
+const TAG_CXL_REJ_RESPONSE_TO: u32 = 434;
+const TAG_USERNAME: u32 = 553;
+const TAG_PASSWORD: u32 = 554;
+
+const MSG_HEARTBEAT: &str = "0";
+const MSG_TEST_REQUEST: &str = "1";
+const MSG_RESEND_REQUEST: &str = "2";
+const MSG_REJECT: &str = "3";
+const MSG_SEQUENCE_RESET: &str = "4";
+const MSG_LOGOUT: &str = "5";
+const MSG_EXECUTION_REPORT: &str = "8";
+const MSG_ORDER_CANCEL_REJECT: &str = "9";
+const MSG_LOGON: &str = "A";
+const MSG_NEW_ORDER_SINGLE: &str = "D";
+const MSG_ORDER_CANCEL_REQUEST: &str = "F";
+const MSG_ORDER_CANCEL_REPLACE_REQUEST: &str = "G";
+
+/// Session level messages, these are replaced by a gap fill when the counterparty asks for a
+/// resend.
+const ADMIN_MESSAGES: [&str; 7] = [
+    MSG_HEARTBEAT,
+    MSG_TEST_REQUEST,
+    MSG_RESEND_REQUEST,
+    MSG_REJECT,
+    MSG_SEQUENCE_RESET,
+    MSG_LOGOUT,
+    MSG_LOGON,
+];
+
+#[derive(Debug, Clone)]
+struct Message {
+    msg_type: String,
+    /// everything but `BeginString(8)`, `BodyLength(9)`, `MsgType(35)` and `CheckSum(10)`
+    fields: Vec<(u32, String)>,
+}
+
+impl Message {
+    fn new(msg_type: &str) -> Self {
+        Self {
+            msg_type: msg_type.to_string(),
+            fields: Vec::new(),
+        }
+    }
+
+    fn get(&self, tag: u32) -> Option<&str> {
+        self.fields
+            .iter()
+            .find_map(|(t, v)| (*t == tag).then_some(v.as_str()))
+    }
+
+    fn set(&mut self, tag: u32, value: impl ToString) -> &mut Self {
+        let value = value.to_string();
+        match self.fields.iter_mut().find(|(t, _)| *t == tag) {
+            Some((_, v)) => *v = value,
+            None => self.fields.push((tag, value)),
+        }
+        self
+    }
+
+    fn seq(&self) -> Option<u64> {
+        self.get(TAG_MSG_SEQ_NUM)?.parse().ok()
+    }
+
+    /// Parses a complete message, checking `BeginString`, `BodyLength` and `CheckSum`.
+    fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let checksum_at = buf.len().checked_sub(7)?;
+        let sum = buf[..checksum_at].iter().map(|b| *b as u32).sum::<u32>() % 256;
+        if buf[checksum_at..] != *format!("10={:03}\x01", sum).as_bytes() {
+            return None;
+        }
+
+        let text = std::str::from_utf8(&buf[..checksum_at]).ok()?;
+        let mut fields = text.split_terminator('\x01').map(|field| {
+            let (tag, value) = field.split_once('=')?;
+            Some((tag.parse::<u32>().ok()?, value.to_string()))
+        });
+
+        if fields.next()?? != (8, BEGIN_STRING.to_string()) {
+            return None;
+        }
+        let (tag, len) = fields.next()??;
+        let body_at = text.find("\x0135=")? + 1;
+        if tag != 9 || len.parse::<usize>().ok()? != checksum_at - body_at {
+            return None;
+        }
+        let (tag, msg_type) = fields.next()??;
+        if tag != TAG_MSG_TYPE {
+            return None;
+        }
+
+        Some(Self {
+            msg_type,
+            fields: fields.collect::<Option<_>>()?,
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut body = format!("35={}\x01", self.msg_type);
+        for (tag, value) in &self.fields {
+            body.push_str(&format!("{}={}\x01", tag, value));
+        }
+
+        let mut msg = format!("8={}\x019={}\x01{}", BEGIN_STRING, body.len(), body).into_bytes();
+        let sum = msg.iter().map(|b| *b as u32).sum::<u32>() % 256;
+        msg.extend_from_slice(format!("10={:03}\x01", sum).as_bytes());
+        msg
+    }
+}
+
+/// What of a raw message goes into the audit trail, the `Password` stays out of it.
+fn redacted(raw: &[u8]) -> Vec<u8> {
+    let password = format!("{}=", TAG_PASSWORD);
+    raw.split_inclusive(|b| *b == b'\x01')
+        .flat_map(|field| {
+            if field.starts_with(password.as_bytes()) {
+                format!("{}***\x01", password).into_bytes()
+            } else {
+                field.to_vec()
+            }
+        })
+        .collect()
+}
+
+/// Reads the raw bytes of one message: `8=...`, `9=<len>`, `<len>` bytes of body and the
+/// 7 byte checksum field.
+fn read_message(stream: &mut impl BufRead) -> std::io::Result<Vec<u8>> {
+    let mut raw = Vec::new();
+    for _ in 0..2 {
+        let read = stream.take(32).read_until(b'\x01', &mut raw)?;
+        if read == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+    }
+
+    let header = String::from_utf8_lossy(&raw).to_string();
+    let len = header
+        .split_terminator('\x01')
+        .nth(1)
+        .and_then(|f| f.strip_prefix("9="))
+        .and_then(|len| len.parse::<usize>().ok())
+        .filter(|len| *len < MAX_MESSAGE)
+        .ok_or(ErrorKind::InvalidData)?;
+
+    let at = raw.len();
+    raw.resize(at + len + 7, 0);
+    stream.read_exact(&mut raw[at..])?;
+    Ok(raw)
+}
+
+/// `YYYYMMDD-HH:MM:SS.sss` in UTC.
+fn utc_timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+
+    // civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}{:02}{:02}-{:02}:{:02}:{:02}.{:03}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        now.subsec_millis()
+    )
+}
+
+/// Comp ids name the files of the sequence store, anything that isn't safe in a file name is
+/// refused instead of mapped so two comp ids never share a store.
+fn valid_comp_id(comp_id: &str) -> bool {
+    !comp_id.is_empty()
+        && comp_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Sequence numbers and every message we sent to one counterparty, kept on disk so a session
+/// survives reconnects and restarts. Both are synced before a message goes out, whatever the
+/// counterparty saw can be resent after a crash.
+#[derive(Debug)]
+struct SeqStore {
+    seqs: PathBuf,
+    messages: File,
+    /// where in `messages` each of them is and how long it is, by `MsgSeqNum`
+    index: BTreeMap<u64, (u64, usize)>,
+    /// next `MsgSeqNum` we expect from the counterparty
+    next_in: u64,
+    /// next `MsgSeqNum` we send
+    next_out: u64,
+}
+
+impl SeqStore {
+    /// The store in `dir`, `STORE_DIR` but for tests. `comp_id` has to pass [`valid_comp_id`],
+    /// it's used as the file name as is.
+    fn open(dir: &str, comp_id: &str) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let dir = PathBuf::from(dir);
+        let seqs = dir.join(format!("{}.seqs", comp_id));
+        let messages = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(dir.join(format!("{}.messages", comp_id)))?;
+
+        let mut index = BTreeMap::new();
+        let mut at = 0;
+        for raw in BufReader::new(&messages).split(b'\n') {
+            let raw = raw?;
+            if let Some(seq) = Message::from_bytes(&raw).and_then(|msg| msg.seq()) {
+                index.insert(seq, (at, raw.len()));
+            }
+            at += raw.len() as u64 + 1;
+        }
+
+        let (next_in, next_out) = std::fs::read_to_string(&seqs)
+            .ok()
+            .and_then(|s| {
+                let (a, b) = s.trim().split_once(' ')?;
+                Some((a.parse().ok()?, b.parse().ok()?))
+            })
+            .unwrap_or((1, 1));
+
+        Ok(Self {
+            seqs,
+            messages,
+            index,
+            next_in,
+            next_out,
+        })
+    }
+
+    fn persist(&self) -> std::io::Result<()> {
+        let tmp = self.seqs.with_extension("seqs.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(format!("{} {}\n", self.next_in, self.next_out).as_bytes())?;
+        file.sync_data()?;
+        std::fs::rename(&tmp, &self.seqs)
+    }
+
+    fn reset(&mut self) -> std::io::Result<()> {
+        self.next_in = 1;
+        self.next_out = 1;
+        self.messages.set_len(0)?;
+        self.index.clear();
+        self.persist()
+    }
+
+    /// Messages are stored one per line, FIX messages can't contain a newline.
+    fn record(&mut self, seq: u64, raw: &[u8]) -> std::io::Result<()> {
+        let at = self.messages.metadata()?.len();
+        let mut line = raw.to_vec();
+        line.push(b'\n');
+        self.messages.write_all(&line)?;
+        self.messages.sync_data()?;
+        self.index.insert(seq, (at, raw.len()));
+        Ok(())
+    }
+
+    fn sent(&self, from: u64, to: u64) -> Vec<Message> {
+        if from > to {
+            return Vec::new();
+        }
+        let mut file = &self.messages;
+        self.index
+            .range(from..=to)
+            .filter_map(|(_, &(at, len))| {
+                let mut raw = vec![0; len];
+                file.seek(SeekFrom::Start(at)).ok()?;
+                file.read_exact(&mut raw).ok()?;
+                Message::from_bytes(&raw)
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Side {
+    Buy,
+    Sell,
+}
+
+impl Side {
+    fn from_fix(side: &str) -> Option<Self> {
+        match side {
+            "1" => Some(Side::Buy),
+            "2" => Some(Side::Sell),
+            _ => None,
+        }
+    }
+
+    fn to_fix(self) -> &'static str {
+        match self {
+            Side::Buy => "1",
+            Side::Sell => "2",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct FixOrder {
+    cl_ord_id: String,
+    side: Side,
+    qty: isize,
+    /// `None` for market orders
+    price: Option<f64>,
+    cum_qty: isize,
+    notional: f64,
+}
+
+impl FixOrder {
+    fn leaves(&self) -> isize {
+        self.qty - self.cum_qty
+    }
+
+    fn avg_px(&self) -> f64 {
+        if self.cum_qty == 0 {
+            0.0
+        } else {
+            self.notional / self.cum_qty as f64
+        }
+    }
+}
+
+#[derive(Debug)]
+enum PendingKind {
+    New(FixOrder),
+    Cancel {
+        orig_cl_ord_id: String,
+        order_id: isize,
+    },
+    /// `order` already carries the quantity filled on the original order
+    Replace {
+        orig_cl_ord_id: String,
+        order_id: isize,
+        order: FixOrder,
+    },
+}
+
+/// An order submitted to the engine whose responses haven't all arrived yet. The engine works
+/// through the orders of a connection in submission order and delimits the responses to each
+/// of them with `StreamMsg::Processed`, so the oldest pending order is always the one a
+/// response belongs to.
+#[derive(Debug)]
+struct Pending {
+    kind: PendingKind,
+    failed: bool,
+}
+
+/// State of one logged on FIX connection, shared by its reader and writer thread.
+#[derive(Debug)]
+struct FixSession {
+    stream: TcpStream,
+    store: SeqStore,
+    target_comp_id: String,
+    addr: SocketAddr,
+    order_sender: Sender<(SocketAddr, Order, u64)>,
+    audit: Audit,
+    /// correlation id of what is being sent, see `AuditLog`
+    correlation: u64,
+    pending: VecDeque<Pending>,
+    /// resting limit orders by engine order id
+    orders: BTreeMap<isize, FixOrder>,
+    exec_id: u64,
+    last_sent: Instant,
+}
+
+impl FixSession {
+    fn write(&mut self, raw: &[u8]) -> std::io::Result<()> {
+        self.audit
+            .get()
+            .outbound(self.correlation, "fix", self.addr, raw);
+        self.stream.write_all(raw)
+    }
+
+    fn send(&mut self, msg: &Message) -> std::io::Result<()> {
+        let seq = self.store.next_out;
+        self.store.next_out += 1;
+
+        let mut full = Message::new(&msg.msg_type);
+        full.set(TAG_SENDER_COMP_ID, COMP_ID)
+            .set(TAG_TARGET_COMP_ID, &self.target_comp_id)
+            .set(TAG_MSG_SEQ_NUM, seq)
+            .set(TAG_SENDING_TIME, utc_timestamp());
+        full.fields.extend(msg.fields.iter().cloned());
+
+        let raw = full.to_bytes();
+        self.store.record(seq, &raw)?;
+        self.store.persist()?;
+        self.last_sent = Instant::now();
+        self.write(&raw)
+    }
+
+    /// Answers a `ResendRequest`, application messages are sent again as possible duplicates,
+    /// session level messages are skipped with a gap fill.
+    fn resend(&mut self, from: u64, to: u64) -> std::io::Result<()> {
+        let to = if to == 0 {
+            self.store.next_out - 1
+        } else {
+            to.min(self.store.next_out - 1)
+        };
+
+        let mut gap_from = None;
+        for msg in self.store.sent(from, to) {
+            let seq = msg.seq().unwrap_or_default();
+            if ADMIN_MESSAGES.contains(&msg.msg_type.as_str()) {
+                gap_from.get_or_insert(seq);
+                continue;
+            }
+
+            if let Some(gap_from) = gap_from.take() {
+                self.gap_fill(gap_from, seq)?;
+            }
+
+            // both flags belong to the header, so they go in before the body fields
+            let orig = msg.get(TAG_SENDING_TIME).unwrap_or_default().to_string();
+            let mut dup = Message::new(&msg.msg_type);
+            dup.set(TAG_SENDER_COMP_ID, COMP_ID)
+                .set(TAG_TARGET_COMP_ID, &self.target_comp_id)
+                .set(TAG_MSG_SEQ_NUM, seq)
+                .set(TAG_POSS_DUP_FLAG, "Y")
+                .set(TAG_SENDING_TIME, utc_timestamp())
+                .set(TAG_ORIG_SENDING_TIME, orig);
+            let body: Vec<_> = msg
+                .fields
+                .into_iter()
+                .filter(|(tag, _)| dup.get(*tag).is_none())
+                .collect();
+            dup.fields.extend(body);
+            self.write(&dup.to_bytes())?;
+        }
+
+        if let Some(gap_from) = gap_from {
+            self.gap_fill(gap_from, to + 1)?;
+        }
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    fn gap_fill(&mut self, seq: u64, new_seq: u64) -> std::io::Result<()> {
+        let mut msg = Message::new(MSG_SEQUENCE_RESET);
+        msg.set(TAG_SENDER_COMP_ID, COMP_ID)
+            .set(TAG_TARGET_COMP_ID, &self.target_comp_id)
+            .set(TAG_MSG_SEQ_NUM, seq)
+            .set(TAG_POSS_DUP_FLAG, "Y")
+            .set(TAG_SENDING_TIME, utc_timestamp())
+            .set(TAG_GAP_FILL_FLAG, "Y")
+            .set(TAG_NEW_SEQ_NO, new_seq);
+        self.write(&msg.to_bytes())
+    }
+
+    fn next_exec_id(&mut self) -> String {
+        self.exec_id += 1;
+        format!("{}-{}", self.store.next_out, self.exec_id)
+    }
+
+    fn execution_report(
+        &mut self,
+        order_id: Option<isize>,
+        order: &FixOrder,
+        exec_type: &str,
+        ord_status: &str,
+    ) -> Message {
+        let mut msg = Message::new(MSG_EXECUTION_REPORT);
+        msg.set(
+            TAG_ORDER_ID,
+            order_id.map_or("NONE".to_string(), |id| id.to_string()),
+        )
+        .set(TAG_CL_ORD_ID, &order.cl_ord_id)
+        .set(TAG_EXEC_ID, self.next_exec_id())
+        .set(TAG_EXEC_TYPE, exec_type)
+        .set(TAG_ORD_STATUS, ord_status)
+        .set(TAG_SYMBOL, SYMBOL)
+        .set(TAG_SIDE, order.side.to_fix())
+        .set(TAG_ORDER_QTY, order.qty)
+        .set(TAG_ORD_TYPE, if order.price.is_some() { "2" } else { "1" });
+        if let Some(price) = order.price {
+            msg.set(TAG_PRICE, price);
+        }
+        msg.set(TAG_LEAVES_QTY, order.leaves())
+            .set(TAG_CUM_QTY, order.cum_qty)
+            .set(TAG_AVG_PX, order.avg_px());
+        msg
+    }
+
+    fn fill_report(
+        &mut self,
+        order_id: Option<isize>,
+        order: &FixOrder,
+        qty: isize,
+        price: f64,
+    ) -> std::io::Result<()> {
+        let status = if order.leaves() == 0 { "2" } else { "1" };
+        let mut msg = self.execution_report(order_id, order, "F", status);
+        msg.set(TAG_LAST_QTY, qty).set(TAG_LAST_PX, price);
+        self.send(&msg)
+    }
+
+    fn cancel_reject(
+        &mut self,
+        cl_ord_id: &str,
+        orig_cl_ord_id: &str,
+        order_id: Option<isize>,
+        response_to: &str,
+        text: &str,
+    ) -> std::io::Result<()> {
+        let mut msg = Message::new(MSG_ORDER_CANCEL_REJECT);
+        msg.set(
+            TAG_ORDER_ID,
+            order_id.map_or("NONE".to_string(), |id| id.to_string()),
+        )
+        .set(TAG_CL_ORD_ID, cl_ord_id)
+        .set(TAG_ORIG_CL_ORD_ID, orig_cl_ord_id)
+        .set(TAG_ORD_STATUS, "8")
+        .set(TAG_CXL_REJ_RESPONSE_TO, response_to)
+        .set(TAG_TEXT, text);
+        self.send(&msg)
+    }
+
+    fn reject(&mut self, order: &FixOrder, text: &str) -> std::io::Result<()> {
+        let mut msg = self.execution_report(None, order, "8", "8");
+        msg.set(TAG_LEAVES_QTY, 0).set(TAG_TEXT, text);
+        self.send(&msg)
+    }
+
+    /// Translates one message of the binary protocol the engine sent to this peer.
+    /// Returns false if the engine dropped the peer.
+    fn on_engine(&mut self, data: &[u8]) -> std::io::Result<bool> {
+        match data.first() {
+            Some(0x01) if data.len() >= 18 => {
+                let a = isize::from_le_bytes(data[2..10].try_into().unwrap());
+                match data[1] {
+                    0 => self.on_accepted(a)?,
+                    1 => self.on_unsolicited_cancel(isize::from_le_bytes(
+                        data[3..11].try_into().unwrap(),
+                    ))?,
+                    2 => self
+                        .on_market_fill(a, f64::from_le_bytes(data[10..18].try_into().unwrap()))?,
+                    _ => {}
+                }
+            }
+            Some(0x20) if data.len() >= 25 => {
+                let order_id = isize::from_le_bytes(data[1..9].try_into().unwrap());
+                let qty = isize::from_le_bytes(data[9..17].try_into().unwrap());
+                let price = f64::from_le_bytes(data[17..25].try_into().unwrap());
+                if let Some(mut order) = self.orders.remove(&order_id) {
+                    order.cum_qty += qty;
+                    order.notional += qty as f64 * price;
+                    self.fill_report(Some(order_id), &order, qty, price)?;
+                    if order.leaves() > 0 {
+                        self.orders.insert(order_id, order);
+                    }
+                }
+            }
+            Some(0xe0) => self.on_canceled()?,
+            Some(0xfd) => self.on_cancel_rejected()?,
+            Some(0xfb) => self.on_replace_rejected()?,
+            Some(0xff | 0xfe | 0xfc) => self.on_rejected()?,
+            Some(0x69) => return Ok(false),
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn on_accepted(&mut self, order_id: isize) -> std::io::Result<()> {
+        let pending = match self.pending.front() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+
+        let (mut order, replaced) = match &pending.kind {
+            PendingKind::New(order) => (order.clone(), None),
+            PendingKind::Replace {
+                orig_cl_ord_id,
+                order_id,
+                order,
+            } => (order.clone(), Some((orig_cl_ord_id.clone(), *order_id))),
+            _ => return Ok(()),
+        };
+
+        // the engine rests the leaves quantity the replace was submitted with, fills of the
+        // original that came in while it was queued come on top of that
+        if let Some((_, orig_order_id)) = &replaced {
+            if let Some(original) = self.orders.remove(orig_order_id) {
+                order.qty += original.cum_qty - order.cum_qty;
+                order.cum_qty = original.cum_qty;
+                order.notional = original.notional;
+            }
+        }
+
+        self.orders.insert(order_id, order.clone());
+        let msg = match replaced {
+            None => self.execution_report(Some(order_id), &order, "0", "0"),
+            Some((orig_cl_ord_id, _)) => {
+                let ord_status = if order.cum_qty > 0 { "1" } else { "0" };
+                let mut msg = self.execution_report(Some(order_id), &order, "5", ord_status);
+                msg.set(TAG_ORIG_CL_ORD_ID, orig_cl_ord_id);
+                msg
+            }
+        };
+        self.send(&msg)
+    }
+
+    fn on_market_fill(&mut self, qty: isize, price: f64) -> std::io::Result<()> {
+        if qty == 0 {
+            return Ok(());
+        }
+
+        let order = match self.pending.front_mut().map(|p| &mut p.kind) {
+            Some(PendingKind::New(order)) if order.price.is_none() => {
+                order.cum_qty += qty;
+                order.notional += qty as f64 * price;
+                order.clone()
+            }
+            _ => return Ok(()),
+        };
+        self.fill_report(None, &order, qty, price)
+    }
+
+    fn on_canceled(&mut self) -> std::io::Result<()> {
+        let (orig_cl_ord_id, order_id) = match self.pending.front().map(|p| &p.kind) {
+            Some(PendingKind::Cancel {
+                orig_cl_ord_id,
+                order_id,
+            }) => (orig_cl_ord_id.clone(), *order_id),
+            _ => return Ok(()),
+        };
+
+        if let Some(order) = self.orders.remove(&order_id) {
+            let mut msg = self.execution_report(Some(order_id), &order, "4", "4");
+            msg.set(TAG_ORIG_CL_ORD_ID, orig_cl_ord_id)
+                .set(TAG_LEAVES_QTY, 0);
+            self.send(&msg)?;
+        }
+        Ok(())
+    }
+
+    /// The admin pulled a resting order, there is no request this answers.
+    fn on_unsolicited_cancel(&mut self, order_id: isize) -> std::io::Result<()> {
+        if let Some(order) = self.orders.remove(&order_id) {
+            let mut msg = self.execution_report(Some(order_id), &order, "4", "4");
+            msg.set(TAG_LEAVES_QTY, 0)
+                .set(TAG_TEXT, "canceled by the exchange");
+            self.send(&msg)?;
+        }
+        Ok(())
+    }
+
+    fn on_cancel_rejected(&mut self) -> std::io::Result<()> {
+        let pending = match self.pending.front_mut() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        pending.failed = true;
+
+        let (orig_cl_ord_id, order_id) = match &pending.kind {
+            PendingKind::Cancel {
+                orig_cl_ord_id,
+                order_id,
+            } => (orig_cl_ord_id.clone(), *order_id),
+            _ => return Ok(()),
+        };
+        let cl_ord_id = self
+            .orders
+            .get(&order_id)
+            .map_or(orig_cl_ord_id.clone(), |o| o.cl_ord_id.clone());
+
+        self.cancel_reject(
+            &cl_ord_id,
+            &orig_cl_ord_id,
+            Some(order_id),
+            "1",
+            "too late to cancel",
+        )
+    }
+
+    /// The engine replaces atomically, a refused replace leaves the original order as it was.
+    fn on_replace_rejected(&mut self) -> std::io::Result<()> {
+        let pending = match self.pending.front_mut() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        pending.failed = true;
+
+        let (cl_ord_id, orig_cl_ord_id, order_id) = match &pending.kind {
+            PendingKind::Replace {
+                orig_cl_ord_id,
+                order_id,
+                order,
+            } => (order.cl_ord_id.clone(), orig_cl_ord_id.clone(), *order_id),
+            _ => return Ok(()),
+        };
+
+        self.cancel_reject(
+            &cl_ord_id,
+            &orig_cl_ord_id,
+            Some(order_id),
+            "2",
+            "replace rejected by the exchange",
+        )
+    }
+
+    fn on_rejected(&mut self) -> std::io::Result<()> {
+        let pending = match self.pending.front_mut() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        pending.failed = true;
+
+        match &pending.kind {
+            PendingKind::New(order) => {
+                let order = order.clone();
+                self.reject(&order, "rejected by the exchange")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The engine is done with the oldest pending order.
+    fn on_processed(&mut self) -> std::io::Result<()> {
+        let pending = match self.pending.pop_front() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+
+        match pending.kind {
+            // market orders are immediate or cancel, whatever didn't fill is gone
+            PendingKind::New(order)
+                if order.price.is_none() && !pending.failed && order.leaves() > 0 =>
+            {
+                let mut msg = self.execution_report(None, &order, "4", "4");
+                msg.set(TAG_LEAVES_QTY, 0)
+                    .set(TAG_TEXT, "no liquidity left");
+                self.send(&msg)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Hands an order to the engine, it goes through the same parsers as the binary protocol.
+    /// Returns false if they refused it.
+    fn submit(&mut self, kind: PendingKind) -> bool {
+        let mut buffer = [0u8; crate::tcp::MAX_FRAME];
+        match &kind {
+            PendingKind::New(order) => {
+                let amount = match order.side {
+                    Side::Buy => order.qty,
+                    Side::Sell => -order.qty,
+                };
+                match order.price {
+                    Some(price) => {
+                        buffer[0] = 0;
+                        buffer[1..9].copy_from_slice(&price.to_le_bytes()[..]);
+                        buffer[9..17].copy_from_slice(&amount.to_le_bytes()[..]);
+                    }
+                    None => {
+                        buffer[0] = 1;
+                        buffer[1..9].copy_from_slice(&amount.to_le_bytes()[..]);
+                    }
+                }
+            }
+            PendingKind::Cancel { order_id, .. } => {
+                buffer[0] = 2;
+                buffer[1..9].copy_from_slice(&order_id.to_le_bytes()[..]);
+            }
+            PendingKind::Replace {
+                order_id, order, ..
+            } => {
+                let amount = match order.side {
+                    Side::Buy => order.leaves(),
+                    Side::Sell => -order.leaves(),
+                };
+                buffer[0] = 4;
+                buffer[1..9].copy_from_slice(&order_id.to_le_bytes()[..]);
+                buffer[9..17].copy_from_slice(&order.price.unwrap_or_default().to_le_bytes()[..]);
+                buffer[17..25].copy_from_slice(&amount.to_le_bytes()[..]);
+            }
+        }
+
+        let order = match parse_order(&buffer, false) {
+            Some(order) => order,
+            None => return false,
+        };
+
+        self.pending.push_back(Pending {
+            kind,
+            failed: false,
+        });
+        self.order_sender.send((self.addr, order, self.correlation));
+        true
+    }
+
+    fn on_new_order_single(&mut self, msg: &Message) -> std::io::Result<()> {
+        let order = match parse_fix_order(msg) {
+            Ok(order) => order,
+            Err(order) => return self.reject(&order, "invalid order"),
+        };
+
+        if !self.submit(PendingKind::New(order.clone())) {
+            self.reject(&order, "invalid order")?;
+        }
+        Ok(())
+    }
+
+    fn find_order(&self, cl_ord_id: &str) -> Option<isize> {
+        self.orders
+            .iter()
+            .find_map(|(id, o)| (o.cl_ord_id == cl_ord_id).then_some(*id))
+    }
+
+    fn on_order_cancel_request(&mut self, msg: &Message) -> std::io::Result<()> {
+        let cl_ord_id = msg.get(TAG_CL_ORD_ID).unwrap_or_default().to_string();
+        let orig_cl_ord_id = msg.get(TAG_ORIG_CL_ORD_ID).unwrap_or_default().to_string();
+
+        match self.find_order(&orig_cl_ord_id) {
+            Some(order_id) => {
+                self.submit(PendingKind::Cancel {
+                    orig_cl_ord_id,
+                    order_id,
+                });
+                Ok(())
+            }
+            None => self.cancel_reject(&cl_ord_id, &orig_cl_ord_id, None, "1", "unknown order"),
+        }
+    }
+
+    fn on_order_cancel_replace_request(&mut self, msg: &Message) -> std::io::Result<()> {
+        let cl_ord_id = msg.get(TAG_CL_ORD_ID).unwrap_or_default().to_string();
+        let orig_cl_ord_id = msg.get(TAG_ORIG_CL_ORD_ID).unwrap_or_default().to_string();
+
+        let order_id = match self.find_order(&orig_cl_ord_id) {
+            Some(order_id) => order_id,
+            None => {
+                return self.cancel_reject(&cl_ord_id, &orig_cl_ord_id, None, "2", "unknown order")
+            }
+        };
+
+        // OrderQty is the new total, what the original already filled counts against it
+        let original = &self.orders[&order_id];
+        let replacement = match parse_fix_order(msg) {
+            Ok(mut order)
+                if order.price.is_some()
+                    && order.side == original.side
+                    && order.qty > original.cum_qty =>
+            {
+                order.cum_qty = original.cum_qty;
+                order.notional = original.notional;
+                order
+            }
+            _ => {
+                return self.cancel_reject(
+                    &cl_ord_id,
+                    &orig_cl_ord_id,
+                    Some(order_id),
+                    "2",
+                    "invalid replacement",
+                )
+            }
+        };
+
+        let submitted = self.submit(PendingKind::Replace {
+            orig_cl_ord_id: orig_cl_ord_id.clone(),
+            order_id,
+            order: replacement,
+        });
+        if !submitted {
+            self.cancel_reject(
+                &cl_ord_id,
+                &orig_cl_ord_id,
+                Some(order_id),
+                "2",
+                "invalid replacement",
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads side, quantity, type and price of a `NewOrderSingle` or `OrderCancelReplaceRequest`.
+/// On error whatever could be read is returned so the reject can echo it.
+fn parse_fix_order(msg: &Message) -> Result<FixOrder, FixOrder> {
+    let side = msg.get(TAG_SIDE).and_then(Side::from_fix);
+    let qty = msg.get(TAG_ORDER_QTY).and_then(|q| q.parse::<isize>().ok());
+    let price = msg.get(TAG_PRICE).and_then(|p| p.parse::<f64>().ok());
+
+    let mut order = FixOrder {
+        cl_ord_id: msg.get(TAG_CL_ORD_ID).unwrap_or_default().to_string(),
+        side: side.unwrap_or(Side::Buy),
+        qty: qty.unwrap_or_default(),
+        price,
+        cum_qty: 0,
+        notional: 0.0,
+    };
+
+    let price = match msg.get(TAG_ORD_TYPE) {
+        Some("1") => None,
+        Some("2") => Some(price),
+        _ => return Err(order),
+    };
+    order.price = price.flatten();
+
+    if side.is_none()
+        || qty.is_none_or(|q| q <= 0)
+        || price == Some(None)
+        || msg.get(TAG_SYMBOL) != Some(SYMBOL)
+        || order.cl_ord_id.is_empty()
+    {
+        return Err(order);
+    }
+    Ok(order)
+}
+
+/// FIX 4.4 acceptor. Orders are translated into the binary protocol and go through the same
+/// engine queue, everything the engine sends back is turned into `ExecutionReport`s and
+/// `OrderCancelReject`s. The session is identified by the counterparties `SenderCompID`, which
+/// has to be the account it logs on with, its sequence numbers and sent messages are kept in
+/// `fix/` so they survive reconnects. Only one connection per `SenderCompID` at a time.
+pub(crate) fn listen(
+    listener: TcpListener,
+    logons: Logons,
+    sessions: Sessions,
+    accounts: Accounts,
+    audit: Audit,
+    order_sender: Sender<(SocketAddr, Order, u64)>,
+) {
+    let active = MThread::new(BTreeSet::new());
+    let open = MThread::new(0);
+    for stream in listener.incoming().flatten() {
+        let slot = match Slot::take(&open) {
+            Some(slot) => slot,
+            None => continue,
+        };
+        let logons = logons.clone();
+        let sessions = sessions.clone();
+        let accounts = accounts.clone();
+        let active = active.clone();
+        let (audit, order_sender) = (audit.clone(), order_sender.clone());
+        std::thread::spawn(move || {
+            let _slot = slot;
+            connection(
+                stream,
+                logons,
+                sessions,
+                accounts,
+                active,
+                audit,
+                order_sender,
+            )
+        });
+    }
+}
+
+/// Claims a `SenderCompID` for one connection, released when the connection ends.
+struct ActiveCompId {
+    active: MThread<BTreeSet<String>>,
+    comp_id: String,
+}
+
+impl ActiveCompId {
+    fn claim(active: &MThread<BTreeSet<String>>, comp_id: &str) -> Option<Self> {
+        active.get().insert(comp_id.to_string()).then(|| Self {
+            active: active.clone(),
+            comp_id: comp_id.to_string(),
+        })
+    }
+}
+
+impl Drop for ActiveCompId {
+    fn drop(&mut self) {
+        self.active.get().remove(&self.comp_id);
+    }
+}
+
+/// Answers a logon that isn't accepted. There is no session yet, so the `Logout` is sent
+/// outside of it: it doesn't touch the sequence store and always carries `MsgSeqNum` 1.
+fn refuse(
+    stream: &mut TcpStream,
+    audit: &Audit,
+    correlation: u64,
+    addr: SocketAddr,
+    target_comp_id: &str,
+    text: &str,
+) {
+    let mut msg = Message::new(MSG_LOGOUT);
+    msg.set(TAG_SENDER_COMP_ID, COMP_ID)
+        .set(TAG_TARGET_COMP_ID, target_comp_id)
+        .set(TAG_MSG_SEQ_NUM, 1)
+        .set(TAG_SENDING_TIME, utc_timestamp())
+        .set(TAG_TEXT, text);
+    let raw = msg.to_bytes();
+    audit.get().outbound(correlation, "fix", addr, &raw);
+    stream.write_all(&raw);
+}
+
+fn connection(
+    stream: TcpStream,
+    logons: Logons,
+    sessions: Sessions,
+    accounts: Accounts,
+    active: MThread<BTreeSet<String>>,
+    audit: Audit,
+    order_sender: Sender<(SocketAddr, Order, u64)>,
+) {
+    let addr = match stream.peer_addr() {
+        Ok(addr) => addr,
+        Err(_) => return,
+    };
+    let mut wstream = match stream.try_clone() {
+        Ok(wstream) => wstream,
+        Err(_) => return,
+    };
+    if stream.set_read_timeout(Some(LOGON_TIMEOUT)).is_err() {
+        return;
+    }
+    let mut reader = BufReader::new(stream);
+
+    let raw = match read_message(&mut reader) {
+        Ok(raw) => raw,
+        Err(_) => return,
+    };
+    let id = audit.get().inbound("fix", addr, &redacted(&raw));
+    let logon = match Message::from_bytes(&raw) {
+        Some(msg) if msg.msg_type == MSG_LOGON => msg,
+        _ => {
+            audit.get().dropped(id, "not a logon");
+            return;
+        }
+    };
+
+    let target_comp_id = logon
+        .get(TAG_SENDER_COMP_ID)
+        .unwrap_or_default()
+        .to_string();
+    let heart_bt_int = logon
+        .get(TAG_HEART_BT_INT)
+        .and_then(|h| h.parse::<u64>().ok())
+        .filter(|h| *h > 0 && *h <= 300);
+    let account = logon
+        .get(TAG_USERNAME)
+        .unwrap_or(&target_comp_id)
+        .to_string();
+    let password = logon.get(TAG_PASSWORD).unwrap_or_default();
+
+    let heart_bt_int = match heart_bt_int {
+        Some(h)
+            if logon.get(TAG_TARGET_COMP_ID) == Some(COMP_ID) && valid_comp_id(&target_comp_id) =>
+        {
+            Duration::from_secs(h)
+        }
+        _ => {
+            audit.get().dropped(id, "bad HeartBtInt or comp ids");
+            return;
+        }
+    };
+
+    // the store is only opened once the counterparty proved it is the account its
+    // `SenderCompID` names, anyone else must not be able to move its sequence numbers
+    if account != target_comp_id {
+        audit.get().dropped(id, "SenderCompID isn't the account");
+        refuse(
+            &mut wstream,
+            &audit,
+            id,
+            addr,
+            &target_comp_id,
+            "SenderCompID has to be the Username",
+        );
+        return;
+    }
+    let grant = logons.get().logon_password(addr, &account, password);
+    let grant = match grant {
+        Some(grant) => grant,
+        None => {
+            audit.get().dropped(id, "logon refused");
+            refuse(
+                &mut wstream,
+                &audit,
+                id,
+                addr,
+                &target_comp_id,
+                "invalid credentials",
+            );
+            return;
+        }
+    };
+    // the reader gives up after two silent intervals, one with a test request outstanding,
+    // the logon has to outlive that
+    logons.get().set_timeout(grant.token, heart_bt_int * 3);
+
+    let _claim = match ActiveCompId::claim(&active, &target_comp_id) {
+        Some(claim) => claim,
+        None => {
+            logons.get().logout(grant.token);
+            audit.get().dropped(id, "session already active");
+            refuse(
+                &mut wstream,
+                &audit,
+                id,
+                addr,
+                &target_comp_id,
+                "session already active",
+            );
+            return;
+        }
+    };
+
+    let store = match SeqStore::open(STORE_DIR, &target_comp_id) {
+        Ok(store) => store,
+        Err(_) => {
+            logons.get().logout(grant.token);
+            audit.get().dropped(id, "sequence store unavailable");
+            return;
+        }
+    };
+
+    let mut session = FixSession {
+        stream: wstream,
+        store,
+        target_comp_id,
+        addr,
+        order_sender: order_sender.clone(),
+        audit: audit.clone(),
+        correlation: id,
+        pending: VecDeque::new(),
+        orders: BTreeMap::new(),
+        exec_id: 0,
+        last_sent: Instant::now(),
+    };
+    let session = MThread::new(session);
+
+    let logon_seq = logon.seq().unwrap_or_default();
+    let accepted = (|| -> std::io::Result<bool> {
+        let mut session = session.get();
+        if logon.get(TAG_RESET_SEQ_NUM_FLAG) == Some("Y") {
+            session.store.reset()?;
+        }
+
+        if logon_seq < session.store.next_in {
+            let mut msg = Message::new(MSG_LOGOUT);
+            msg.set(
+                TAG_TEXT,
+                format!("MsgSeqNum too low, expecting {}", session.store.next_in),
+            );
+            session.send(&msg)?;
+            return Ok(false);
+        }
+
+        let mut msg = Message::new(MSG_LOGON);
+        msg.set(TAG_ENCRYPT_METHOD, 0)
+            .set(TAG_HEART_BT_INT, heart_bt_int.as_secs());
+        if logon.get(TAG_RESET_SEQ_NUM_FLAG) == Some("Y") {
+            msg.set(TAG_RESET_SEQ_NUM_FLAG, "Y");
+        }
+        session.send(&msg)?;
+
+        if logon_seq > session.store.next_in {
+            let mut msg = Message::new(MSG_RESEND_REQUEST);
+            msg.set(TAG_BEGIN_SEQ_NO, session.store.next_in)
+                .set(TAG_END_SEQ_NO, 0);
+            session.send(&msg)?;
+        } else {
+            session.store.next_in += 1;
+            session.store.persist()?;
+        }
+        Ok(true)
+    })();
+
+    if !accepted.unwrap_or(false) {
+        logons.get().logout(grant.token);
+        return;
+    }
+
+    let (tx, rx) = channel();
+    let wsession = session.clone();
+    std::thread::spawn(move || writer(wsession, rx, heart_bt_int));
+
+    accept_logon(addr, &grant, &order_sender, id);
+    sessions.get().attach(addr, tx);
+
+    reader
+        .get_ref()
+        .set_read_timeout(Some(heart_bt_int + heart_bt_int / 5));
+    let mut test_request_sent = false;
+    loop {
+        let raw = match read_message(&mut reader) {
+            Ok(raw) => raw,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if test_request_sent {
+                    break;
+                }
+                let mut msg = Message::new(MSG_TEST_REQUEST);
+                msg.set(TAG_TEST_REQ_ID, utc_timestamp());
+                let mut session = session.get();
+                session.correlation = audit.get().correlate();
+                if session.send(&msg).is_err() {
+                    break;
+                }
+                test_request_sent = true;
+                continue;
+            }
+            Err(_) => break,
+        };
+        test_request_sent = false;
+        let id = audit.get().inbound("fix", addr, &redacted(&raw));
+
+        if !logons.get().touch(grant.token) {
+            audit.get().dropped(id, "logon expired");
+            break;
+        }
+
+        let msg = match Message::from_bytes(&raw) {
+            Some(msg) => msg,
+            None => {
+                audit.get().dropped(id, "malformed message");
+                continue;
+            }
+        };
+
+        let mut session = session.get();
+        session.correlation = id;
+        match handle(&mut session, &msg) {
+            Ok(true) => {}
+            _ => break,
+        }
+    }
+
+    let id = audit.get().closed("fix", addr);
+    // a logon the exchange ended in the meantime took the session with it already
+    if logons.get().logout(grant.token).is_some() {
+        order_sender.send((addr, Order::Logout, id));
+    }
+    // dropping the writers sender makes it close the connection
+    sessions.get().forget(&addr);
+}
+
+/// Handles one inbound message after logon, returns false if the session is over.
+fn handle(session: &mut FixSession, msg: &Message) -> std::io::Result<bool> {
+    let seq = msg.seq().unwrap_or_default();
+    let poss_dup = msg.get(TAG_POSS_DUP_FLAG) == Some("Y");
+
+    if msg.msg_type == MSG_SEQUENCE_RESET {
+        let new_seq = msg
+            .get(TAG_NEW_SEQ_NO)
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or_default();
+        if new_seq > session.store.next_in {
+            session.store.next_in = new_seq;
+            session.store.persist()?;
+        }
+        return Ok(true);
+    }
+
+    if seq < session.store.next_in {
+        if poss_dup {
+            return Ok(true);
+        }
+        let mut res = Message::new(MSG_LOGOUT);
+        res.set(
+            TAG_TEXT,
+            format!("MsgSeqNum too low, expecting {}", session.store.next_in),
+        );
+        session.send(&res)?;
+        return Ok(false);
+    }
+
+    // resend requests are answered even ahead of a gap so two sides with a gap each can't
+    // deadlock, everything else waits for the gap to be filled
+    if msg.msg_type == MSG_RESEND_REQUEST {
+        let from = msg
+            .get(TAG_BEGIN_SEQ_NO)
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(1);
+        let to = msg
+            .get(TAG_END_SEQ_NO)
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(0);
+        session.resend(from, to)?;
+    }
+
+    if seq > session.store.next_in {
+        if !poss_dup {
+            let mut res = Message::new(MSG_RESEND_REQUEST);
+            res.set(TAG_BEGIN_SEQ_NO, session.store.next_in)
+                .set(TAG_END_SEQ_NO, 0);
+            session.send(&res)?;
+        }
+        return Ok(true);
+    }
+
+    session.store.next_in += 1;
+    session.store.persist()?;
+
+    match msg.msg_type.as_str() {
+        MSG_TEST_REQUEST => {
+            let mut res = Message::new(MSG_HEARTBEAT);
+            res.set(
+                TAG_TEST_REQ_ID,
+                msg.get(TAG_TEST_REQ_ID).unwrap_or_default(),
+            );
+            session.send(&res)?;
+        }
+        MSG_LOGOUT => {
+            session.send(&Message::new(MSG_LOGOUT))?;
+            return Ok(false);
+        }
+        MSG_NEW_ORDER_SINGLE => session.on_new_order_single(msg)?,
+        MSG_ORDER_CANCEL_REQUEST => session.on_order_cancel_request(msg)?,
+        MSG_ORDER_CANCEL_REPLACE_REQUEST => session.on_order_cancel_replace_request(msg)?,
+        _ => {}
+    }
+    Ok(true)
+}
+
+fn writer(session: MThread<FixSession>, rx: Receiver<StreamMsg>, heart_bt_int: Duration) {
+    loop {
+        let alive = match rx.recv_timeout(heart_bt_int / 2) {
+            Ok(StreamMsg::Data(data, id)) => {
+                let mut session = session.get();
+                session.correlation = id;
+                session.on_engine(&data)
+            }
+            Ok(StreamMsg::Processed(id)) => {
+                let mut session = session.get();
+                session.correlation = id;
+                session.on_processed().map(|_| true)
+            }
+            Err(RecvTimeoutError::Timeout) => Ok(true),
+            Err(RecvTimeoutError::Disconnected) => Ok(false),
+        };
+
+        // checked on every pass, engine pushes keep the channel busy but aren't necessarily
+        // sent on to the counterparty
+        let alive = alive.and_then(|alive| {
+            let mut session = session.get();
+            if alive && session.last_sent.elapsed() >= heart_bt_int {
+                let id = session.audit.get().correlate();
+                session.correlation = id;
+                session.send(&Message::new(MSG_HEARTBEAT))?;
+            }
+            Ok(alive)
+        });
+
+        if !alive.unwrap_or(false) {
+            break;
+        }
+    }
+
+    let mut session = session.get();
+    session.send(&Message::new(MSG_LOGOUT));
+    session.stream.shutdown(Shutdown::Both);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(text: &str) -> Vec<u8> {
+        text.replace('|', "\x01").into_bytes()
+    }
+
+    #[test]
+    fn parses_a_heartbeat() {
+        let msg = Message::from_bytes(&raw("8=FIX.4.4|9=5|35=0|10=163|")).unwrap();
+        assert_eq!(msg.msg_type, MSG_HEARTBEAT);
+        assert!(msg.fields.is_empty());
+    }
+
+    #[test]
+    fn parses_fields_in_order() {
+        let msg = Message::from_bytes(&raw(
+            "8=FIX.4.4|9=41|35=A|49=alice|56=MARKETMAKER|34=1|108=30|10=122|",
+        ))
+        .unwrap();
+        assert_eq!(msg.msg_type, MSG_LOGON);
+        assert_eq!(msg.get(TAG_SENDER_COMP_ID), Some("alice"));
+        assert_eq!(msg.get(TAG_HEART_BT_INT), Some("30"));
+        assert_eq!(msg.seq(), Some(1));
+        assert_eq!(msg.get(TAG_PASSWORD), None);
+    }
+
+    #[test]
+    fn refuses_broken_framing() {
+        // checksum off by one
+        assert!(Message::from_bytes(&raw("8=FIX.4.4|9=5|35=0|10=164|")).is_none());
+        // checksum not zero padded
+        assert!(Message::from_bytes(&raw("8=FIX.4.4|9=5|35=0|10=63|")).is_none());
+        // wrong body length, the checksum matches the bytes as sent
+        assert!(Message::from_bytes(&raw("8=FIX.4.4|9=6|35=0|10=164|")).is_none());
+        // other version
+        assert!(Message::from_bytes(&raw("8=FIX.4.2|9=5|35=0|10=161|")).is_none());
+        // MsgType has to come first
+        let mut msg = Message::new(MSG_HEARTBEAT);
+        msg.set(TAG_MSG_SEQ_NUM, 1);
+        let text = String::from_utf8(msg.to_bytes()).unwrap();
+        let swapped = text.replacen("35=0\x0134=1", "34=1\x0135=0", 1);
+        assert!(Message::from_bytes(swapped.as_bytes()).is_none());
+        assert!(Message::from_bytes(b"").is_none());
+    }
+
+    #[test]
+    fn serialized_messages_parse_back() {
+        let mut msg = Message::new(MSG_NEW_ORDER_SINGLE);
+        msg.set(TAG_CL_ORD_ID, "o1")
+            .set(TAG_SIDE, "2")
+            .set(TAG_ORDER_QTY, 10)
+            .set(TAG_PRICE, 1.25)
+            .set(TAG_SIDE, "1");
+        let parsed = Message::from_bytes(&msg.to_bytes()).unwrap();
+        assert_eq!(parsed.msg_type, MSG_NEW_ORDER_SINGLE);
+        assert_eq!(parsed.fields, msg.fields);
+        // setting a tag again replaces it
+        assert_eq!(parsed.get(TAG_SIDE), Some("1"));
+    }
+
+    #[test]
+    fn reads_one_message_at_a_time() {
+        let heartbeat = raw("8=FIX.4.4|9=5|35=0|10=163|");
+        let mut stream = heartbeat.repeat(2);
+        stream.extend_from_slice(b"8=FIX.4.4\x019=99999\x01");
+        let mut reader = &stream[..];
+
+        assert_eq!(read_message(&mut reader).unwrap(), heartbeat);
+        assert_eq!(read_message(&mut reader).unwrap(), heartbeat);
+        assert_eq!(
+            read_message(&mut reader).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        assert_eq!(
+            read_message(&mut &b""[..]).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn redacts_the_password() {
+        let logon = raw("8=FIX.4.4|9=20|35=A|553=alice|554=secret|10=000|");
+        assert_eq!(
+            redacted(&logon),
+            raw("8=FIX.4.4|9=20|35=A|553=alice|554=***|10=000|")
+        );
+        let heartbeat = raw("8=FIX.4.4|9=5|35=0|10=163|");
+        assert_eq!(redacted(&heartbeat), heartbeat);
+    }
+
+    #[test]
+    fn comp_ids_have_to_be_safe_file_names() {
+        assert!(valid_comp_id("alice_2-b"));
+        assert!(!valid_comp_id(""));
+        assert!(!valid_comp_id("../alice"));
+        assert!(!valid_comp_id("a.b"));
+    }
+
+    #[test]
+    fn translates_orders() {
+        let mut msg = Message::new(MSG_NEW_ORDER_SINGLE);
+        msg.set(TAG_CL_ORD_ID, "o1")
+            .set(TAG_SYMBOL, SYMBOL)
+            .set(TAG_SIDE, "2")
+            .set(TAG_ORDER_QTY, 10)
+            .set(TAG_ORD_TYPE, "2")
+            .set(TAG_PRICE, "1.5");
+        let order = parse_fix_order(&msg).unwrap();
+        assert_eq!(order.side, Side::Sell);
+        assert_eq!((order.qty, order.price), (10, Some(1.5)));
+
+        // a market order ignores the price
+        msg.set(TAG_ORD_TYPE, "1");
+        assert_eq!(parse_fix_order(&msg).unwrap().price, None);
+
+        // a limit order needs one
+        msg.set(TAG_ORD_TYPE, "2").set(TAG_PRICE, "cheap");
+        assert!(parse_fix_order(&msg).is_err());
+        msg.set(TAG_PRICE, "1.5").set(TAG_ORDER_QTY, 0);
+        assert!(parse_fix_order(&msg).is_err());
+        msg.set(TAG_ORDER_QTY, 10).set(TAG_SYMBOL, "OTHER");
+        let rejected = parse_fix_order(&msg).unwrap_err();
+        // what could be read is kept for the reject
+        assert_eq!(rejected.cl_ord_id, "o1");
+    }
+
+    #[test]
+    fn the_store_finds_what_was_sent() {
+        let dir = std::env::temp_dir().join(format!("mm-fix-{}", std::process::id()));
+        let dir = dir.to_str().unwrap();
+        let mut store = SeqStore::open(dir, "CLIENT").unwrap();
+        store.reset().unwrap();
+        for seq in 1..=3 {
+            let mut msg = Message::new(MSG_HEARTBEAT);
+            msg.set(TAG_MSG_SEQ_NUM, seq);
+            store.record(seq, &msg.to_bytes()).unwrap();
+        }
+        store.next_in = 7;
+        store.next_out = 4;
+        store.persist().unwrap();
+
+        let seqs = |store: &SeqStore, from, to| -> Vec<u64> {
+            store
+                .sent(from, to)
+                .iter()
+                .filter_map(Message::seq)
+                .collect()
+        };
+        assert_eq!(seqs(&store, 2, 3), [2, 3]);
+        assert_eq!(seqs(&store, 3, 1), []);
+
+        // a restart finds them again
+        let mut store = SeqStore::open(dir, "CLIENT").unwrap();
+        assert_eq!((store.next_in, store.next_out), (7, 4));
+        assert_eq!(seqs(&store, 1, 10), [1, 2, 3]);
+
+        store.reset().unwrap();
+        assert_eq!(seqs(&store, 1, 10), []);
+        let store = SeqStore::open(dir, "CLIENT").unwrap();
+        assert_eq!((store.next_in, store.next_out), (1, 1));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,104 @@
+use std::io::{BufRead, Read, Write};
+
+const MAX_LINE: u64 = 8192;
+const MAX_HEADERS: usize = 64;
+const MAX_BODY: usize = 64 * 1024;
+
+/// A parsed HTTP/1.1 request, just what the websocket handshake and the admin api need.
+#[derive(Debug)]
+pub(crate) struct Request {
+    pub method: String,
+    /// path without the query string
+    pub path: String,
+    pub query: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+fn read_line(stream: &mut impl BufRead) -> Option<String> {
+    let mut line = Vec::new();
+    stream
+        .take(MAX_LINE)
+        .read_until(b'\n', &mut line)
+        .ok()
+        .filter(|read| *read != 0)?;
+    if !line.ends_with(b"\n") {
+        return None;
+    }
+    let line = String::from_utf8(line).ok()?;
+    Some(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+impl Request {
+    pub(crate) fn read(stream: &mut impl BufRead) -> Option<Self> {
+        let line = read_line(stream)?;
+        let mut parts = line.split(' ');
+        let method = parts.next()?.to_string();
+        let target = parts.next()?;
+        if !parts.next()?.starts_with("HTTP/1.") {
+            return None;
+        }
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+        let mut headers = Vec::new();
+        loop {
+            let line = read_line(stream)?;
+            if line.is_empty() {
+                break;
+            }
+            if headers.len() >= MAX_HEADERS {
+                return None;
+            }
+            let (name, value) = line.split_once(':')?;
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+
+        let mut req = Self {
+            method,
+            path: path.to_string(),
+            query: query.to_string(),
+            headers,
+            body: Vec::new(),
+        };
+
+        let len = req
+            .header("content-length")
+            .map_or(Some(0), |len| len.parse::<usize>().ok())
+            .filter(|len| *len <= MAX_BODY)?;
+        req.body = vec![0; len];
+        stream.read_exact(&mut req.body).ok()?;
+
+        Some(req)
+    }
+
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find_map(|(n, v)| n.eq_ignore_ascii_case(name).then_some(v.as_str()))
+    }
+
+    /// Value of a `key=value` pair from the query string, no percent decoding.
+    pub(crate) fn param(&self, key: &str) -> Option<&str> {
+        self.query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix(key)?.strip_prefix('='))
+    }
+}
+
+pub(crate) fn respond(
+    stream: &mut impl Write,
+    status: u16,
+    reason: &str,
+    content_type: &str,
+    body: &[u8],
+) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)
+}
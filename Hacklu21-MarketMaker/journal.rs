@@ -0,0 +1,753 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::mpsc::channel;
+
+use crate::account::{Accounts, Role};
+use crate::admin::{self, AdminCommand};
+use crate::json::Json;
+use crate::replication::Standbys;
+use crate::session::SessionTable;
+use crate::{marketdata, snapshot, stats, Order, OrderBook};
+
+/// Where the engine journals to, relative to the working directory like everything else.
+pub(crate) const JOURNAL: &str = "journal";
+
+/// `[0]`, the engine (re)started and carries on with the state the journal ends in, minus the
+/// sessions: their logons were lost with the process
+const START: u8 = 0;
+/// `[1][addr][order]`, the order in the form the clients send it
+const ORDER: u8 = 1;
+/// `[2][addr][account id]`
+const LOGON: u8 = 2;
+/// `[3][addr]`
+const LOGOUT: u8 = 3;
+/// `[4][addr][from addr]`
+const REBIND: u8 = 4;
+/// `[5][command u8]` followed by `[order id isize]` for a cancel, `[account id][money f64]
+/// [position isize]` for an adjustment, `[account id]` for a kick, `[name][money f64]
+/// [position isize]` for a policy, `[account id][policy][role u8][entitlements u8]` for a
+/// provisioning, `[kind][max f64]` for a limit, NaN lifting it, and `[account id]
+/// [amount f64]` for a deposit or withdrawal
+const ADMIN: u8 = 5;
+/// `[6][count u16]` and `count` times `[addr]`, the end of a cycle and whose logons expired
+const CYCLE: u8 = 6;
+
+const ADMIN_CANCEL: u8 = 0;
+const ADMIN_HALT: u8 = 1;
+const ADMIN_RESUME: u8 = 2;
+const ADMIN_ADJUST: u8 = 3;
+const ADMIN_KICK: u8 = 4;
+const ADMIN_POLICY: u8 = 5;
+const ADMIN_PROVISION: u8 = 6;
+const ADMIN_LIMIT: u8 = 7;
+const ADMIN_CASH: u8 = 8;
+
+/// Write-ahead journal of everything the engine takes off the order channel that can change
+/// the book or the accounts, and of the cycle boundaries. Every record is
+/// `[len u32][type u8][...]`, addresses and account ids are `[len u8]` followed by their text
+/// form.
+///
+/// A record is written before the engine acts on it, the file is synced at the end of every
+/// cycle. Replaying it through the same engine code rebuilds the book and the accounts, from
+/// the latest snapshot on if there is one. Every record also goes out to the standbys as it
+/// is written.
+#[derive(Debug)]
+pub(crate) struct Journal {
+    file: File,
+    /// bytes in the journal, where the next record goes
+    len: u64,
+    standbys: Standbys,
+}
+
+/// What the replay feeds the engine.
+#[derive(Debug)]
+pub(crate) enum Entry {
+    Start,
+    Order(SocketAddr, Order),
+    Cycle(Vec<SocketAddr>),
+}
+
+/// Longest account id, policy name or limit kind a record can hold, the length is a single
+/// byte. Anything longer is refused where it comes in, before it could reach the journal.
+pub(crate) const MAX_STR: usize = u8::MAX as usize;
+
+pub(crate) fn push_str(buf: &mut Vec<u8>, s: &str) {
+    buf.push(s.len() as u8);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+pub(crate) fn push_addr(buf: &mut Vec<u8>, addr: &SocketAddr) {
+    push_str(buf, &addr.to_string());
+}
+
+/// The order in the binary form of the client protocol, `None` for everything that doesn't
+/// change the book or the accounts.
+fn encode(order: &Order) -> Option<Vec<u8>> {
+    let mut res = Vec::new();
+    match order {
+        Order::Lmt(lmt) => {
+            res.push(0);
+            res.extend_from_slice(&lmt.lmt.to_le_bytes()[..]);
+            res.extend_from_slice(&lmt.amount.to_le_bytes()[..]);
+        }
+        Order::Market(mkt) => {
+            res.push(1);
+            res.extend_from_slice(&mkt.amount.to_le_bytes()[..]);
+        }
+        Order::Cncl(cncl) => {
+            res.push(2);
+            res.extend_from_slice(&cncl.order_id.to_le_bytes()[..]);
+        }
+        Order::Hidden(hid) => {
+            res.push(3);
+            res.extend_from_slice(&hid.amount.to_le_bytes()[..]);
+            res.extend_from_slice(&hid.lmt.to_le_bytes()[..]);
+        }
+        Order::Replace(rpl) => {
+            res.push(4);
+            res.extend_from_slice(&rpl.order_id.to_le_bytes()[..]);
+            res.extend_from_slice(&rpl.lmt.to_le_bytes()[..]);
+            res.extend_from_slice(&rpl.amount.to_le_bytes()[..]);
+        }
+        Order::Transfer(transfer) => {
+            res.push(9);
+            res.extend_from_slice(&transfer.amount.to_le_bytes()[..]);
+            push_str(&mut res, &transfer.to);
+        }
+        _ => return None,
+    }
+    Some(res)
+}
+
+impl Journal {
+    /// Appends to the journal at `path`, marking that the engine (re)started. A record cut
+    /// short by a crash is cut off first, the records after it would be read as its rest.
+    pub(crate) fn open(path: &str, standbys: Standbys) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let len = complete(&std::fs::read(path)?) as u64;
+        file.set_len(len)?;
+        let mut journal = Self {
+            file,
+            len,
+            standbys,
+        };
+        journal.write(&[START]);
+        Ok(journal)
+    }
+
+    fn write(&mut self, record: &[u8]) {
+        let mut buf = (record.len() as u32).to_le_bytes().to_vec();
+        buf.extend_from_slice(record);
+        // a standby catching up reads the file while holding this, it sees every record once
+        let mut standbys = self.standbys.get();
+        // UNWRAP: whatever the engine does without a journal entry could never be recovered
+        self.file.write_all(&buf).unwrap();
+        self.len += buf.len() as u64;
+        // a standby that can't keep up is dropped, it catches up again when it reconnects
+        standbys.retain_mut(|stream| stream.write_all(&buf).is_ok());
+    }
+
+    /// Where the next record goes, what a snapshot taken now covers.
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Called with every order the engine takes off the channel, before it runs it.
+    pub(crate) fn order(&mut self, addr: SocketAddr, order: &Order) {
+        let mut record = Vec::new();
+        match order {
+            Order::Logon(account) => {
+                record.push(LOGON);
+                push_addr(&mut record, &addr);
+                push_str(&mut record, account);
+            }
+            Order::Logout => {
+                record.push(LOGOUT);
+                push_addr(&mut record, &addr);
+            }
+            Order::Rebind(from) => {
+                record.push(REBIND);
+                push_addr(&mut record, &addr);
+                push_addr(&mut record, from);
+            }
+            Order::Admin(cmd, _) => {
+                record.push(ADMIN);
+                match cmd {
+                    AdminCommand::Book => return,
+                    AdminCommand::Cancel(order_id) => {
+                        record.push(ADMIN_CANCEL);
+                        record.extend_from_slice(&order_id.to_le_bytes()[..]);
+                    }
+                    AdminCommand::Halt => record.push(ADMIN_HALT),
+                    AdminCommand::Resume => record.push(ADMIN_RESUME),
+                    AdminCommand::Adjust {
+                        account,
+                        money,
+                        position,
+                    } => {
+                        record.push(ADMIN_ADJUST);
+                        push_str(&mut record, account);
+                        record.extend_from_slice(&money.to_le_bytes()[..]);
+                        record.extend_from_slice(&position.to_le_bytes()[..]);
+                    }
+                    AdminCommand::Kick(account) => {
+                        record.push(ADMIN_KICK);
+                        push_str(&mut record, account);
+                    }
+                    AdminCommand::Policy {
+                        name,
+                        money,
+                        position,
+                    } => {
+                        record.push(ADMIN_POLICY);
+                        push_str(&mut record, name);
+                        record.extend_from_slice(&money.to_le_bytes()[..]);
+                        record.extend_from_slice(&position.to_le_bytes()[..]);
+                    }
+                    AdminCommand::Provision {
+                        account,
+                        policy,
+                        role,
+                        entitlements,
+                    } => {
+                        record.push(ADMIN_PROVISION);
+                        push_str(&mut record, account);
+                        push_str(&mut record, policy);
+                        record.push(role.to_u8());
+                        record.push(*entitlements);
+                    }
+                    AdminCommand::Limit { kind, max } => {
+                        record.push(ADMIN_LIMIT);
+                        push_str(&mut record, kind);
+                        let max = max.unwrap_or(f64::NAN);
+                        record.extend_from_slice(&max.to_le_bytes()[..]);
+                    }
+                    AdminCommand::Cash { account, amount } => {
+                        record.push(ADMIN_CASH);
+                        push_str(&mut record, account);
+                        record.extend_from_slice(&amount.to_le_bytes()[..]);
+                    }
+                }
+            }
+            _ => match encode(order) {
+                Some(order) => {
+                    record.push(ORDER);
+                    push_addr(&mut record, &addr);
+                    record.extend_from_slice(&order);
+                }
+                None => return,
+            },
+        }
+        self.write(&record);
+    }
+
+    /// Called at the end of every cycle, before the engine ends the sessions in `expired`.
+    pub(crate) fn cycle(&mut self, expired: &[SocketAddr]) {
+        let mut record = vec![CYCLE];
+        record.extend_from_slice(&(expired.len() as u16).to_le_bytes()[..]);
+        expired.iter().for_each(|addr| push_addr(&mut record, addr));
+        self.write(&record);
+        // UNWRAP: the cycle is what clients get confirmed, it has to be on disk before that
+        self.file.sync_data().unwrap();
+    }
+}
+
+/// How much of `journal` are whole records.
+fn complete(journal: &[u8]) -> usize {
+    let mut at = 0;
+    while let Some(len) = journal.get(at..at + 4) {
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        if journal.len() < at + 4 + len {
+            break;
+        }
+        at += 4 + len;
+    }
+    at
+}
+
+/// Reads the records of a journal back. A record cut short by a crash ends the journal.
+pub(crate) fn read(journal: &[u8]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut at = 0;
+    while let Some(len) = journal.get(at..at + 4) {
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        let record = match journal.get(at + 4..at + 4 + len) {
+            Some(record) => record,
+            None => break,
+        };
+        at += 4 + len;
+        match decode(record) {
+            Some(entry) => entries.push(entry),
+            None => break,
+        }
+    }
+    entries
+}
+
+/// Reads the string at `at` and moves past it.
+pub(crate) fn read_str(record: &[u8], at: &mut usize) -> Option<String> {
+    let len = *record.get(*at)? as usize;
+    let s = std::str::from_utf8(record.get(*at + 1..*at + 1 + len)?).ok()?;
+    *at += 1 + len;
+    Some(s.to_string())
+}
+
+/// Reads the address at `at` and moves past it.
+pub(crate) fn read_addr(record: &[u8], at: &mut usize) -> Option<SocketAddr> {
+    read_str(record, at)?.parse().ok()
+}
+
+/// One record without its length.
+pub(crate) fn decode(record: &[u8]) -> Option<Entry> {
+    let mut at = 1;
+
+    Some(match *record.first()? {
+        START => Entry::Start,
+        ORDER => {
+            let addr = read_addr(record, &mut at)?;
+            // the parsers want their zero padding
+            let mut order = record[at..].to_vec();
+            order.resize(order.len() + 32, 0);
+            // whether the peer may send hidden orders was decided when it was journaled
+            Entry::Order(addr, crate::parse_order(&order, true)?)
+        }
+        LOGON => {
+            let addr = read_addr(record, &mut at)?;
+            Entry::Order(addr, Order::Logon(read_str(record, &mut at)?))
+        }
+        LOGOUT => Entry::Order(read_addr(record, &mut at)?, Order::Logout),
+        REBIND => {
+            let to = read_addr(record, &mut at)?;
+            Entry::Order(to, Order::Rebind(read_addr(record, &mut at)?))
+        }
+        ADMIN => {
+            let num = |at: usize| Some(record.get(at..at + 8)?.try_into().unwrap());
+            let cmd = match *record.get(1)? {
+                ADMIN_CANCEL => AdminCommand::Cancel(isize::from_le_bytes(num(2)?)),
+                ADMIN_HALT => AdminCommand::Halt,
+                ADMIN_RESUME => AdminCommand::Resume,
+                ADMIN_ADJUST => {
+                    at = 2;
+                    let account = read_str(record, &mut at)?;
+                    AdminCommand::Adjust {
+                        account,
+                        money: f64::from_le_bytes(num(at)?),
+                        position: isize::from_le_bytes(num(at + 8)?),
+                    }
+                }
+                ADMIN_KICK => {
+                    at = 2;
+                    AdminCommand::Kick(read_str(record, &mut at)?)
+                }
+                ADMIN_POLICY => {
+                    at = 2;
+                    let name = read_str(record, &mut at)?;
+                    AdminCommand::Policy {
+                        name,
+                        money: f64::from_le_bytes(num(at)?),
+                        position: isize::from_le_bytes(num(at + 8)?),
+                    }
+                }
+                ADMIN_PROVISION => {
+                    at = 2;
+                    let account = read_str(record, &mut at)?;
+                    let policy = read_str(record, &mut at)?;
+                    AdminCommand::Provision {
+                        account,
+                        policy,
+                        role: Role::from_u8(*record.get(at)?)?,
+                        entitlements: *record.get(at + 1)?,
+                    }
+                }
+                ADMIN_LIMIT => {
+                    at = 2;
+                    let kind = read_str(record, &mut at)?;
+                    let max = f64::from_le_bytes(num(at)?);
+                    AdminCommand::Limit {
+                        kind,
+                        max: (!max.is_nan()).then_some(max),
+                    }
+                }
+                ADMIN_CASH => {
+                    at = 2;
+                    let account = read_str(record, &mut at)?;
+                    AdminCommand::Cash {
+                        account,
+                        amount: f64::from_le_bytes(num(at)?),
+                    }
+                }
+                _ => return None,
+            };
+            // nobody waits for the answer
+            let (reply, _) = channel();
+            Entry::Order(
+                SocketAddr::from(([0, 0, 0, 0], 0)),
+                Order::Admin(cmd, reply),
+            )
+        }
+        CYCLE => {
+            let count = u16::from_le_bytes(record.get(1..3)?.try_into().unwrap());
+            at = 3;
+            let expired = (0..count)
+                .map(|_| read_addr(record, &mut at))
+                .collect::<Option<Vec<_>>>()?;
+            Entry::Cycle(expired)
+        }
+        _ => return None,
+    })
+}
+
+/// The state the engine ends up with after the journal.
+#[derive(Debug)]
+pub(crate) struct Restored {
+    pub order_book: OrderBook,
+    pub accounts: Accounts,
+    pub halted: bool,
+    /// the journal records that were run through the engine after the snapshot
+    pub records: usize,
+}
+
+impl Restored {
+    /// An empty book and no accounts, where a journal without a snapshot starts.
+    pub(crate) fn new() -> Self {
+        Self {
+            order_book: OrderBook::new(),
+            accounts: Accounts::new(Default::default()),
+            halted: false,
+            records: 0,
+        }
+    }
+
+    /// Runs one journal record through the engine, `feed` and `out` only take what it would
+    /// have sent.
+    pub(crate) fn apply(
+        &mut self,
+        feed: &mut marketdata::Feed,
+        out: &mut SessionTable,
+        entry: Entry,
+    ) {
+        self.records += 1;
+        match entry {
+            Entry::Start => self.accounts.get().logout_all(),
+            Entry::Order(addr, order) => crate::execute(
+                &mut self.order_book,
+                feed,
+                &self.accounts,
+                out,
+                &mut self.halted,
+                addr,
+                order,
+            ),
+            Entry::Cycle(expired) => {
+                crate::end_cycle(
+                    &mut self.order_book,
+                    feed,
+                    &self.accounts,
+                    out,
+                    self.halted,
+                    b"",
+                    &expired,
+                );
+            }
+        }
+        // the live engine put these in the ledger already
+        self.order_book.tape.executions.clear();
+        self.order_book.events.ledger.clear();
+        self.accounts.get().transfers.clear();
+    }
+}
+
+/// Runs the journal at `path` through the engine without any network I/O, starting from the
+/// snapshot at `snapshot` and only the records after it if there is one. A missing journal
+/// leaves an empty book and no accounts.
+pub(crate) fn restore(path: &str, snapshot: &str) -> Restored {
+    let journal = std::fs::read(path).unwrap_or_default();
+
+    let (mut restored, offset) = match snapshot::load(snapshot) {
+        Some(snapshot) => (
+            Restored {
+                order_book: snapshot.order_book,
+                accounts: Accounts::new(snapshot.accounts),
+                halted: snapshot.halted,
+                records: 0,
+            },
+            snapshot.offset as usize,
+        ),
+        None => (Restored::new(), 0),
+    };
+    let mut feed = marketdata::Feed::new(None, stats::Stats::default());
+    let mut out = SessionTable::offline();
+
+    for entry in read(journal.get(offset..).unwrap_or_default()) {
+        restored.apply(&mut feed, &mut out, entry);
+    }
+    restored
+}
+
+/// Replay mode: restores the state from the snapshot and the journal at `path` the way the
+/// engine does on startup and prints the accounts and the book it ends up with.
+pub(crate) fn replay(path: &str) {
+    let restored = restore(path, snapshot::SNAPSHOT);
+
+    let state = Json::object()
+        .with("records", restored.records as isize)
+        .with("accounts", admin::accounts_json(&restored.accounts.get()))
+        .with(
+            "book",
+            admin::book_json(&restored.order_book, restored.halted),
+        );
+    println!("{}", state);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CancleOrder, LimitOrder, MarketOrder, ReplaceOrder, TransferOrder};
+
+    fn path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("mm-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    /// `addr(0)` is where admin commands come from.
+    fn addr(port: u16) -> SocketAddr {
+        match port {
+            0 => SocketAddr::from(([0, 0, 0, 0], 0)),
+            port => SocketAddr::from(([127, 0, 0, 1], port)),
+        }
+    }
+
+    fn admin(cmd: AdminCommand) -> Order {
+        Order::Admin(cmd, channel().0)
+    }
+
+    #[test]
+    fn orders_encode_the_way_clients_send_them() {
+        let mut lmt = vec![0];
+        lmt.extend_from_slice(&101.5f64.to_le_bytes()[..]);
+        lmt.extend_from_slice(&(-20isize).to_le_bytes()[..]);
+        let mut transfer = vec![9];
+        transfer.extend_from_slice(&12.5f64.to_le_bytes()[..]);
+        push_str(&mut transfer, "bob");
+
+        for payload in [lmt, transfer] {
+            let mut padded = payload.clone();
+            padded.resize(padded.len() + 32, 0);
+            let order = crate::parse_order(&padded, false).unwrap();
+            assert_eq!(encode(&order).unwrap(), payload);
+        }
+        assert!(encode(&Order::Logout).is_none());
+    }
+
+    #[test]
+    fn records_read_back() {
+        let path = path("journal-records");
+        let orders = vec![
+            (addr(1), Order::Logon("alice".to_string())),
+            (
+                addr(1),
+                Order::Lmt(LimitOrder {
+                    lmt: 99.5,
+                    amount: 10,
+                }),
+            ),
+            (addr(1), Order::Market(MarketOrder { amount: -3 })),
+            (addr(1), Order::Cncl(CancleOrder { order_id: 4 })),
+            (
+                addr(1),
+                Order::Replace(ReplaceOrder {
+                    order_id: 4,
+                    lmt: 100.0,
+                    amount: 5,
+                }),
+            ),
+            (
+                addr(1),
+                Order::Transfer(TransferOrder {
+                    amount: 12.5,
+                    to: "bob".to_string(),
+                }),
+            ),
+            (addr(2), Order::Rebind(addr(1))),
+            (addr(2), Order::Logout),
+            (
+                addr(0),
+                admin(AdminCommand::Provision {
+                    account: "bob".to_string(),
+                    policy: "default".to_string(),
+                    role: Role::MarketMaker,
+                    entitlements: 3,
+                }),
+            ),
+            (
+                addr(0),
+                admin(AdminCommand::Limit {
+                    kind: "transfer".to_string(),
+                    max: None,
+                }),
+            ),
+            (
+                addr(0),
+                admin(AdminCommand::Cash {
+                    account: "bob".to_string(),
+                    amount: -50.0,
+                }),
+            ),
+        ];
+
+        let mut journal = Journal::open(&path, Standbys::new(Vec::new())).unwrap();
+        for (addr, order) in &orders {
+            journal.order(*addr, order);
+        }
+        // nothing to replay in a look at the book
+        journal.order(addr(0), &admin(AdminCommand::Book));
+        journal.cycle(&[addr(2)]);
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(journal.len(), bytes.len() as u64);
+        let entries = read(&bytes);
+        assert_eq!(entries.len(), 1 + orders.len() + 1);
+        assert!(matches!(entries[0], Entry::Start));
+        for (entry, (addr, order)) in entries[1..].iter().zip(&orders) {
+            assert_eq!(
+                format!("{:?}", entry),
+                format!("Order({:?}, {:?})", addr, order)
+            );
+        }
+        assert!(
+            matches!(&entries[entries.len() - 1], Entry::Cycle(expired) if expired == &[addr(2)])
+        );
+
+        // a record cut short ends the journal
+        assert_eq!(read(&bytes[..bytes.len() - 1]).len(), entries.len() - 1);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn reopening_cuts_off_a_torn_record() {
+        let path = path("journal-torn");
+        let mut journal = Journal::open(&path, Standbys::new(Vec::new())).unwrap();
+        journal.order(addr(1), &Order::Logon("alice".to_string()));
+        journal.cycle(&[]);
+        let whole = journal.len();
+        journal.order(addr(1), &Order::Market(MarketOrder { amount: 3 }));
+        drop(journal);
+
+        // the crash left only part of the market order
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(whole + 6).unwrap();
+
+        let mut journal = Journal::open(&path, Standbys::new(Vec::new())).unwrap();
+        journal.order(addr(1), &Order::Logout);
+        journal.cycle(&[addr(2)]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), journal.len());
+
+        let entries = read(&std::fs::read(&path).unwrap());
+        let entries: Vec<String> = entries.iter().map(|e| format!("{:?}", e)).collect();
+        assert_eq!(
+            entries,
+            [
+                "Start".to_string(),
+                format!("Order({:?}, Logon(\"alice\"))", addr(1)),
+                "Cycle([])".to_string(),
+                "Start".to_string(),
+                format!("Order({:?}, Logout)", addr(1)),
+                format!("Cycle([{:?}])", addr(2)),
+            ]
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn replay_ends_where_the_engine_was() {
+        let snapshot = path("journal-replay-snapshot");
+        let path = path("journal-replay");
+        let mut journal = Journal::open(&path, Standbys::new(Vec::new())).unwrap();
+        let mut live = Restored::new();
+        let mut feed = marketdata::Feed::new(None, stats::Stats::default());
+        let mut out = SessionTable::offline();
+
+        let lmt = |lmt, amount| Order::Lmt(LimitOrder { lmt, amount });
+        let cycles = vec![
+            vec![
+                (addr(1), Order::Logon("alice".to_string())),
+                (addr(2), Order::Logon("bob".to_string())),
+                (
+                    addr(0),
+                    admin(AdminCommand::Adjust {
+                        account: "bob".to_string(),
+                        money: 0.0,
+                        position: 5,
+                    }),
+                ),
+                (addr(1), lmt(100.0, 10)),
+                (addr(1), lmt(110.0, -5)),
+                (addr(2), Order::Market(MarketOrder { amount: -4 })),
+                (addr(2), lmt(105.0, 3)),
+            ],
+            vec![
+                (
+                    addr(2),
+                    Order::Transfer(TransferOrder {
+                        amount: 25.0,
+                        to: "alice".to_string(),
+                    }),
+                ),
+                (
+                    addr(0),
+                    admin(AdminCommand::Cash {
+                        account: "bob".to_string(),
+                        amount: 100.0,
+                    }),
+                ),
+                (addr(1), Order::Cncl(CancleOrder { order_id: 1 })),
+                (addr(2), Order::Market(MarketOrder { amount: 2 })),
+                (addr(3), Order::Rebind(addr(2))),
+                (addr(3), lmt(95.0, -1)),
+            ],
+        ];
+
+        // what the engine's loop does, minus the network
+        for cycle in cycles {
+            for (addr, order) in cycle {
+                journal.order(addr, &order);
+                crate::execute(
+                    &mut live.order_book,
+                    &mut feed,
+                    &live.accounts,
+                    &mut out,
+                    &mut live.halted,
+                    addr,
+                    order,
+                );
+            }
+            journal.cycle(&[addr(1)]);
+            crate::end_cycle(
+                &mut live.order_book,
+                &mut feed,
+                &live.accounts,
+                &mut out,
+                live.halted,
+                b"",
+                &[addr(1)],
+            );
+        }
+
+        // bob's market sale went to alice's bid
+        assert_eq!(live.accounts.get().accounts["alice"].position, 4);
+
+        let replayed = restore(&path, &snapshot);
+        assert_eq!(replayed.records, 1 + 13 + 2);
+        assert_eq!(
+            admin::accounts_json(&replayed.accounts.get()).to_string(),
+            admin::accounts_json(&live.accounts.get()).to_string()
+        );
+        assert_eq!(
+            admin::book_json(&replayed.order_book, replayed.halted).to_string(),
+            admin::book_json(&live.order_book, live.halted).to_string()
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+}
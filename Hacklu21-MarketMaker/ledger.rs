@@ -0,0 +1,484 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+
+use crate::account::AccountTable;
+use crate::marketdata::{self, BookEvent};
+use crate::parquet::{self, Column};
+use crate::{journal, snapshot, OrderBook, FLOATING_TO_FIXED_OFF};
+
+/// Where the engine keeps its ledger, next to the journal.
+pub(crate) const LEDGER: &str = "ledger";
+
+const NS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+/// The exchange charges no fees, the column is there for the analytics pipeline.
+const FEE: f64 = 0.0;
+/// The other side of a fill against the exchange, and of deposits and withdrawals.
+pub(crate) const EXCHANGE: &str = "exchange";
+
+const TRADES: &[(&str, Kind)] = &[
+    ("trade_id", Kind::Int),
+    ("timestamp", Kind::Int),
+    ("instrument", Kind::Text),
+    ("price", Kind::Double),
+    ("amount", Kind::Int),
+    ("buyer", Kind::Text),
+    ("seller", Kind::Text),
+    ("aggressor", Kind::Text),
+    ("fee", Kind::Double),
+];
+const EVENTS: &[(&str, Kind)] = &[
+    ("timestamp", Kind::Int),
+    ("event", Kind::Text),
+    ("order_id", Kind::Int),
+    ("side", Kind::Text),
+    ("amount", Kind::Int),
+    ("price", Kind::Double),
+    ("match_id", Kind::Int),
+    ("new_order_id", Kind::Int),
+];
+const TRANSFERS: &[(&str, Kind)] = &[
+    ("timestamp", Kind::Int),
+    ("from", Kind::Text),
+    ("to", Kind::Text),
+    ("amount", Kind::Double),
+];
+const POSITIONS: &[(&str, Kind)] = &[
+    ("day", Kind::Text),
+    ("timestamp", Kind::Int),
+    ("account", Kind::Text),
+    ("money", Kind::Double),
+    ("position", Kind::Int),
+];
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Int,
+    Double,
+    Text,
+}
+
+/// One fill, the price is fixed point.
+#[derive(Debug)]
+pub(crate) struct Execution {
+    pub trade_id: u64,
+    pub timestamp: u64,
+    pub price: isize,
+    pub amount: isize,
+    pub aggressor: u8,
+    /// account ids, `None` is the exchange
+    pub buyer: Option<String>,
+    pub seller: Option<String>,
+}
+
+/// The day `timestamp` (ns since the epoch) falls on, `YYYY-MM-DD` in UTC.
+fn date(timestamp: u64) -> String {
+    // days to civil date, proleptic gregorian calendar with years starting in march
+    let z = (timestamp / NS_PER_DAY) as i64 + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+fn price(price: isize) -> f64 {
+    price as f64 / FLOATING_TO_FIXED_OFF
+}
+
+fn account(id: &Option<String>) -> &str {
+    id.as_deref().unwrap_or(EXCHANGE)
+}
+
+/// Append-only record of every execution and order event of the live engine and of the
+/// positions at the end of every day, one CSV line each:
+///
+/// ```text
+/// T,trade id,timestamp,instrument,price,amount,buyer,seller,aggressor,fee
+/// E,timestamp,event,order id,side,amount,price,match id,new order id
+/// C,timestamp,from,to,amount
+/// P,day,timestamp,account,money,position
+/// ```
+///
+/// Timestamps are ns since the epoch, days are UTC. Cash movements other than trades are C,
+/// deposits come from and withdrawals go to the exchange. Events are the ones of the order events
+/// feed, what doesn't apply to one is 0, a side `-`. Only the live engine writes it, neither a
+/// replay nor a standby that follows its primary does, the ledger of a standby starts when it takes
+/// over.
+#[derive(Debug)]
+pub(crate) struct Ledger {
+    file: File,
+    /// the day of the last cycle
+    day: String,
+}
+
+impl Ledger {
+    pub(crate) fn open(path: &str) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file,
+            day: date(marketdata::now()),
+        })
+    }
+
+    fn write(&mut self, lines: String) {
+        if lines.is_empty() {
+            return;
+        }
+        // UNWRAP: an execution missing from the ledger would go unnoticed until the export
+        self.file.write_all(lines.as_bytes()).unwrap();
+    }
+
+    /// Takes the executions and the order events the book collected and the transfers between
+    /// accounts since the last call.
+    pub(crate) fn record(&mut self, order_book: &mut OrderBook, accounts: &mut AccountTable) {
+        let mut lines = String::new();
+        for exec in order_book.tape.executions.drain(..) {
+            lines += &format!(
+                "T,{},{},{},{},{},{},{},{},{}\n",
+                exec.trade_id,
+                exec.timestamp,
+                marketdata::INSTRUMENT,
+                price(exec.price),
+                exec.amount,
+                account(&exec.buyer),
+                account(&exec.seller),
+                exec.aggressor as char,
+                FEE,
+            );
+        }
+        for (timestamp, event) in order_book.events.ledger.drain(..) {
+            // event, order id, side, amount, price, match id, new order id
+            let (kind, order_id, side, amount, px, match_id, new_order_id) = match event {
+                BookEvent::Add {
+                    order_id,
+                    side,
+                    amount,
+                    price,
+                } => ('A', order_id, side as char, amount, price, 0, 0),
+                BookEvent::Executed {
+                    order_id,
+                    amount,
+                    match_id,
+                } => ('E', order_id, '-', amount, 0, match_id, 0),
+                BookEvent::Cancel { order_id, amount } => ('X', order_id, '-', amount, 0, 0, 0),
+                BookEvent::Replace {
+                    order_id,
+                    new_order_id,
+                    amount,
+                    price,
+                } => ('U', order_id, '-', amount, price, 0, new_order_id),
+                BookEvent::Trade {
+                    side,
+                    amount,
+                    price,
+                    match_id,
+                } => ('P', 0, side as char, amount, price, match_id, 0),
+            };
+            lines += &format!(
+                "E,{},{},{},{},{},{},{},{}\n",
+                timestamp,
+                kind,
+                order_id,
+                side,
+                amount,
+                price(px),
+                match_id,
+                new_order_id,
+            );
+        }
+        for transfer in accounts.transfers.drain(..) {
+            lines += &format!(
+                "C,{},{},{},{}\n",
+                transfer.timestamp,
+                account(&transfer.from),
+                account(&transfer.to),
+                transfer.amount,
+            );
+        }
+        self.write(lines);
+    }
+
+    /// Called at the end of every cycle, writes everyone's position once a day is over.
+    pub(crate) fn cycle(&mut self, accounts: &AccountTable) {
+        let now = marketdata::now();
+        let today = date(now);
+        if today == self.day {
+            return;
+        }
+        let lines = positions(&self.day, now, accounts)
+            .iter()
+            .map(|row| format!("P,{}\n", row.join(",")))
+            .collect();
+        self.write(lines);
+        // UNWRAP: same as for `write`, the day is only closed once its positions are on disk
+        self.file.sync_data().unwrap();
+        self.day = today;
+    }
+}
+
+fn positions(day: &str, now: u64, accounts: &AccountTable) -> Vec<Vec<String>> {
+    accounts
+        .accounts
+        .values()
+        .map(|account| {
+            vec![
+                day.to_string(),
+                now.to_string(),
+                account.id.clone(),
+                account.money.to_string(),
+                account.position.to_string(),
+            ]
+        })
+        .collect()
+}
+
+/// Writes `rows` to `<dir>/<name>-<day>.csv` and `.parquet`.
+fn table(
+    dir: &str,
+    name: &str,
+    day: &str,
+    columns: &[(&str, Kind)],
+    rows: &[Vec<String>],
+) -> std::io::Result<()> {
+    let path = format!("{}/{}-{}", dir, name, day);
+
+    let mut csv = columns
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(",");
+    csv.push('\n');
+    for row in rows {
+        csv += &row.join(",");
+        csv.push('\n');
+    }
+    File::create(format!("{}.csv", path))?.write_all(csv.as_bytes())?;
+
+    let values = |at: usize| rows.iter().map(move |row| row[at].as_str());
+    let columns: Vec<_> = columns
+        .iter()
+        .enumerate()
+        .map(|(at, (name, kind))| {
+            let column = match kind {
+                Kind::Int => Column::Int(values(at).map(|v| v.parse().unwrap_or(0)).collect()),
+                Kind::Double => {
+                    Column::Double(values(at).map(|v| v.parse().unwrap_or(0.0)).collect())
+                }
+                Kind::Text => Column::Text(values(at).map(str::to_string).collect()),
+            };
+            (*name, column)
+        })
+        .collect();
+    parquet::write(&format!("{}.parquet", path), &columns)
+}
+
+/// The trades, the order events, the transfers and the end of day positions of `day` in the
+/// ledger, each row without the record type.
+fn on_day(ledger: &str, day: &str) -> [Vec<Vec<String>>; 4] {
+    let (mut trades, mut events, mut transfers, mut positions) =
+        (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    for line in ledger.lines() {
+        let fields: Vec<String> = line.split(',').map(str::to_string).collect();
+        let on_day = |at: usize, len: usize| {
+            fields.len() == len + 1 && fields[at + 1].parse().is_ok_and(|ts: u64| date(ts) == day)
+        };
+        match fields[0].as_str() {
+            "T" if on_day(1, TRADES.len()) => trades.push(fields[1..].to_vec()),
+            "E" if on_day(0, EVENTS.len()) => events.push(fields[1..].to_vec()),
+            "C" if on_day(0, TRANSFERS.len()) => transfers.push(fields[1..].to_vec()),
+            "P" if fields.len() == POSITIONS.len() + 1 && fields[1] == day => {
+                positions.push(fields[1..].to_vec())
+            }
+            _ => (),
+        }
+    }
+    [trades, events, transfers, positions]
+}
+
+/// Export mode: writes the trades, the order events, the transfers and the end of day
+/// positions of `day` (`YYYY-MM-DD`, UTC) from the ledger to `trades-<day>`, `events-<day>`,
+/// `transfers-<day>` and `positions-<day>` in `dir`, each as `.csv` and `.parquet`. For a day that
+/// isn't over yet the positions are the ones right now, restored from the snapshot and the journal.
+pub(crate) fn export(day: &str, dir: &str) {
+    let ledger = std::fs::read_to_string(LEDGER).unwrap_or_default();
+    let [trades, events, transfers, mut positions] = on_day(&ledger, day);
+
+    let now = marketdata::now();
+    if positions.is_empty() && date(now) == day {
+        let restored = journal::restore(journal::JOURNAL, snapshot::SNAPSHOT);
+        positions = self::positions(day, now, &restored.accounts.get());
+    }
+
+    for (name, columns, rows) in [
+        ("trades", TRADES, &trades),
+        ("events", EVENTS, &events),
+        ("transfers", TRANSFERS, &transfers),
+        ("positions", POSITIONS, &positions),
+    ] {
+        table(dir, name, day, columns, rows).unwrap();
+        println!("{}/{}-{}: {} rows", dir, name, day, rows.len());
+    }
+}
+
+/// Today, for an export without a day.
+pub(crate) fn today() -> String {
+    date(marketdata::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::Transfer;
+
+    const DAY: u64 = NS_PER_DAY;
+
+    fn temp(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("mm-{}-{}", name, std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn dates_are_utc_days() {
+        let second = 1_000_000_000;
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(DAY - 1), "1970-01-01");
+        assert_eq!(date(946598400 * second), "1999-12-31");
+        assert_eq!(date(951782400 * second), "2000-02-29");
+        assert_eq!(date(951868800 * second - 1), "2000-02-29");
+        assert_eq!(date(951868800 * second), "2000-03-01");
+        assert_eq!(date(1709164800 * second), "2024-02-29");
+        // not a leap year
+        assert_eq!(date(4107542400 * second - DAY), "2100-02-28");
+        assert_eq!(date(4107542400 * second), "2100-03-01");
+    }
+
+    #[test]
+    fn everything_recorded_is_a_line() {
+        let path = temp("ledger");
+        let mut ledger = Ledger::open(&path).unwrap();
+        let mut order_book = OrderBook::new();
+        order_book.tape.executions.push(Execution {
+            trade_id: 7,
+            timestamp: 11,
+            price: 100_500,
+            amount: 2,
+            aggressor: marketdata::BUY,
+            buyer: Some("alice".to_string()),
+            seller: None,
+        });
+        let events = [
+            BookEvent::Add {
+                order_id: 3,
+                side: marketdata::SELL,
+                amount: 4,
+                price: 99_000,
+            },
+            BookEvent::Replace {
+                order_id: 3,
+                new_order_id: 5,
+                amount: 1,
+                price: 98_000,
+            },
+        ];
+        for event in events {
+            order_book.events.ledger.push((12, event));
+        }
+        let mut accounts = AccountTable::default();
+        accounts.transfers.push(Transfer {
+            timestamp: 13,
+            from: None,
+            to: Some("bob".to_string()),
+            amount: 50.5,
+        });
+
+        ledger.record(&mut order_book, &mut accounts);
+        // all of it was taken
+        ledger.record(&mut order_book, &mut accounts);
+        let lines = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            lines,
+            "T,7,11,MM,100.5,2,alice,exchange,B,0\n\
+             E,12,A,3,S,4,99,0,0\n\
+             E,12,U,3,-,1,98,0,5\n\
+             C,13,exchange,bob,50.5\n"
+        );
+    }
+
+    #[test]
+    fn the_export_only_takes_the_day() {
+        let ledger = format!(
+            "T,1,{day},MM,100,2,alice,bob,B,0\n\
+             T,2,{next},MM,100,2,alice,bob,B,0\n\
+             T,3,{day},MM,100\n\
+             E,{day},X,3,-,4,0,0,0\n\
+             E,{next},X,3,-,4,0,0,0\n\
+             C,{day},exchange,bob,50\n\
+             C,nonsense,exchange,bob,50\n\
+             P,1970-01-02,{day},alice,10000,0\n\
+             P,1970-01-03,{next},alice,10000,0\n\
+             \n\
+             Z,{day}\n",
+            day = DAY + 5,
+            next = 2 * DAY + 5,
+        );
+        let [trades, events, transfers, positions] = on_day(&ledger, "1970-01-02");
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0][..3], ["1", &(DAY + 5).to_string(), "MM"]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(positions.len(), 1);
+        assert_eq!(positions[0][0], "1970-01-02");
+        assert!(on_day(&ledger, "1970-01-04").iter().all(Vec::is_empty));
+    }
+
+    #[test]
+    fn tables_are_written_as_csv_and_parquet() {
+        let dir = temp("export");
+        std::fs::create_dir_all(&dir).unwrap();
+        let rows = vec![
+            vec![
+                "1970-01-02".into(),
+                "5".into(),
+                "alice".into(),
+                "10.5".into(),
+                "3".into(),
+            ],
+            vec![
+                "1970-01-02".into(),
+                "5".into(),
+                "bob".into(),
+                "-1".into(),
+                "x".into(),
+            ],
+        ];
+        table(&dir, "positions", "1970-01-02", POSITIONS, &rows).unwrap();
+
+        let path = format!("{}/positions-1970-01-02", dir);
+        let csv = std::fs::read_to_string(format!("{}.csv", path)).unwrap();
+        assert_eq!(
+            csv,
+            "day,timestamp,account,money,position\n\
+             1970-01-02,5,alice,10.5,3\n\
+             1970-01-02,5,bob,-1,x\n"
+        );
+        let file = std::fs::read(format!("{}.parquet", path)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            (&file[..4], &file[file.len() - 4..]),
+            (&b"PAR1"[..], &b"PAR1"[..])
+        );
+        // what doesn't parse is 0 in the typed columns, the position column is the last page
+        let position: Vec<u8> = [3i64, 0].iter().flat_map(|v| v.to_le_bytes()).collect();
+        let footer = file.len()
+            - 8
+            - u32::from_le_bytes(file[file.len() - 8..file.len() - 4].try_into().unwrap()) as usize;
+        assert_eq!(file[footer - 16..footer], position[..]);
+    }
+}
@@ -0,0 +1,329 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::crypto::{self, MAC_LEN};
+use crate::journal;
+use crate::MThread;
+
+/// client -> server: `[0x40][name len u8][name][nonce 16][proof 32]`
+/// with `proof = HMAC(password, name || nonce)`
+pub(crate) const LOGON: u8 = 0x40;
+/// server -> client: `[0x41][status u8][token u64][nonce 16][mac 32]`, status 0 means logged on.
+/// The session key is `HMAC(password, "session" || client nonce || server nonce)`, the response
+/// is MACed with it so the client knows it talks to someone that knows its password.
+pub(crate) const LOGON_RESPONSE: u8 = 0x41;
+/// client -> server, inside `AUTHENTICATED`: `[0x42]`
+pub(crate) const LOGOUT: u8 = 0x42;
+/// both directions, inside `AUTHENTICATED` on the way in: `[0x43]`
+pub(crate) const HEARTBEAT: u8 = 0x43;
+/// client -> server: `[0x44][token u64][frame][mac 32]`, every message after logon is wrapped in
+/// this. The MAC covers everything in front of it and `frame` has to be a session layer frame,
+/// its sequence number is what protects against replays.
+pub(crate) const AUTHENTICATED: u8 = 0x44;
+/// server -> client: `[0x45]`, the datagram was dropped because the peer is not logged on
+pub(crate) const NOT_LOGGED_ON: u8 = 0x45;
+
+/// A session that hasn't sent anything (orders or heartbeats) for this long gets logged out,
+/// unless its transport negotiated a longer interval, see `LogonTable::set_timeout`.
+pub(crate) const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many client nonces are remembered, the oldest are forgotten first.
+const SEEN_NONCES: usize = 1 << 16;
+
+#[derive(Debug)]
+pub(crate) struct LogonRequest {
+    pub account: String,
+    pub nonce: [u8; 16],
+    pub proof: [u8; MAC_LEN],
+}
+
+impl LogonRequest {
+    pub(crate) fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let (account, buf) = read_str(buf)?;
+        let nonce = buf.get(0..16)?.try_into().unwrap();
+        let proof = buf.get(16..16 + MAC_LEN)?.try_into().unwrap();
+
+        if account.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            account,
+            nonce,
+            proof,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct LogonGrant {
+    pub token: u64,
+    /// id of the account the peer logged on to
+    pub account: String,
+    pub key: [u8; MAC_LEN],
+    pub nonce: [u8; 16],
+}
+
+impl LogonGrant {
+    pub(crate) fn to_bytes(&self) -> [u8; 26 + MAC_LEN] {
+        let mut res = [0u8; 26 + MAC_LEN];
+        res[0] = LOGON_RESPONSE;
+        res[2..10].copy_from_slice(&self.token.to_le_bytes()[..]);
+        res[10..26].copy_from_slice(&self.nonce[..]);
+        let mac = crypto::hmac(&self.key, &[&res[..26]]);
+        res[26..].copy_from_slice(&mac[..]);
+        res
+    }
+}
+
+fn read_str(buf: &[u8]) -> Option<(String, &[u8])> {
+    let len = *buf.first()? as usize;
+    let s = buf.get(1..1 + len)?;
+    let s = String::from_utf8(s.to_vec()).ok()?;
+    Some((s, &buf[1 + len..]))
+}
+
+#[derive(Debug)]
+struct Logon {
+    account: String,
+    addr: SocketAddr,
+    last_seen: Instant,
+    timeout: Duration,
+    key: [u8; MAC_LEN],
+}
+
+/// Maps session tokens to the account they were issued for and the address the session
+/// currently trades from. The token, not the address, is what identifies a peer: a packet
+/// carrying a valid token from a new address moves the session over to that address.
+#[derive(Debug)]
+pub(crate) struct LogonTable {
+    credentials: BTreeMap<String, String>,
+    logons: BTreeMap<u64, Logon>,
+    random: RandomState,
+    issued: u64,
+    /// client nonces of the last `SEEN_NONCES` accepted logons, so a sniffed logon can't be
+    /// replayed to kick the legitimate session, and the order they came in
+    seen_nonces: BTreeSet<[u8; 16]>,
+    nonce_order: VecDeque<[u8; 16]>,
+    bad_macs: u64,
+    /// accounts the exchange closed, logons to them are refused
+    closed: BTreeSet<String>,
+}
+
+pub(crate) type Logons = MThread<LogonTable>;
+
+impl LogonTable {
+    /// Parses the `accounts` file, one `name:password` pair per line.
+    pub(crate) fn from_accounts(accounts: &str) -> Self {
+        let credentials = accounts
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, password)| (name.trim().to_string(), password.trim().to_string()))
+            .collect();
+
+        Self {
+            credentials,
+            logons: BTreeMap::new(),
+            random: RandomState::new(),
+            issued: 0,
+            seen_nonces: BTreeSet::new(),
+            nonce_order: VecDeque::new(),
+            bad_macs: 0,
+            closed: BTreeSet::new(),
+        }
+    }
+
+    fn new_token(&mut self) -> u64 {
+        loop {
+            self.issued += 1;
+            let mut hasher = self.random.build_hasher();
+            hasher.write_u64(self.issued);
+            let token = hasher.finish();
+            if token != 0 && !self.logons.contains_key(&token) {
+                return token;
+            }
+        }
+    }
+
+    /// Checks the logon proof and issues a fresh token and session key bound to the account.
+    ///
+    /// An account can be logged on any number of times from different addresses, every logon
+    /// is a session of its own. Logging on again from the same address replaces the logon
+    /// that was there.
+    pub(crate) fn logon(&mut self, addr: SocketAddr, req: &LogonRequest) -> Option<LogonGrant> {
+        let password = self.credentials.get(&req.account)?.as_bytes();
+        let proof = crypto::hmac(password, &[req.account.as_bytes(), &req.nonce[..]]);
+        if !crypto::verify(&req.proof, &proof) || self.seen_nonces.contains(&req.nonce) {
+            self.bad_macs += 1;
+            return None;
+        }
+
+        let nonce = crypto::nonce();
+        let key = crypto::hmac(password, &[b"session", &req.nonce[..], &nonce[..]]);
+
+        let grant = self.grant(addr, &req.account, key, nonce)?;
+        if self.nonce_order.len() >= SEEN_NONCES {
+            // UNWRAP: it's full
+            let oldest = self.nonce_order.pop_front().unwrap();
+            self.seen_nonces.remove(&oldest);
+        }
+        self.seen_nonces.insert(req.nonce);
+        self.nonce_order.push_back(req.nonce);
+        Some(grant)
+    }
+
+    /// Logon for transports that carry the plain password, like FIX does in `Password(554)`.
+    pub(crate) fn logon_password(
+        &mut self,
+        addr: SocketAddr,
+        account: &str,
+        password: &str,
+    ) -> Option<LogonGrant> {
+        if self.credentials.get(account).map(String::as_str) != Some(password) {
+            self.bad_macs += 1;
+            return None;
+        }
+
+        let nonce = crypto::nonce();
+        let key = crypto::hmac(password.as_bytes(), &[b"session", &nonce[..]]);
+        self.grant(addr, account, key, nonce)
+    }
+
+    fn grant(
+        &mut self,
+        addr: SocketAddr,
+        account: &str,
+        key: [u8; MAC_LEN],
+        nonce: [u8; 16],
+    ) -> Option<LogonGrant> {
+        if account.len() > journal::MAX_STR || self.closed.contains(account) {
+            return None;
+        }
+        if self
+            .logons
+            .values()
+            .any(|l| l.addr == addr && l.account != account)
+        {
+            return None;
+        }
+
+        self.logons.retain(|_, l| l.addr != addr);
+
+        let token = self.new_token();
+        self.logons.insert(
+            token,
+            Logon {
+                account: account.to_string(),
+                addr,
+                last_seen: Instant::now(),
+                timeout: HEARTBEAT_TIMEOUT,
+                key,
+            },
+        );
+
+        Some(LogonGrant {
+            token,
+            account: account.to_string(),
+            key,
+            nonce,
+        })
+    }
+
+    /// Validates `token` and the MAC of `datagram`. Returns the address the session is bound
+    /// to and the part of the datagram the MAC covered.
+    ///
+    /// A valid MAC only means the datagram came from the peer at some point, it may be a
+    /// replay. Neither the address nor the liveness change here, that's up to the session
+    /// layer once it knows the datagram is fresh, see `rebind` and `touch`.
+    pub(crate) fn authenticate<'a>(
+        &mut self,
+        token: u64,
+        datagram: &'a [u8],
+    ) -> Option<(SocketAddr, &'a [u8])> {
+        let logon = match self.logons.get_mut(&token) {
+            Some(logon) => logon,
+            None => {
+                self.bad_macs += 1;
+                return None;
+            }
+        };
+
+        let body = match crypto::open(&logon.key, datagram) {
+            Some(body) => body,
+            None => {
+                self.bad_macs += 1;
+                return None;
+            }
+        };
+
+        Some((logon.addr, body))
+    }
+
+    /// The peer of `token` moved to `addr` (e.g. a NAT rebinding its port).
+    pub(crate) fn rebind(&mut self, token: u64, addr: SocketAddr) {
+        if let Some(logon) = self.logons.get_mut(&token) {
+            logon.addr = addr;
+        }
+    }
+
+    /// Amount of datagrams that were dropped because of an unknown token or a bad MAC.
+    pub(crate) fn bad_macs(&self) -> u64 {
+        self.bad_macs
+    }
+
+    /// Refreshes the liveness of a session whose transport authenticates the peer by itself.
+    pub(crate) fn touch(&mut self, token: u64) -> bool {
+        match self.logons.get_mut(&token) {
+            Some(logon) => {
+                logon.last_seen = Instant::now();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Lets a session stay silent for `timeout` before it expires, for transports whose
+    /// heartbeat interval is negotiated. Never shorter than `HEARTBEAT_TIMEOUT`.
+    pub(crate) fn set_timeout(&mut self, token: u64, timeout: Duration) {
+        if let Some(logon) = self.logons.get_mut(&token) {
+            logon.timeout = timeout.max(HEARTBEAT_TIMEOUT);
+        }
+    }
+
+    pub(crate) fn logout(&mut self, token: u64) -> Option<SocketAddr> {
+        self.logons.remove(&token).map(|l| l.addr)
+    }
+
+    /// Ends whatever session is bound to `addr`, used when the exchange drops a client.
+    pub(crate) fn end(&mut self, addr: &SocketAddr) {
+        self.logons.retain(|_, l| l.addr != *addr);
+    }
+
+    /// Ends every session of `account`, used when it is kicked.
+    pub(crate) fn end_account(&mut self, account: &str) {
+        self.logons.retain(|_, l| l.account != account);
+    }
+
+    /// Ends every session of `account` and refuses logons to it from now on, used when the
+    /// exchange closes it.
+    pub(crate) fn close_account(&mut self, account: &str) {
+        self.end_account(account);
+        self.closed.insert(account.to_string());
+    }
+
+    /// Logs out every session that missed its heartbeats and returns their addresses.
+    pub(crate) fn expired(&mut self) -> Vec<SocketAddr> {
+        let mut expired = Vec::new();
+        self.logons.retain(|_, l| {
+            if l.last_seen.elapsed() > l.timeout {
+                expired.push(l.addr);
+                false
+            } else {
+                true
+            }
+        });
+        expired
+    }
+}
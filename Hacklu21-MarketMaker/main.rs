@@ -1,9 +1,35 @@
 #![allow(unused)]
 use std::collections::BTreeMap;
-use std::net::{SocketAddr, UdpSocket};
+use std::net::{SocketAddr, TcpListener, UdpSocket};
 use std::sync::mpsc::{channel, Sender};
 use std::sync::{Arc, Mutex, MutexGuard};
 
+mod account;
+mod admin;
+mod audit;
+mod crypto;
+mod fix;
+mod http;
+mod journal;
+mod json;
+mod ledger;
+mod logon;
+mod marketdata;
+mod multicast;
+mod parquet;
+mod replication;
+mod session;
+mod snapshot;
+mod stats;
+mod tcp;
+mod websocket;
+
+use account::{AccountTable, Accounts, Role};
+use audit::Audit;
+use logon::{LogonGrant, LogonRequest, LogonTable, Logons};
+use marketdata::BookEvent;
+use session::{SessionTable, Sessions};
+
 #[derive(Debug, Default)]
 pub(crate) struct MThread<T> {
     ptr: Arc<Mutex<T>>,
@@ -33,46 +59,6 @@ where
     }
 }
 
-#[derive(Debug)]
-struct Client {
-    pub addr: SocketAddr,
-    pub money: f64,
-    pub is_market_maker: bool,
-    pub net_liquidity_contribution: isize,
-    pub position: isize,
-    pub cycles_present: isize,
-}
-
-impl Client {
-    pub fn new(addr: SocketAddr) -> Self {
-        let money = if addr.ip().is_loopback() {
-            1e9
-        } else {
-            10000.0
-        };
-        Self {
-            cycles_present: 0,
-            addr,
-            money,
-            is_market_maker: false,
-            net_liquidity_contribution: 0,
-            position: 0,
-        }
-    }
-
-    pub fn to_bytes(&self) -> [u8; 25] {
-        let mut ret = [0; 25];
-
-        ret[0] = 0x21;
-
-        ret[1..9].copy_from_slice(&self.money.to_le_bytes()[..]);
-        ret[9..17].copy_from_slice(&self.net_liquidity_contribution.to_le_bytes()[..]);
-        ret[17..25].copy_from_slice(&self.position.to_le_bytes()[..]);
-
-        ret
-    }
-}
-
 #[derive(Debug)]
 struct LimitOrder {
     lmt: f64,
@@ -157,9 +143,60 @@ impl HiddenOrder {
     }
 }
 
+#[derive(Debug)]
+struct ReplaceOrder {
+    order_id: isize,
+    lmt: f64,
+    amount: isize,
+}
+
+impl ReplaceOrder {
+    fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() <= 24 {
+            return None;
+        }
+
+        let order_id = isize::from_le_bytes(buf[0..8].try_into().unwrap());
+        let lmt = f64::from_le_bytes(buf[8..16].try_into().unwrap());
+        let amount = isize::from_le_bytes(buf[16..24].try_into().unwrap());
+
+        if lmt.is_nan() || lmt.is_sign_negative() {
+            return None;
+        }
+
+        if amount == 0 || amount.abs() > 10000 {
+            return None;
+        }
+
+        Some(Self {
+            order_id,
+            lmt,
+            amount,
+        })
+    }
+}
+
+/// Money to another account, `[amount f64][len u8][account id]`.
+#[derive(Debug)]
+struct TransferOrder {
+    amount: f64,
+    to: String,
+}
+
+impl TransferOrder {
+    fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let amount = f64::from_le_bytes(buf.get(0..8)?.try_into().unwrap());
+        let len = *buf.get(8)? as usize;
+        let to = std::str::from_utf8(buf.get(9..9 + len)?).ok()?.to_string();
+
+        Some(Self { amount, to })
+    }
+}
+
 #[derive(Debug)]
 struct BookEntry {
-    client: SocketAddr,
+    /// id of the account the order belongs to
+    account: String,
     amount: isize,
     id: isize,
     cycles_present: isize,
@@ -170,9 +207,13 @@ struct OrderBook {
     bids: BTreeMap<isize, Vec<BookEntry>>,
     asks: BTreeMap<isize, Vec<BookEntry>>,
     inc_id: isize,
+    tape: marketdata::Tape,
+    events: marketdata::BookEvents,
 }
 
 const FLOATING_TO_FIXED_OFF: f64 = 1000.0;
+/// The binary protocol, a standby only takes over once nothing holds this anymore.
+const UDP_ADDR: &str = "0.0.0.0:14550";
 
 impl OrderBook {
     fn new() -> Self {
@@ -180,17 +221,20 @@ impl OrderBook {
             bids: BTreeMap::new(),
             asks: BTreeMap::new(),
             inc_id: 0,
+            tape: marketdata::Tape::default(),
+            events: marketdata::BookEvents::default(),
         }
     }
 
     fn do_hidden(
         &mut self,
-        clients: Clients,
-        socket: &mut UdpSocket,
+        accounts: Accounts,
+        out: &mut SessionTable,
         ordering_client: SocketAddr,
         mut order: HiddenOrder,
     ) -> Result<(), ()> {
-        let mut lock = clients.get();
+        let mut lock = accounts.get();
+        let ordering_account = lock.id(&ordering_client).ok_or(())?;
 
         if order.amount.is_negative() {
             let nbbo = self.bids.iter().next();
@@ -198,7 +242,7 @@ impl OrderBook {
                 None => return Err(()),
                 Some((nbbo, _)) => {
                     let as_f64 = (*nbbo as f64 / FLOATING_TO_FIXED_OFF);
-                    if as_f64 * order.amount as f64 > lock.get(&ordering_client).unwrap().money {
+                    if as_f64 * order.amount as f64 > lock.accounts[&ordering_account].money {
                         return Err(());
                     }
                 }
@@ -220,10 +264,21 @@ impl OrderBook {
 
                     entry.amount -= sell_amt;
                     order.amount -= sell_amt;
+                    let match_id = self.tape.record(
+                        *bid,
+                        sell_amt,
+                        marketdata::SELL,
+                        Some(entry.account.clone()),
+                        Some(ordering_account.clone()),
+                    );
+                    self.events.push(BookEvent::Executed {
+                        order_id: entry.id,
+                        amount: sell_amt,
+                        match_id,
+                    });
 
-                    let buyer = lock.get_mut(&entry.client).unwrap();
-                    buyer.money -= sell_amt as f64 * price;
-                    buyer.position += sell_amt;
+                    let buyer = lock.accounts.get_mut(&entry.account).unwrap();
+                    buyer.fill(sell_amt, price);
                     buyer.net_liquidity_contribution += 1;
                     buyer.is_market_maker = buyer.net_liquidity_contribution >= 100;
 
@@ -232,17 +287,16 @@ impl OrderBook {
                         amount: sell_amt,
                         price,
                     };
-                    socket.send_to(&lmtexec.to_bytes(), buyer.addr);
+                    lock.send(out, &entry.account, &lmtexec.to_bytes());
 
-                    let oc = lock.get_mut(&ordering_client).unwrap();
-                    oc.money -= sell_amt as f64 * bid_as_f64;
-                    oc.position += sell_amt;
+                    let oc = lock.accounts.get_mut(&ordering_account).unwrap();
+                    oc.fill(sell_amt, bid_as_f64);
                     oc.is_market_maker = oc.net_liquidity_contribution >= 100;
                     let er = OrderResponse::Market(MarketResponse {
                         amount: sell_amt,
                         price,
                     });
-                    socket.send_to(&er.to_bytes(), ordering_client);
+                    out.send(ordering_client, &er.to_bytes());
 
                     if order.amount <= 0 {
                         return Ok(());
@@ -250,7 +304,7 @@ impl OrderBook {
                 }
             }
         } else {
-            if lock.get(&ordering_client).unwrap().position < order.amount.abs() {
+            if lock.accounts[&ordering_account].position < order.amount.abs() {
                 return Err(());
             }
 
@@ -270,30 +324,40 @@ impl OrderBook {
 
                     entry.amount -= buy_amt;
                     order.amount -= buy_amt;
+                    let match_id = self.tape.record(
+                        *ask,
+                        buy_amt,
+                        marketdata::BUY,
+                        Some(ordering_account.clone()),
+                        Some(entry.account.clone()),
+                    );
+                    self.events.push(BookEvent::Executed {
+                        order_id: entry.id,
+                        amount: buy_amt,
+                        match_id,
+                    });
 
-                    if let Some(seller) = lock.get_mut(&entry.client) {
-                        seller.money += buy_amt as f64 * price;
-                        seller.position -= buy_amt;
+                    if let Some(seller) = lock.accounts.get_mut(&entry.account) {
+                        seller.fill(-buy_amt, price);
                         seller.net_liquidity_contribution += 1;
                         seller.is_market_maker = seller.net_liquidity_contribution >= 100;
-
-                        let lmtexec = LmtExecution {
-                            order_id: entry.id,
-                            amount: buy_amt,
-                            price,
-                        };
-                        socket.send_to(&lmtexec.to_bytes(), seller.addr);
                     }
 
-                    if let Some(oc) = lock.get_mut(&ordering_client) {
-                        oc.money += buy_amt as f64 * price;
-                        oc.position -= buy_amt;
+                    let lmtexec = LmtExecution {
+                        order_id: entry.id,
+                        amount: buy_amt,
+                        price,
+                    };
+                    lock.send(out, &entry.account, &lmtexec.to_bytes());
+
+                    if let Some(oc) = lock.accounts.get_mut(&ordering_account) {
+                        oc.fill(-buy_amt, price);
                         oc.is_market_maker = oc.net_liquidity_contribution >= 100;
                         let er = OrderResponse::Market(MarketResponse {
                             amount: buy_amt,
                             price,
                         });
-                        socket.send_to(&er.to_bytes(), ordering_client);
+                        out.send(ordering_client, &er.to_bytes());
                     }
 
                     if order.amount <= 0 {
@@ -312,18 +376,19 @@ impl OrderBook {
 
     fn do_lmt(
         &mut self,
-        clients: Clients,
-        socket: &mut UdpSocket,
+        accounts: Accounts,
+        out: &mut SessionTable,
         ordering_client: SocketAddr,
         order: LimitOrder,
     ) -> Result<(), ()> {
-        let lock = clients.get();
-        let c = lock.get(&ordering_client).unwrap();
+        let lock = accounts.get();
+        let ordering_account = lock.id(&ordering_client).ok_or(())?;
+        let c = &lock.accounts[&ordering_account];
         let price = (order.lmt * FLOATING_TO_FIXED_OFF) as isize;
         let id = (price << 24) + self.inc_id;
         self.inc_id += 1;
         let be = BookEntry {
-            client: ordering_client,
+            account: ordering_account.clone(),
             amount: order.amount.abs(),
             id,
             cycles_present: 0,
@@ -339,24 +404,35 @@ impl OrderBook {
             }
             self.bids.entry(price).or_insert_with(Vec::new).push(be);
         }
+        self.events.push(BookEvent::Add {
+            order_id: id,
+            side: if order.amount.is_negative() {
+                marketdata::SELL
+            } else {
+                marketdata::BUY
+            },
+            amount: order.amount.abs(),
+            price,
+        });
 
         let res = OrderResponse::Lmt(LmtResponse { order_id: id });
-        socket.send_to(&res.to_bytes(), c.addr);
+        out.send(ordering_client, &res.to_bytes());
 
         Ok(())
     }
 
     fn do_mkt(
         &mut self,
-        clients: Clients,
-        socket: &mut UdpSocket,
+        accounts: Accounts,
+        out: &mut SessionTable,
         ordering_client: SocketAddr,
         mut order: MarketOrder,
     ) -> Result<(), ()> {
-        let mut lock = clients.get();
+        let mut lock = accounts.get();
+        let ordering_account = lock.id(&ordering_client).ok_or(())?;
 
         if order.amount.is_negative() {
-            if lock.get(&ordering_client).unwrap().position < order.amount.abs() {
+            if lock.accounts[&ordering_account].position < order.amount.abs() {
                 return Err(());
             }
             order.amount = order.amount.abs();
@@ -375,31 +451,41 @@ impl OrderBook {
 
                     entry.amount -= sell_amt;
                     order.amount -= sell_amt;
+                    let match_id = self.tape.record(
+                        *bid,
+                        sell_amt,
+                        marketdata::SELL,
+                        Some(entry.account.clone()),
+                        Some(ordering_account.clone()),
+                    );
+                    self.events.push(BookEvent::Executed {
+                        order_id: entry.id,
+                        amount: sell_amt,
+                        match_id,
+                    });
 
-                    if let Some(buyer) = lock.get_mut(&entry.client) {
-                        buyer.money -= sell_amt as f64 * price;
-                        buyer.position += sell_amt;
+                    if let Some(buyer) = lock.accounts.get_mut(&entry.account) {
+                        buyer.fill(sell_amt, price);
                         buyer.net_liquidity_contribution += 1;
                         buyer.is_market_maker = buyer.net_liquidity_contribution >= 100;
-
-                        let lmtexec = LmtExecution {
-                            order_id: entry.id,
-                            amount: sell_amt,
-                            price,
-                        };
-                        socket.send_to(&lmtexec.to_bytes(), buyer.addr);
                     }
 
-                    if let Some(oc) = lock.get_mut(&ordering_client) {
-                        oc.money += sell_amt as f64 * bid_as_f64;
-                        oc.position -= sell_amt;
+                    let lmtexec = LmtExecution {
+                        order_id: entry.id,
+                        amount: sell_amt,
+                        price,
+                    };
+                    lock.send(out, &entry.account, &lmtexec.to_bytes());
+
+                    if let Some(oc) = lock.accounts.get_mut(&ordering_account) {
+                        oc.fill(-sell_amt, bid_as_f64);
                         oc.net_liquidity_contribution -= 1;
                         oc.is_market_maker = oc.net_liquidity_contribution >= 100;
                         let er = OrderResponse::Market(MarketResponse {
                             amount: sell_amt,
                             price,
                         });
-                        socket.send_to(&er.to_bytes(), ordering_client);
+                        out.send(ordering_client, &er.to_bytes());
                     }
 
                     if order.amount <= 0 {
@@ -411,7 +497,7 @@ impl OrderBook {
             let nbbo = self.asks.iter().next();
             if let Some((nbbo, _)) = nbbo {
                 let as_f64 = (*nbbo as f64 / FLOATING_TO_FIXED_OFF);
-                if as_f64 * order.amount as f64 > lock.get(&ordering_client).unwrap().money {
+                if as_f64 * order.amount as f64 > lock.accounts[&ordering_account].money {
                     return Err(());
                 }
             }
@@ -432,31 +518,41 @@ impl OrderBook {
 
                     entry.amount -= buy_amt;
                     order.amount -= buy_amt;
+                    let match_id = self.tape.record(
+                        *ask,
+                        buy_amt,
+                        marketdata::BUY,
+                        Some(ordering_account.clone()),
+                        Some(entry.account.clone()),
+                    );
+                    self.events.push(BookEvent::Executed {
+                        order_id: entry.id,
+                        amount: buy_amt,
+                        match_id,
+                    });
 
-                    if let Some(seller) = lock.get_mut(&entry.client) {
-                        seller.money += buy_amt as f64 * price;
-                        seller.position -= buy_amt;
+                    if let Some(seller) = lock.accounts.get_mut(&entry.account) {
+                        seller.fill(-buy_amt, price);
                         seller.net_liquidity_contribution += 1;
                         seller.is_market_maker = seller.net_liquidity_contribution >= 100;
-
-                        let lmtexec = LmtExecution {
-                            order_id: entry.id,
-                            amount: buy_amt,
-                            price,
-                        };
-                        socket.send_to(&lmtexec.to_bytes(), seller.addr);
                     }
 
-                    if let Some(oc) = lock.get_mut(&ordering_client) { 
-                        oc.money -= buy_amt as f64 * price;
-                        oc.position += buy_amt;
+                    let lmtexec = LmtExecution {
+                        order_id: entry.id,
+                        amount: buy_amt,
+                        price,
+                    };
+                    lock.send(out, &entry.account, &lmtexec.to_bytes());
+
+                    if let Some(oc) = lock.accounts.get_mut(&ordering_account) { 
+                        oc.fill(buy_amt, price);
                         oc.net_liquidity_contribution -= 1;
                         oc.is_market_maker = oc.net_liquidity_contribution >= 100;
                         let er = OrderResponse::Market(MarketResponse {
                             amount: buy_amt,
                             price,
                         });
-                        socket.send_to(&er.to_bytes(), ordering_client);
+                        out.send(ordering_client, &er.to_bytes());
                     }
 
                     if order.amount <= 0 {
@@ -466,15 +562,29 @@ impl OrderBook {
             }
 
             if order.amount != 0 {
-                if let Some(oc) = lock.get_mut(&ordering_client) {
-                    oc.money -= order.amount as f64 * 1.0;
-                    oc.position += order.amount;
-                    order.amount = 0;
+                if let Some(oc) = lock.accounts.get_mut(&ordering_account) {
+                    let price = FLOATING_TO_FIXED_OFF as isize;
+                    // the exchange sells whatever the book doesn't have
+                    let match_id = self.tape.record(
+                        price,
+                        order.amount,
+                        marketdata::BUY,
+                        Some(ordering_account.clone()),
+                        None,
+                    );
+                    self.events.push(BookEvent::Trade {
+                        side: marketdata::BUY,
+                        amount: order.amount,
+                        price,
+                        match_id,
+                    });
+                    oc.fill(order.amount, 1.0);
                     let er = OrderResponse::Market(MarketResponse {
                         amount: order.amount,
                         price: 1.0,
                     });
-                    socket.send_to(&er.to_bytes(), ordering_client);
+                    order.amount = 0;
+                    out.send(ordering_client, &er.to_bytes());
                 }
             }
         }
@@ -488,43 +598,126 @@ impl OrderBook {
 
     fn do_cncl(
         &mut self,
-        socket: &mut UdpSocket,
+        accounts: Accounts,
+        out: &mut SessionTable,
         ordering_client: SocketAddr,
         cncl: CancleOrder,
     ) -> Result<(), ()> {
+        // any session of the account may cancel its orders
+        let ordering_account = accounts.get().id(&ordering_client).ok_or(())?;
         let price = cncl.order_id >> 24;
         // let price = (order.lmt * FLOATING_TO_FIXED_OFF) as isize;
 
         if let Some(entries) = self.bids.get_mut(&price) {
             if let Some(idx) = entries.iter().enumerate().find_map(|(i, bid)| {
-                if bid.id == cncl.order_id && ordering_client == bid.client {
+                if bid.id == cncl.order_id && ordering_account == bid.account {
                     Some(i)
                 } else {
                     None
                 }
             }) {
-                entries.remove(idx);
-                socket.send_to(&[0xe0], ordering_client);
+                let entry = entries.remove(idx);
+                self.events.push(BookEvent::Cancel {
+                    order_id: entry.id,
+                    amount: entry.amount,
+                });
+                out.send(ordering_client, &[0xe0]);
                 return Ok(());
             }
         }
 
         if let Some(entries) = self.asks.get_mut(&price) {
             if let Some(idx) = entries.iter().enumerate().find_map(|(i, ask)| {
-                if ask.id == cncl.order_id && ordering_client == ask.client {
+                if ask.id == cncl.order_id && ordering_account == ask.account {
                     Some(i)
                 } else {
                     None
                 }
             }) {
-                entries.remove(idx);
-                socket.send_to(&[0xe0], ordering_client);
+                let entry = entries.remove(idx);
+                self.events.push(BookEvent::Cancel {
+                    order_id: entry.id,
+                    amount: entry.amount,
+                });
+                out.send(ordering_client, &[0xe0]);
                 return Ok(());
             }
         }
 
         Err(())
     }
+
+    fn remove(&mut self, order_id: isize) -> Option<BookEntry> {
+        let price = order_id >> 24;
+        let entry = [self.bids.get_mut(&price), self.asks.get_mut(&price)]
+            .into_iter()
+            .flatten()
+            .find_map(|entries| {
+                let idx = entries.iter().position(|entry| entry.id == order_id)?;
+                Some(entries.remove(idx))
+            })?;
+        self.events.push(BookEvent::Cancel {
+            order_id: entry.id,
+            amount: entry.amount,
+        });
+        Some(entry)
+    }
+
+    /// Moves a resting order to a new price and amount on the same side, it gets a new id
+    /// and goes to the back of the queue like any new order.
+    fn do_replace(
+        &mut self,
+        accounts: Accounts,
+        out: &mut SessionTable,
+        ordering_client: SocketAddr,
+        order: ReplaceOrder,
+    ) -> Result<(), ()> {
+        let lock = accounts.get();
+        let ordering_account = lock.id(&ordering_client).ok_or(())?;
+        let c = &lock.accounts[&ordering_account];
+        let old_price = order.order_id >> 24;
+        let book = if order.amount.is_negative() {
+            if order.amount.abs() > c.position {
+                return Err(());
+            }
+            &mut self.asks
+        } else {
+            if order.lmt * order.amount as f64 > c.money {
+                return Err(());
+            }
+            &mut self.bids
+        };
+
+        let entries = book.get_mut(&old_price).ok_or(())?;
+        let idx = entries
+            .iter()
+            .position(|entry| {
+                entry.id == order.order_id && entry.account == ordering_account && entry.amount != 0
+            })
+            .ok_or(())?;
+        entries.remove(idx);
+
+        let price = (order.lmt * FLOATING_TO_FIXED_OFF) as isize;
+        let id = (price << 24) + self.inc_id;
+        self.inc_id += 1;
+        book.entry(price).or_insert_with(Vec::new).push(BookEntry {
+            account: ordering_account.clone(),
+            amount: order.amount.abs(),
+            id,
+            cycles_present: 0,
+        });
+        self.events.push(BookEvent::Replace {
+            order_id: order.order_id,
+            new_order_id: id,
+            amount: order.amount.abs(),
+            price,
+        });
+
+        let res = OrderResponse::Lmt(LmtResponse { order_id: id });
+        out.send(ordering_client, &res.to_bytes());
+
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -588,6 +781,19 @@ enum Order {
     Market(MarketOrder),
     Cncl(CancleOrder),
     Hidden(HiddenOrder),
+    Replace(ReplaceOrder),
+    Transfer(TransferOrder),
+    Subscribe(marketdata::SubscribeRequest),
+    Recover(marketdata::RecoveryRequest),
+    /// not an order, the peer just logged on to the account with the contained id, which is
+    /// opened if it doesn't exist yet
+    Logon(String),
+    /// not an order, the peer logged out or its connection is gone
+    Logout,
+    /// not an order, the logged on peer moved from the contained address to the sending one
+    Rebind(SocketAddr),
+    /// not an order either, the admin api wants the engine to do something
+    Admin(admin::AdminCommand, Sender<(u16, json::Json)>),
 }
 
 struct LmtExecution {
@@ -609,227 +815,639 @@ impl LmtExecution {
     }
 }
 
-type Clients = MThread<BTreeMap<SocketAddr, Client>>;
-
-fn client_rx(socket: UdpSocket, clients: Clients, order_sender: Sender<(SocketAddr, Order)>) {
+fn client_rx(
+    socket: UdpSocket,
+    logons: Logons,
+    sessions: Sessions,
+    accounts: Accounts,
+    audit: Audit,
+    order_sender: Sender<(SocketAddr, Order, u64)>,
+) {
     const BUFFER_LEN: usize = 2048;
     let mut buffer = [0u8; BUFFER_LEN];
     loop {
         buffer.iter_mut().for_each(|b| *b = 0);
         if let Ok((bytes, addr)) = socket.recv_from(&mut buffer) {
+            let id = audit.get().inbound("udp", addr, &buffer[..bytes.min(BUFFER_LEN)]);
             if bytes >= BUFFER_LEN {
+                audit.get().dropped(id, "too long");
                 continue;
             }
 
-            if buffer[0] == 0x69 {
-                clients.get().remove(&addr);
+            if buffer[0] == logon::LOGON {
+                let grant = LogonRequest::from_bytes(&buffer[1..])
+                    .and_then(|req| logons.get().logon(addr, &req));
+
+                match grant {
+                    Some(grant) => {
+                        accept_logon(addr, &grant, &order_sender, id);
+                        sessions.get().open(addr, grant.key);
+                        audit.get().outbound(id, "udp", addr, &grant.to_bytes());
+                        socket.send_to(&grant.to_bytes(), addr);
+                    }
+                    None => {
+                        audit.get().dropped(id, "logon refused");
+                        audit.get().outbound(id, "udp", addr, &[logon::LOGON_RESPONSE, 1]);
+                        socket.send_to(&[logon::LOGON_RESPONSE, 1], addr);
+                    }
+                }
                 continue;
             }
 
-            let is_mm = clients
-                .get()
-                .entry(addr)
-                .or_insert_with(|| Client::new(addr))
-                .is_market_maker;
+            if buffer[0] != logon::AUTHENTICATED {
+                audit.get().dropped(id, "not logged on");
+                audit.get().outbound(id, "udp", addr, &[logon::NOT_LOGGED_ON]);
+                socket.send_to(&[logon::NOT_LOGGED_ON], addr);
+                continue;
+            }
 
-            let order = move || -> Option<Order> {
-                Some(match buffer[0] {
-                    0 => Order::Lmt(LimitOrder::from_bytes(&buffer[1..])?),
-                    1 => Order::Market(MarketOrder::from_bytes(&buffer[1..])?),
-                    2 => Order::Cncl(CancleOrder::from_bytes(&buffer[1..])?),
-                    3 if is_mm => Order::Hidden(HiddenOrder::from_bytes(&buffer[1..])?),
-                    _ => None?,
-                })
-            }();
+            let token = u64::from_le_bytes(buffer[1..9].try_into().unwrap());
+            let bound = match logons.get().authenticate(token, &buffer[..bytes]) {
+                Some((bound, _)) => bound,
+                None => {
+                    audit.get().dropped(id, "unknown token or bad MAC");
+                    continue;
+                }
+            };
 
-            if let Some(order) = order {
-                order_sender.send((addr, order));
+            // the parsers below rely on the zero padding behind the message
+            buffer[bytes - crypto::MAC_LEN..bytes].fill(0);
+
+            if ![session::SEQ_DATA_IN, session::SEQ_ACK, session::SEQ_RESEND].contains(&buffer[9]) {
+                audit.get().dropped(id, "no session frame");
+                continue;
+            }
+
+            // a valid MAC doesn't make a datagram fresh, only the sequence number does. Only a
+            // fresh data frame moves the session, anything else from a new address is dropped,
+            // duplicates are acked to where the session is
+            let fresh = sessions.get().in_sequence(&bound, &buffer[9..]);
+            let addr = if fresh && bound != addr {
+                if buffer[9] != session::SEQ_DATA_IN {
+                    audit.get().dropped(id, "session control from another address");
+                    continue;
+                }
+                logons.get().rebind(token, addr);
+                sessions.get().rebind(&bound, addr);
+                order_sender.send((addr, Order::Rebind(bound), id));
+                addr
+            } else {
+                bound
+            };
+            if fresh {
+                logons.get().touch(token);
+            }
+
+            let payload = {
+                let mut sessions = sessions.get();
+                sessions.correlate(id);
+                sessions.recv(addr, &buffer[9..])
+            };
+            let payload = match payload {
+                Some(payload) => payload,
+                None => {
+                    audit.get().dropped(id, "session control, duplicate or ahead of a gap");
+                    continue;
+                }
+            };
+
+            match payload[0] {
+                logon::LOGOUT => {
+                    logons.get().logout(token);
+                    order_sender.send((addr, Order::Logout, id));
+                    sessions.get().forget(&addr);
+                    continue;
+                }
+                logon::HEARTBEAT => {
+                    let mut sessions = sessions.get();
+                    sessions.correlate(id);
+                    sessions.publish(addr, &[logon::HEARTBEAT]);
+                    continue;
+                }
+                _ => {}
+            }
+
+            let is_mm = accounts
+                .get()
+                .of(&addr)
+                .is_some_and(|account| account.may_hide());
+
+            match parse_order(payload, is_mm) {
+                Some(order) => {
+                    order_sender.send((addr, order, id));
+                }
+                None => audit.get().dropped(id, "unparseable order"),
             }
         }
     }
 }
 
-fn main() {
-    let flag = std::fs::read_to_string("flag").unwrap();
-    let (order_sender, orders) = channel();
-    let mut socket = UdpSocket::bind("0.0.0.0:14550").unwrap();
-    let tsocket = socket.try_clone().unwrap();
-    let clients = Clients::new(BTreeMap::new());
-    let tclients = clients.clone();
-    let mut order_book = OrderBook::new();
+/// `payload` has to be zero padded, the parsers don't check the actual message length.
+fn parse_order(payload: &[u8], is_mm: bool) -> Option<Order> {
+    Some(match payload[0] {
+        0 => Order::Lmt(LimitOrder::from_bytes(&payload[1..])?),
+        1 => Order::Market(MarketOrder::from_bytes(&payload[1..])?),
+        2 => Order::Cncl(CancleOrder::from_bytes(&payload[1..])?),
+        3 if is_mm => Order::Hidden(HiddenOrder::from_bytes(&payload[1..])?),
+        4 => Order::Replace(ReplaceOrder::from_bytes(&payload[1..])?),
+        5 => Order::Subscribe(marketdata::SubscribeRequest::from_bytes(&payload[1..], true)?),
+        6 => Order::Subscribe(marketdata::SubscribeRequest::from_bytes(&payload[1..], false)?),
+        7 => Order::Recover(marketdata::RecoveryRequest::from_bytes(&payload[1..], false)?),
+        8 => Order::Recover(marketdata::RecoveryRequest::from_bytes(&payload[1..], true)?),
+        9 => Order::Transfer(TransferOrder::from_bytes(&payload[1..])?),
+        _ => None?,
+    })
+}
 
-    std::thread::spawn(move || client_rx(tsocket, tclients, order_sender));
+/// Has the engine start a session of a freshly logged on peer on its account, next to any
+/// other sessions of the account. The caller still has to set up the transport. `id` is the
+/// correlation id of the logon.
+fn accept_logon(
+    addr: SocketAddr,
+    grant: &LogonGrant,
+    order_sender: &Sender<(SocketAddr, Order, u64)>,
+    id: u64,
+) {
+    order_sender.send((addr, Order::Logon(grant.account.clone()), id));
+}
 
-    let order_waiter = std::time::Duration::from_millis(10);
-    loop {
-        let now = std::time::Instant::now();
+/// Runs one order taken off the channel. The live engine and the journal replay share this,
+/// so the replay ends up exactly where the engine was.
+fn execute(
+    order_book: &mut OrderBook,
+    feed: &mut marketdata::Feed,
+    accounts: &Accounts,
+    out: &mut SessionTable,
+    halted: &mut bool,
+    caddr: SocketAddr,
+    order: Order,
+) {
+    // the peer logged out while its order was queued
+    if !matches!(order, Order::Rebind(_) | Order::Admin(..) | Order::Logon(_))
+        && !accounts.get().sessions.contains_key(&caddr)
+    {
+        return;
+    }
 
-        while now.elapsed().subsec_millis() < 500 {
-            if let Ok((caddr, order)) = orders.recv_timeout(order_waiter) {
-                match order {
-                    Order::Lmt(lmt) => {
-                        if order_book
-                            .do_lmt(clients.clone(), &mut socket, caddr, lmt)
-                            .is_err()
+    // accounts that aren't entitled to trade get what everyone gets while halted
+    let may_trade = accounts.get().of(&caddr).is_some_and(|a| a.may_trade());
+    if *halted || !may_trade {
+        let reject = match order {
+            Order::Lmt(_) => Some(0xff),
+            Order::Market(_) => Some(0xfe),
+            Order::Hidden(_) => Some(0xfc),
+            Order::Replace(_) => Some(0xfb),
+            _ => None,
+        };
+        if let Some(reject) = reject {
+            out.send(caddr, &[reject]);
+            out.processed(caddr);
+            return;
+        }
+    }
+
+    match order {
+        Order::Lmt(lmt) => {
+            if order_book.do_lmt(accounts.clone(), out, caddr, lmt).is_err() {
+                out.send(caddr, &[0xff]);
+            }
+        }
+        Order::Market(mkt) => {
+            if order_book.do_mkt(accounts.clone(), out, caddr, mkt).is_err() {
+                out.send(caddr, &[0xfe]);
+            }
+        }
+        Order::Cncl(cncl) => {
+            if order_book.do_cncl(accounts.clone(), out, caddr, cncl).is_err() {
+                out.send(caddr, &[0xfd]);
+            }
+        }
+        Order::Hidden(hid) => {
+            if order_book.do_hidden(accounts.clone(), out, caddr, hid).is_err() {
+                out.send(caddr, &[0xfc]);
+            }
+        }
+        Order::Replace(rpl) => {
+            if order_book.do_replace(accounts.clone(), out, caddr, rpl).is_err() {
+                out.send(caddr, &[0xfb]);
+            }
+        }
+        Order::Transfer(transfer) => {
+            let mut lock = accounts.get();
+            let from = lock
+                .of(&caddr)
+                .filter(|account| account.may_transfer())
+                .map(|account| account.id.clone());
+            let moved = from.is_some_and(|from| {
+                lock.transfer(out, Some(&from), Some(&transfer.to), transfer.amount)
+                    .is_ok()
+            });
+            if !moved {
+                out.send(caddr, &[0xfa]);
+            }
+        }
+        Order::Subscribe(req) => {
+            let status = feed.subscribe(caddr, &req);
+            out.send(caddr, &[marketdata::SUBSCRIPTION, req.feed, status]);
+        }
+        Order::Recover(req) => feed.recover(caddr, &req, out),
+        Order::Logon(account) => {
+            // the account was closed after the logon was granted
+            if !accounts.get().logon(caddr, &account) {
+                out.send(caddr, &[0x69]);
+                out.forget(&caddr);
+            }
+            return;
+        }
+        Order::Logout => {
+            // the orders of the account stay, unless this was its last session
+            accounts.get().logout(&caddr);
+            return;
+        }
+        Order::Rebind(from) => {
+            accounts.get().rebind(from, caddr);
+            feed.rebind(from, caddr);
+            return;
+        }
+        Order::Admin(cmd, reply) => {
+            reply.send(admin::execute(cmd, order_book, accounts, halted, out));
+            feed.update(order_book, out, accounts);
+            return;
+        }
+    }
+    feed.update(order_book, out, accounts);
+    out.processed(caddr);
+}
+
+/// The end of a cycle: ends the sessions whose logons `expired`, closes the accounts that are
+/// done, crosses the book, cancels the orders of accounts nobody trades on anymore and sends
+/// every session its account. Returns the ids of the closed accounts, their logons are up to
+/// the caller.
+fn end_cycle(
+    order_book: &mut OrderBook,
+    feed: &mut marketdata::Feed,
+    accounts: &Accounts,
+    out: &mut SessionTable,
+    halted: bool,
+    flag: &[u8],
+    expired: &[SocketAddr],
+) -> Vec<String> {
+    for &addr in expired {
+        accounts.get().logout(&addr);
+        out.send(addr, &[0x69]);
+        out.forget(&addr);
+    }
+
+    let mut closed = Vec::new();
+    {
+        let mut lock = accounts.get();
+        let ids: Vec<String> = lock.accounts.keys().cloned().collect();
+        for id in ids {
+            // only accounts someone trades on are done, nobody would hear about it otherwise
+            if !lock.present(&id) {
+                continue;
+            }
+            let account = &lock.accounts[&id];
+            let msg: &[u8] = if account.money >= 10000000.0
+                && account.is_market_maker
+                && account.role != Role::House
+            {
+                flag
+            } else if account.money <= 10.0 || account.cycles_present > 2 * 30 * 60 {
+                &[0x69]
+            } else {
+                continue;
+            };
+            for addr in lock.close(&id) {
+                out.send(addr, msg);
+                out.forget(&addr);
+            }
+            closed.push(id);
+        }
+    }
+    {
+        let mut lock = accounts.get();
+        'outer: for (strike, bidbook) in order_book.bids.iter_mut() {
+            if halted {
+                break;
+            }
+            let strike_asf64 = (*strike as f64 / FLOATING_TO_FIXED_OFF);
+            if let Some(askbook) = order_book.asks.get_mut(strike) {
+                let mut asksentryiter = askbook.iter_mut();
+                let mut cur_ask_entry: Option<&mut BookEntry> = asksentryiter.next();
+                for (booki, bid_entry) in bidbook.iter_mut().enumerate() {
+                    while bid_entry.amount != 0 {
+                        if cur_ask_entry.is_none()
+                            || cur_ask_entry.as_ref().unwrap().amount == 0
                         {
-                            socket.send_to(&[0xff], caddr);
+                            cur_ask_entry = asksentryiter.next();
                         }
-                    }
-                    Order::Market(mkt) => {
-                        if order_book
-                            .do_mkt(clients.clone(), &mut socket, caddr, mkt)
-                            .is_err()
-                        {
-                            socket.send_to(&[0xfe], caddr);
+
+                        if cur_ask_entry.is_none() {
+                            continue 'outer;
                         }
-                    }
-                    Order::Cncl(cncl) => {
-                        if order_book.do_cncl(&mut socket, caddr, cncl).is_err() {
-                            socket.send_to(&[0xfd], caddr);
+                        let ask_entry = cur_ask_entry.as_mut().unwrap();
+
+                        let trade_amt = if bid_entry.amount >= ask_entry.amount {
+                            ask_entry.amount
+                        } else {
+                            bid_entry.amount
+                        };
+                        let price = strike_asf64;
+
+                        bid_entry.amount -= trade_amt;
+                        ask_entry.amount -= trade_amt;
+                        let match_id = order_book.tape.record(
+                            *strike,
+                            trade_amt,
+                            marketdata::AUCTION,
+                            Some(bid_entry.account.clone()),
+                            Some(ask_entry.account.clone()),
+                        );
+                        for order_id in [bid_entry.id, ask_entry.id] {
+                            order_book.events.push(BookEvent::Executed {
+                                order_id,
+                                amount: trade_amt,
+                                match_id,
+                            });
                         }
-                    }
-                    Order::Hidden(hid) => {
-                        if order_book
-                            .do_hidden(clients.clone(), &mut socket, caddr, hid)
-                            .is_err()
-                        {
-                            socket.send_to(&[0xfc], caddr);
+
+                        if let Some(buyer) = lock.accounts.get_mut(&bid_entry.account) {
+                            buyer.fill(trade_amt, price);
+                            buyer.net_liquidity_contribution += 1;
+                            buyer.is_market_maker = buyer.net_liquidity_contribution >= 100;
                         }
+
+                        let lmtexec = LmtExecution {
+                            order_id: bid_entry.id,
+                            amount: trade_amt,
+                            price,
+                        };
+                        lock.send(out, &bid_entry.account, &lmtexec.to_bytes());
+
+                        if let Some(seller) = lock.accounts.get_mut(&ask_entry.account) {
+                            seller.fill(-trade_amt, price);
+                            seller.net_liquidity_contribution += 1;
+                            seller.is_market_maker = seller.net_liquidity_contribution >= 100;
+                        }
+
+                        let lmtexec = LmtExecution {
+                            order_id: ask_entry.id,
+                            amount: trade_amt,
+                            price,
+                        };
+                        lock.send(out, &ask_entry.account, &lmtexec.to_bytes());
                     }
                 }
             }
         }
 
-        clients.get().retain(|addr, client| {
-            if client.money >= 10000000.0
-                && client.is_market_maker
-                && !client.addr.ip().is_loopback()
-            {
-                socket.send_to(flag.as_bytes(), addr);
-                false
-            } else if client.money <= 10.0 || client.cycles_present > 2 * 30 * 60 {
-                socket.send_to(&[0x69], addr);
-                false
-            } else {
-                true
+        // orders of accounts without a session are canceled here
+        let events = &mut order_book.events;
+        let mut keep = |entry: &BookEntry| {
+            let live = lock.present(&entry.account);
+            if !live && entry.amount != 0 {
+                events.push(BookEvent::Cancel {
+                    order_id: entry.id,
+                    amount: entry.amount,
+                });
             }
+            entry.amount != 0 && live
+        };
+
+        order_book.bids.iter_mut().for_each(|(_, lvl2)| {
+            lvl2.retain(|entry| keep(entry));
         });
 
-        {
-            let mut lock = clients.get();
-            'outer: for (strike, bidbook) in order_book.bids.iter_mut() {
-                let strike_asf64 = (*strike as f64 / FLOATING_TO_FIXED_OFF);
-                if let Some(askbook) = order_book.asks.get_mut(strike) {
-                    let mut asksentryiter = askbook.iter_mut();
-                    let mut cur_ask_entry: Option<&mut BookEntry> = asksentryiter.next();
-                    for (booki, bid_entry) in bidbook.iter_mut().enumerate() {
-                        while bid_entry.amount != 0 {
-                            if cur_ask_entry.is_none()
-                                || cur_ask_entry.as_ref().unwrap().amount == 0
-                            {
-                                cur_ask_entry = asksentryiter.next();
-                            }
-
-                            if cur_ask_entry.is_none() {
-                                continue 'outer;
-                            }
-                            let ask_entry = cur_ask_entry.as_mut().unwrap();
-
-                            let trade_amt = if bid_entry.amount >= ask_entry.amount {
-                                ask_entry.amount
-                            } else {
-                                bid_entry.amount
-                            };
-                            let price = strike_asf64;
-
-                            bid_entry.amount -= trade_amt;
-                            ask_entry.amount -= trade_amt;
-
-                                let lmtexec = LmtExecution {
-                                    order_id: bid_entry.id,
-                                    amount: trade_amt,
-                                    price,
-                                };
-
-                            if let Some(buyer) = lock.get_mut(&bid_entry.client) { 
-                                buyer.money -= trade_amt as f64 * price;
-                                buyer.position += trade_amt;
-                                buyer.net_liquidity_contribution += 1;
-                                buyer.is_market_maker = buyer.net_liquidity_contribution >= 100;
-
-                                socket.send_to(&lmtexec.to_bytes(), buyer.addr);
-                            }
-
-                            if let Some(seller) = lock.get_mut(&ask_entry.client) {
-                                seller.money += trade_amt as f64 * price;
-                                seller.position -= trade_amt;
-                                seller.net_liquidity_contribution += 1;
-                                seller.is_market_maker = seller.net_liquidity_contribution >= 100;
-
-                                socket.send_to(&lmtexec.to_bytes(), seller.addr);
-                            }
-                        }
-                    }
-                }
+        order_book.asks.iter_mut().for_each(|(_, lvl2)| {
+            lvl2.retain(|entry| keep(entry));
+        });
+
+        order_book
+            .bids
+            .values_mut()
+            .chain(order_book.asks.values_mut())
+            .flatten()
+            .for_each(|entry| entry.cycles_present += 1);
+        lock.mark = marketdata::mark(order_book);
+        let AccountTable {
+            accounts,
+            sessions,
+            mark,
+            ..
+        } = &mut *lock;
+        for account in accounts.values_mut() {
+            if sessions.values().any(|id| *id == account.id) {
+                account.cycles_present += 1;
             }
+        }
+        for (addr, id) in sessions.iter() {
+            feed.account(*addr, &accounts[id].to_bytes(*mark));
+        }
+    }
+    feed.cycle(order_book, out, accounts);
+    closed
+}
 
-            order_book.bids.iter_mut().for_each(|(_, lvl2)| {
-                lvl2.retain(|entry| entry.amount != 0 && lock.get(&entry.client).is_some());
-            });
+fn main() {
+    // `mm replay [journal]` rebuilds the state from the journal instead of serving
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("replay") {
+        journal::replay(args.get(2).map_or(journal::JOURNAL, String::as_str));
+        return;
+    }
+    // `mm export [day [dir]]` writes a day of the ledger out for analytics, today by default
+    if args.get(1).map(String::as_str) == Some("export") {
+        let day = args.get(2).cloned().unwrap_or_else(ledger::today);
+        ledger::export(&day, args.get(3).map_or(".", String::as_str));
+        return;
+    }
+    // `mm standby [primary]` follows a primary and only serves once it's gone, otherwise pick
+    // up where the last run left off, from the latest snapshot and the journal after it
+    let restored = if args.get(1).map(String::as_str) == Some("standby") {
+        replication::standby(args.get(2).map_or(replication::REPLICATION_ADDR, String::as_str))
+    } else {
+        journal::restore(journal::JOURNAL, snapshot::SNAPSHOT)
+    };
 
-            order_book.asks.iter_mut().for_each(|(_, lvl2)| {
-                lvl2.retain(|entry| entry.amount != 0 && lock.get(&entry.client).is_some());
+    let flag = std::fs::read_to_string("flag").unwrap();
+    let credentials = match std::fs::read_to_string("accounts") {
+        Ok(credentials) => credentials,
+        Err(e) => {
+            eprintln!("can't read the accounts file: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // without a token nobody could use the admin api, it isn't served then
+    let admin_token = std::fs::read_to_string("admin_token")
+        .ok()
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty());
+    // public market data goes out on multicast if there is a config for it
+    let multicast = std::fs::read_to_string("multicast")
+        .ok()
+        .map(|config| multicast::Multicast::from_config(&config).unwrap());
+    // bar intervals of the market statistics, cycle, 1s and 1m without a file
+    let stats = std::fs::read_to_string("bars")
+        .ok()
+        .map_or_else(stats::Stats::default, |config| {
+            stats::Stats::from_config(&config).unwrap()
+        });
+    let audit = Audit::new(audit::AuditLog::open(audit::AUDIT).unwrap());
+    // every order carries the correlation id of the message it came in with
+    let (order_sender, orders) = channel();
+    let socket = UdpSocket::bind(UDP_ADDR).unwrap();
+    let sessions = Sessions::new(SessionTable::new(socket.try_clone().unwrap(), audit.clone()));
+    // accounts, their funding and what they may do, queued before anyone can log on
+    if let Ok(config) = std::fs::read_to_string("provisioning") {
+        let commands = account::provisioning(&config).unwrap();
+        let addr = SocketAddr::from(([0, 0, 0, 0], 0));
+        let id = audit.get().inbound("config", addr, config.as_bytes());
+        for cmd in commands {
+            // nobody waits for the answer
+            let (reply, _) = channel();
+            order_sender.send((addr, Order::Admin(cmd, reply), id));
+        }
+    }
+    let tsessions = sessions.clone();
+    let logons = Logons::new(LogonTable::from_accounts(&credentials));
+    let tlogons = logons.clone();
+    let accounts = restored.accounts;
+    let taccounts = accounts.clone();
+    for account in accounts.get().accounts.values().filter(|a| a.closed) {
+        logons.get().close_account(&account.id);
+    }
+    let mut order_book = restored.order_book;
+    let mut feed = marketdata::Feed::new(multicast, stats);
+    let standbys = replication::Standbys::new(Vec::new());
+    let mut journal = journal::Journal::open(journal::JOURNAL, standbys.clone()).unwrap();
+    // what `Restored::apply` does with the START the journal just got
+    accounts.get().logout_all();
+    let mut ledger = ledger::Ledger::open(ledger::LEDGER).unwrap();
+
+    let listener = TcpListener::bind(replication::REPLICATION_ADDR).unwrap();
+    std::thread::spawn(move || replication::listen(listener, standbys));
+
+    let listener = TcpListener::bind(tcp::TCP_ADDR).unwrap();
+    {
+        let (logons, sessions, accounts) = (logons.clone(), sessions.clone(), accounts.clone());
+        let (audit, order_sender) = (audit.clone(), order_sender.clone());
+        std::thread::spawn(move || {
+            tcp::listen(listener, logons, sessions, accounts, audit, order_sender)
+        });
+    }
+
+    let listener = TcpListener::bind(fix::FIX_ADDR).unwrap();
+    {
+        let (logons, sessions, accounts) = (logons.clone(), sessions.clone(), accounts.clone());
+        let (audit, order_sender) = (audit.clone(), order_sender.clone());
+        std::thread::spawn(move || {
+            fix::listen(listener, logons, sessions, accounts, audit, order_sender)
+        });
+    }
+
+    let listener = TcpListener::bind(websocket::WS_ADDR).unwrap();
+    {
+        let (logons, sessions, accounts) = (logons.clone(), sessions.clone(), accounts.clone());
+        let (audit, order_sender) = (audit.clone(), order_sender.clone());
+        std::thread::spawn(move || {
+            websocket::listen(listener, logons, sessions, accounts, audit, order_sender)
+        });
+    }
+
+    match admin_token {
+        Some(token) => {
+            let listener = TcpListener::bind(admin::ADMIN_ADDR).unwrap();
+            let (logons, accounts) = (logons.clone(), accounts.clone());
+            let (audit, order_sender) = (audit.clone(), order_sender.clone());
+            std::thread::spawn(move || {
+                admin::listen(listener, token, logons, accounts, audit, order_sender)
             });
+        }
+        None => eprintln!("no admin_token, the admin api is disabled"),
+    }
 
-            let mut buffer = [0u8; 0x1000];
-            let mut idx = 1;
-            buffer[0] = 0xc1;
-            for (strike, lvl2) in order_book.bids.iter_mut().rev() {
-                let volume: isize = lvl2
-                    .iter_mut()
-                    .map(|entry| {
-                        entry.cycles_present += 1;
-                        entry.amount
-                    })
-                    .sum();
-                let strike = (*strike as f64 / FLOATING_TO_FIXED_OFF);
-                buffer[idx..idx + 8].copy_from_slice(&strike.to_le_bytes()[..]);
-                buffer[idx + 8..idx + 16].copy_from_slice(&volume.to_le_bytes()[..]);
-                idx += 16;
-
-                if idx >= 0x800 {
-                    break;
-                }
-            }
+    {
+        let audit = audit.clone();
+        std::thread::spawn(move || {
+            client_rx(socket, tlogons, tsessions, taccounts, audit, order_sender)
+        });
+    }
 
-            idx = 0x800;
-            buffer[idx] = 0xc2;
+    let order_waiter = std::time::Duration::from_millis(10);
+    let mut halted = restored.halted;
+    let mut cycles = 0u64;
+    loop {
+        let now = std::time::Instant::now();
 
-            for (strike, lvl2) in order_book.asks.iter_mut() {
-                let volume: isize = lvl2
-                    .iter_mut()
-                    .map(|entry| {
-                        entry.cycles_present += 1;
-                        entry.amount
-                    })
-                    .sum();
-                let strike = (*strike as f64 / FLOATING_TO_FIXED_OFF);
-                buffer[idx..idx + 8].copy_from_slice(&strike.to_le_bytes()[..]);
-                buffer[idx + 8..idx + 16].copy_from_slice(&strike.to_le_bytes()[..]);
-                idx += 16;
-                if idx >= buffer.len() {
-                    break;
+        while now.elapsed().subsec_millis() < 500 {
+            if let Ok((caddr, order, id)) = orders.recv_timeout(order_waiter) {
+                journal.order(caddr, &order);
+                audit.get().order(id, caddr, &order);
+                let before = audit::balances(&accounts.get());
+                {
+                    let mut out = sessions.get();
+                    out.correlate(id);
+                    execute(
+                        &mut order_book,
+                        &mut feed,
+                        &accounts,
+                        &mut out,
+                        &mut halted,
+                        caddr,
+                        order,
+                    );
                 }
+                let mut audit = audit.get();
+                audit.events(id, &order_book.events.ledger);
+                audit.transfers(id, &accounts.get().transfers);
+                audit.balances(id, &before, &accounts.get());
+                ledger.record(&mut order_book, &mut accounts.get());
+            } else {
+                // throttled subscriptions come due while nothing happens
+                let mut out = sessions.get();
+                out.correlate(0);
+                feed.flush(&mut out);
             }
+        }
 
-            for (addr, client) in lock.iter_mut() {
-                client.cycles_present += 1;
-                socket.send_to(&buffer, addr);
-                socket.send_to(&client.to_bytes(), addr);
-            }
+        let expired = logons.get().expired();
+        journal.cycle(&expired);
+        let id = audit.get().cycle(&expired);
+        let before = audit::balances(&accounts.get());
+        let closed = {
+            let mut out = sessions.get();
+            out.correlate(id);
+            end_cycle(
+                &mut order_book,
+                &mut feed,
+                &accounts,
+                &mut out,
+                halted,
+                flag.as_bytes(),
+                &expired,
+            )
+        };
+        {
+            let mut audit = audit.get();
+            audit.events(id, &order_book.events.ledger);
+            audit.balances(id, &before, &accounts.get());
+        }
+        ledger.record(&mut order_book, &mut accounts.get());
+        ledger.cycle(&accounts.get());
+        let mut logons = logons.get();
+        closed.iter().for_each(|id| logons.close_account(id));
+
+        cycles += 1;
+        if cycles.is_multiple_of(snapshot::SNAPSHOT_CYCLES) {
+            // a failed snapshot only means a longer replay, the next one may work out
+            snapshot::save(
+                snapshot::SNAPSHOT,
+                journal.len(),
+                &order_book,
+                &accounts.get(),
+                halted,
+            );
         }
     }
 }
-
-// TODO: SENT OUT NBBO/LVL2
- 
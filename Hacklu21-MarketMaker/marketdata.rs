@@ -0,0 +1,1264 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::account::Accounts;
+use crate::ledger::Execution;
+use crate::multicast::Multicast;
+use crate::session::SessionTable;
+use crate::stats::Stats;
+use crate::{BookEntry, OrderBook, FLOATING_TO_FIXED_OFF};
+
+/// `[0xb1][seq u64][bid f64][bid size isize][ask f64][ask size isize][last f64][last size isize]`,
+/// an empty side or no trade yet is price and size 0.
+pub(crate) const TOP_OF_BOOK: u8 = 0xb1;
+/// `[0xb2][seq u64][count u16]` and `count` times `[action u8][side u8][price f64][volume isize]`,
+/// see `ADD`, `UPDATE` and `DELETE` and `BID` and `ASK`. Every message takes the next sequence
+/// number of the depth feed.
+pub(crate) const DEPTH_DELTA: u8 = 0xb2;
+/// `[0xb3][seq u64][fragment u16][fragments u16][count u16]` and `count` times
+/// `[side u8][price f64][volume isize]`, bids best first then asks best first. `seq` is the
+/// last delta the snapshot includes, it doesn't take a sequence number of its own.
+pub(crate) const DEPTH_SNAPSHOT: u8 = 0xb3;
+/// `[0xb4][count u16]` and `count` times `[len u16][event]`, every event starts with
+/// `[type u8][seq u64][timestamp u64]`, ns since the epoch, followed by
+/// - `A` add: `[order id isize][side u8][amount isize][price f64]`
+/// - `E` executed: `[order id isize][amount isize][match id u64]`
+/// - `X` cancel: `[order id isize][amount isize]`, what was left of the order
+/// - `U` replace: `[order id isize][new order id isize][amount isize][price f64]`
+/// - `P` trade: `[side u8][amount isize][price f64][match id u64]`, a fill without a resting
+///   order on the other side
+///
+/// Sides are `BUY` and `SELL`, a resting order is gone once it is executed down to 0.
+pub(crate) const ORDER_EVENTS: u8 = 0xb4;
+
+/// `[0xb7][count u16]` and `count` times
+/// `[trade id u64][timestamp u64][price f64][amount isize][aggressor u8]`, every fill in the
+/// book. Trade ids count up without gaps, they are the sequence numbers of the feed. The
+/// aggressor is `BUY`, `SELL` or `AUCTION`.
+pub(crate) const TRADES: u8 = 0xb7;
+
+/// `[0xc1][seq u64][timestamp u64][fragment u16][fragments u16][bids u16][asks u16]` followed
+/// by `bids` bid and `asks` ask levels `[price f64][volume isize][orders u32]`, both best first.
+/// Sent every cycle, a deep book is split into several fragments with the same `seq`.
+pub(crate) const DEPTH: u8 = 0xc1;
+
+/// `[0xb5][feed u8][status u8]`, the answer to a subscribe or unsubscribe request.
+pub(crate) const SUBSCRIPTION: u8 = 0xb5;
+/// `[0xb6][feed u8][status u8][first seq u64][last seq u64]`, the answer to a recovery request,
+/// sent after whatever was resent. `first` and `last` are the sequence numbers that were
+/// resent, both 0 if nothing was.
+pub(crate) const RECOVERY: u8 = 0xb6;
+
+/// The only thing traded here.
+pub(crate) const INSTRUMENT: &str = "MM";
+
+/// `TOP_OF_BOOK`
+pub(crate) const FEED_L1: u8 = 1;
+/// `DEPTH`, and `DEPTH_DELTA` and `DEPTH_SNAPSHOT` when subscribed with a depth of 0
+pub(crate) const FEED_L2: u8 = 2;
+/// `ORDER_EVENTS`
+pub(crate) const FEED_L3: u8 = 3;
+/// the public trade tape
+pub(crate) const FEED_TRADES: u8 = 4;
+/// the client's own account update every cycle
+pub(crate) const FEED_ACCOUNT: u8 = 5;
+/// `stats::BAR`, whenever a bar closes
+pub(crate) const FEED_STATS: u8 = 6;
+
+pub(crate) const SUBSCRIBED: u8 = 0;
+pub(crate) const UNKNOWN_INSTRUMENT: u8 = 1;
+pub(crate) const UNKNOWN_FEED: u8 = 2;
+pub(crate) const BAD_PARAMETERS: u8 = 3;
+/// the feed is published on multicast only
+pub(crate) const MULTICAST_ONLY: u8 = 4;
+/// the recovery request got everything it asked for
+pub(crate) const RECOVERED: u8 = 0;
+/// part of the range is older than anything still kept, only a snapshot helps
+pub(crate) const UNAVAILABLE: u8 = 5;
+
+/// What goes out on multicast instead of unicast when it is enabled.
+const PUBLIC_FEEDS: [u8; 4] = [FEED_L1, FEED_L2, FEED_TRADES, FEED_STATS];
+
+pub(crate) const ADD: u8 = 0;
+pub(crate) const UPDATE: u8 = 1;
+pub(crate) const DELETE: u8 = 2;
+
+pub(crate) const BID: u8 = 0;
+pub(crate) const ASK: u8 = 1;
+
+pub(crate) const BUY: u8 = b'B';
+pub(crate) const SELL: u8 = b'S';
+/// the cycle cross, where nobody took liquidity from anyone
+pub(crate) const AUCTION: u8 = b'A';
+
+/// Keeps the messages well below the 0x1000 bytes of the old depth buffer.
+const LEVELS_PER_MESSAGE: usize = 64;
+/// A full snapshot goes out every this many cycles, so clients can (re)build their book.
+const SNAPSHOT_CYCLES: u64 = 4;
+const EVENTS_PER_MESSAGE: usize = 32;
+const TRADES_PER_MESSAGE: usize = 64;
+/// Keeps a fragment of the cycle depth within the 0x1000 bytes it always had.
+const LEVELS_PER_FRAGMENT: usize = 200;
+const MAX_THROTTLE_MS: u32 = 60_000;
+/// Queued messages per subscription, throttled subscribers of busy feeds lose the oldest.
+const MAX_PENDING: usize = 1024;
+/// Published messages kept per sequenced feed for recovery requests.
+const RECENT_MESSAGES: usize = 1024;
+/// Sequence numbers a single recovery request may ask for.
+const MAX_RECOVERY: u64 = 1024;
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+/// Every fill in the book goes through here, prices are fixed point.
+#[derive(Debug, Default)]
+pub(crate) struct Tape {
+    pub trades: u64,
+    pub last: (isize, isize),
+    /// `(trade id, timestamp, price, amount, aggressor)` not yet published
+    prints: Vec<(u64, u64, isize, isize, u8)>,
+    /// not yet in the ledger
+    pub executions: Vec<Execution>,
+}
+
+impl Tape {
+    /// Returns the match id of the trade, which doubles as its trade id. The buyer and the
+    /// seller are account ids, `None` is the exchange itself.
+    pub(crate) fn record(
+        &mut self,
+        price: isize,
+        amount: isize,
+        aggressor: u8,
+        buyer: Option<String>,
+        seller: Option<String>,
+    ) -> u64 {
+        self.trades += 1;
+        self.last = (price, amount);
+        let timestamp = now();
+        self.prints
+            .push((self.trades, timestamp, price, amount, aggressor));
+        self.executions.push(Execution {
+            trade_id: self.trades,
+            timestamp,
+            price,
+            amount,
+            aggressor,
+            buyer,
+            seller,
+        });
+        self.trades
+    }
+}
+
+/// What happened to individual orders, prices are fixed point.
+#[derive(Debug, Clone)]
+pub(crate) enum BookEvent {
+    Add {
+        order_id: isize,
+        side: u8,
+        amount: isize,
+        price: isize,
+    },
+    Executed {
+        order_id: isize,
+        amount: isize,
+        match_id: u64,
+    },
+    Cancel {
+        order_id: isize,
+        amount: isize,
+    },
+    Replace {
+        order_id: isize,
+        new_order_id: isize,
+        amount: isize,
+        price: isize,
+    },
+    Trade {
+        side: u8,
+        amount: isize,
+        price: isize,
+        match_id: u64,
+    },
+}
+
+impl BookEvent {
+    fn to_bytes(&self, seq: u64, timestamp: u64) -> Vec<u8> {
+        let price = |price: &isize| (*price as f64 / FLOATING_TO_FIXED_OFF).to_le_bytes();
+
+        let mut res = vec![match self {
+            BookEvent::Add { .. } => b'A',
+            BookEvent::Executed { .. } => b'E',
+            BookEvent::Cancel { .. } => b'X',
+            BookEvent::Replace { .. } => b'U',
+            BookEvent::Trade { .. } => b'P',
+        }];
+        res.extend_from_slice(&seq.to_le_bytes()[..]);
+        res.extend_from_slice(&timestamp.to_le_bytes()[..]);
+
+        match self {
+            BookEvent::Add {
+                order_id,
+                side,
+                amount,
+                price: p,
+            } => {
+                res.extend_from_slice(&order_id.to_le_bytes()[..]);
+                res.push(*side);
+                res.extend_from_slice(&amount.to_le_bytes()[..]);
+                res.extend_from_slice(&price(p)[..]);
+            }
+            BookEvent::Executed {
+                order_id,
+                amount,
+                match_id,
+            } => {
+                res.extend_from_slice(&order_id.to_le_bytes()[..]);
+                res.extend_from_slice(&amount.to_le_bytes()[..]);
+                res.extend_from_slice(&match_id.to_le_bytes()[..]);
+            }
+            BookEvent::Cancel { order_id, amount } => {
+                res.extend_from_slice(&order_id.to_le_bytes()[..]);
+                res.extend_from_slice(&amount.to_le_bytes()[..]);
+            }
+            BookEvent::Replace {
+                order_id,
+                new_order_id,
+                amount,
+                price: p,
+            } => {
+                res.extend_from_slice(&order_id.to_le_bytes()[..]);
+                res.extend_from_slice(&new_order_id.to_le_bytes()[..]);
+                res.extend_from_slice(&amount.to_le_bytes()[..]);
+                res.extend_from_slice(&price(p)[..]);
+            }
+            BookEvent::Trade {
+                side,
+                amount,
+                price: p,
+                match_id,
+            } => {
+                res.push(*side);
+                res.extend_from_slice(&amount.to_le_bytes()[..]);
+                res.extend_from_slice(&price(p)[..]);
+                res.extend_from_slice(&match_id.to_le_bytes()[..]);
+            }
+        }
+        res
+    }
+}
+
+/// Filled by the book as it changes, drained by the feed.
+#[derive(Debug, Default)]
+pub(crate) struct BookEvents {
+    events: Vec<(u64, BookEvent)>,
+    /// the same events, not yet in the ledger
+    pub ledger: Vec<(u64, BookEvent)>,
+}
+
+impl BookEvents {
+    pub(crate) fn push(&mut self, event: BookEvent) {
+        let timestamp = now();
+        self.ledger.push((timestamp, event.clone()));
+        self.events.push((timestamp, event));
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct TopOfBook {
+    bid: (isize, isize),
+    ask: (isize, isize),
+    trades: u64,
+    last: (isize, isize),
+}
+
+/// Price and volume of the first level that still has something resting.
+fn best<'a>(mut levels: impl Iterator<Item = (&'a isize, &'a Vec<BookEntry>)>) -> (isize, isize) {
+    levels
+        .find_map(|(price, entries)| {
+            let volume: isize = entries.iter().map(|entry| entry.amount).sum();
+            (volume != 0).then_some((*price, volume))
+        })
+        .unwrap_or((0, 0))
+}
+
+impl TopOfBook {
+    fn of(order_book: &OrderBook) -> Self {
+        Self {
+            bid: best(order_book.bids.iter().rev()),
+            ask: best(order_book.asks.iter()),
+            trades: order_book.tape.trades,
+            last: order_book.tape.last,
+        }
+    }
+
+    fn to_bytes(&self, seq: u64) -> [u8; 57] {
+        let mut res = [0; 57];
+        res[0] = TOP_OF_BOOK;
+        res[1..9].copy_from_slice(&seq.to_le_bytes()[..]);
+        for (i, (price, size)) in [self.bid, self.ask, self.last].into_iter().enumerate() {
+            let at = 9 + i * 16;
+            let price = price as f64 / FLOATING_TO_FIXED_OFF;
+            res[at..at + 8].copy_from_slice(&price.to_le_bytes()[..]);
+            res[at + 8..at + 16].copy_from_slice(&size.to_le_bytes()[..]);
+        }
+        res
+    }
+}
+
+/// Where open positions are marked: the mid while both sides have something resting, the
+/// last trade otherwise, nothing before the first one.
+pub(crate) fn mark(order_book: &OrderBook) -> Option<f64> {
+    let top = TopOfBook::of(order_book);
+    let price = if top.bid.1 != 0 && top.ask.1 != 0 {
+        (top.bid.0 + top.ask.0) as f64 / 2.0
+    } else if top.trades != 0 {
+        top.last.0 as f64
+    } else {
+        return None;
+    };
+    Some(price / FLOATING_TO_FIXED_OFF)
+}
+
+/// Aggregated volume per `(side, price)`, bids keyed by the negated price so both sides
+/// iterate best first.
+type Levels = BTreeMap<(u8, isize), isize>;
+
+fn levels(order_book: &OrderBook) -> Levels {
+    let bids = order_book
+        .bids
+        .iter()
+        .map(|(price, entries)| ((BID, -price), entries));
+    let asks = order_book
+        .asks
+        .iter()
+        .map(|(price, entries)| ((ASK, *price), entries));
+    bids.chain(asks)
+        .map(|(key, entries)| (key, entries.iter().map(|entry| entry.amount).sum()))
+        .filter(|(_, volume)| *volume != 0)
+        .collect()
+}
+
+fn push_level(msg: &mut Vec<u8>, (side, price): (u8, isize), volume: isize) {
+    let price = if side == BID { -price } else { price };
+    msg.push(side);
+    msg.extend_from_slice(&(price as f64 / FLOATING_TO_FIXED_OFF).to_le_bytes()[..]);
+    msg.extend_from_slice(&volume.to_le_bytes()[..]);
+}
+
+/// What a client wants of one feed. Messages that supersede each other (top of book, cycle
+/// depth, account) are conflated while throttled, everything else queues up.
+#[derive(Debug)]
+struct Subscription {
+    /// levels per side of the cycle depth, 0 for all
+    depth: usize,
+    throttle: Duration,
+    last_sent: Option<Instant>,
+    latest: Vec<Vec<u8>>,
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl Subscription {
+    fn new(depth: usize, throttle: Duration) -> Self {
+        Self {
+            depth,
+            throttle,
+            last_sent: None,
+            latest: Vec::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn queue(&mut self, msg: &[u8]) {
+        if self.pending.len() >= MAX_PENDING {
+            // the sequence numbers show the gap
+            self.pending.pop_front();
+        }
+        self.pending.push_back(msg.to_vec());
+    }
+}
+
+/// What a client gets before it ever subscribes, the same as before there were subscriptions.
+fn default_subscriptions(multicast: bool) -> BTreeMap<u8, Subscription> {
+    [FEED_L2, FEED_ACCOUNT]
+        .into_iter()
+        .filter(|feed| !multicast || !PUBLIC_FEEDS.contains(feed))
+        .map(|feed| (feed, Subscription::new(0, Duration::ZERO)))
+        .collect()
+}
+
+/// `[5][instrument 8 bytes, zero padded][feed u8][depth u16][throttle ms u32]` subscribes or
+/// changes a subscription, `[6][instrument 8 bytes][feed u8]` unsubscribes. Answered with
+/// `[0xb5][feed u8][status u8]`, see `SUBSCRIBED`.
+#[derive(Debug)]
+pub(crate) struct SubscribeRequest {
+    instrument: [u8; 8],
+    pub feed: u8,
+    depth: u16,
+    throttle_ms: u32,
+    subscribe: bool,
+}
+
+impl SubscribeRequest {
+    pub(crate) fn from_bytes(buf: &[u8], subscribe: bool) -> Option<Self> {
+        if buf.len() <= 15 {
+            return None;
+        }
+
+        Some(Self {
+            instrument: buf[0..8].try_into().unwrap(),
+            feed: buf[8],
+            depth: u16::from_le_bytes(buf[9..11].try_into().unwrap()),
+            throttle_ms: u32::from_le_bytes(buf[11..15].try_into().unwrap()),
+            subscribe,
+        })
+    }
+}
+
+/// `[7][instrument 8 bytes, zero padded][feed u8][from seq u64][to seq u64]` asks for the
+/// messages of `FEED_L1`, `FEED_L2` (the deltas), `FEED_L3` or `FEED_TRADES` with sequence
+/// numbers in `from..=to` again, `[8][instrument 8 bytes][feed u8]` for a snapshot of
+/// `FEED_L1` or `FEED_L2` right now or the bars of `FEED_STATS` so far. Answered with
+/// whatever there is, unthrottled and on unicast even if the feed is on multicast, and then
+/// `[0xb6]`, see `RECOVERY`.
+#[derive(Debug)]
+pub(crate) struct RecoveryRequest {
+    instrument: [u8; 8],
+    pub feed: u8,
+    from: u64,
+    to: u64,
+    snapshot: bool,
+}
+
+impl RecoveryRequest {
+    pub(crate) fn from_bytes(buf: &[u8], snapshot: bool) -> Option<Self> {
+        if buf.len() <= 25 {
+            return None;
+        }
+
+        Some(Self {
+            instrument: buf[0..8].try_into().unwrap(),
+            feed: buf[8],
+            from: u64::from_le_bytes(buf[9..17].try_into().unwrap()),
+            to: u64::from_le_bytes(buf[17..25].try_into().unwrap()),
+            snapshot,
+        })
+    }
+}
+
+fn known_instrument(instrument: &[u8; 8]) -> bool {
+    let mut known = INSTRUMENT.as_bytes().to_vec();
+    known.resize(8, 0);
+    instrument[..] == known[..]
+}
+
+/// Market data that goes out as the book changes instead of once per cycle, to whoever
+/// subscribed to it.
+#[derive(Debug, Default)]
+pub(crate) struct Feed {
+    seq: u64,
+    top: TopOfBook,
+    depth_seq: u64,
+    depth: Levels,
+    events_seq: u64,
+    trade_id: u64,
+    cycles: u64,
+    depth_snapshots: u64,
+    subscriptions: BTreeMap<SocketAddr, BTreeMap<u8, Subscription>>,
+    multicast: Option<Multicast>,
+    stats: Stats,
+    /// `(first seq, last seq, msg)` per sequenced feed, oldest first
+    recent: BTreeMap<u8, VecDeque<(u64, u64, Vec<u8>)>>,
+}
+
+impl Feed {
+    pub(crate) fn new(multicast: Option<Multicast>, stats: Stats) -> Self {
+        Self {
+            multicast,
+            stats,
+            ..Self::default()
+        }
+    }
+
+    /// Closed bars go to everyone subscribed to `FEED_STATS`, nobody is by default.
+    fn bars(&mut self, msgs: Vec<Vec<u8>>) {
+        if msgs.is_empty() {
+            return;
+        }
+        self.publish_multicast(&msgs);
+        let addrs: Vec<SocketAddr> = self.subscriptions.keys().copied().collect();
+        for sub in self.subscribers(&addrs, FEED_STATS) {
+            msgs.iter().for_each(|msg| sub.queue(msg));
+        }
+    }
+
+    fn publish_multicast(&self, msgs: &[Vec<u8>]) {
+        if let Some(multicast) = &self.multicast {
+            msgs.iter().for_each(|msg| multicast.publish(msg));
+        }
+    }
+
+    /// Keeps a published message around for recovery requests.
+    fn remember(&mut self, feed: u8, first: u64, last: u64, msg: &[u8]) {
+        let recent = self.recent.entry(feed).or_default();
+        if recent.len() >= RECENT_MESSAGES {
+            recent.pop_front();
+        }
+        recent.push_back((first, last, msg.to_vec()));
+    }
+
+    /// Returns the status for the `SUBSCRIBED` answer.
+    pub(crate) fn subscribe(&mut self, addr: SocketAddr, req: &SubscribeRequest) -> u8 {
+        if !known_instrument(&req.instrument) {
+            return UNKNOWN_INSTRUMENT;
+        }
+        if !(FEED_L1..=FEED_STATS).contains(&req.feed) {
+            return UNKNOWN_FEED;
+        }
+        if req.throttle_ms > MAX_THROTTLE_MS {
+            return BAD_PARAMETERS;
+        }
+        let multicast = self.multicast.is_some();
+        if multicast && PUBLIC_FEEDS.contains(&req.feed) {
+            return MULTICAST_ONLY;
+        }
+
+        let throttle = Duration::from_millis(req.throttle_ms as u64);
+        let mut sub = Subscription::new(req.depth as usize, throttle);
+        // give new subscribers something to start from instead of waiting for a change
+        match req.feed {
+            FEED_L1 => sub.latest = vec![self.top.to_bytes(self.seq).to_vec()],
+            FEED_L2 if sub.depth == 0 => self.snapshot().iter().for_each(|msg| sub.queue(msg)),
+            _ => {}
+        }
+
+        let subscriptions = self
+            .subscriptions
+            .entry(addr)
+            .or_insert_with(|| default_subscriptions(multicast));
+        if req.subscribe {
+            subscriptions.insert(req.feed, sub);
+        } else {
+            subscriptions.remove(&req.feed);
+        }
+        SUBSCRIBED
+    }
+
+    /// Sends `addr` what it asked for right away, followed by the `RECOVERY` answer.
+    pub(crate) fn recover(&self, addr: SocketAddr, req: &RecoveryRequest, out: &mut SessionTable) {
+        let mut status = RECOVERED;
+        let mut resent = Vec::new();
+        if !known_instrument(&req.instrument) {
+            status = UNKNOWN_INSTRUMENT;
+        } else if !(FEED_L1..=FEED_STATS).contains(&req.feed) {
+            status = UNKNOWN_FEED;
+        } else if req.snapshot {
+            match req.feed {
+                FEED_L1 => resent.push((self.seq, self.seq, self.top.to_bytes(self.seq).to_vec())),
+                FEED_L2 => resent.extend(
+                    self.snapshot()
+                        .into_iter()
+                        .map(|msg| (self.depth_seq, self.depth_seq, msg)),
+                ),
+                FEED_STATS => {
+                    resent.extend(self.stats.current(now()).into_iter().map(|msg| (0, 0, msg)))
+                }
+                _ => status = BAD_PARAMETERS,
+            }
+        } else if matches!(req.feed, FEED_ACCOUNT | FEED_STATS)
+            || req.from == 0
+            || req.from > req.to
+            || req.to - req.from >= MAX_RECOVERY
+        {
+            status = BAD_PARAMETERS;
+        } else {
+            let current = match req.feed {
+                FEED_L1 => self.seq,
+                FEED_L2 => self.depth_seq,
+                FEED_L3 => self.events_seq,
+                _ => self.trade_id,
+            };
+            let recent = self.recent.get(&req.feed);
+            let oldest = recent
+                .and_then(|recent| recent.front())
+                .map_or(current + 1, |(first, ..)| *first);
+            if req.from < oldest {
+                status = UNAVAILABLE;
+            }
+            resent.extend(
+                recent
+                    .into_iter()
+                    .flatten()
+                    .filter(|(first, last, _)| *first <= req.to && *last >= req.from)
+                    .cloned(),
+            );
+        }
+
+        for (_, _, msg) in &resent {
+            out.publish(addr, msg);
+        }
+        let first = resent.first().map_or(0, |(first, ..)| *first);
+        let last = resent.last().map_or(0, |(_, last, _)| *last);
+        let mut answer = vec![RECOVERY, req.feed, status];
+        answer.extend_from_slice(&first.to_le_bytes()[..]);
+        answer.extend_from_slice(&last.to_le_bytes()[..]);
+        out.send(addr, &answer);
+    }
+
+    /// The logged on peer moved, its subscriptions go with it.
+    pub(crate) fn rebind(&mut self, from: SocketAddr, to: SocketAddr) {
+        if let Some(subscriptions) = self.subscriptions.remove(&from) {
+            self.subscriptions.insert(to, subscriptions);
+        }
+    }
+
+    /// Every client subscribed to `feed` among `addrs`.
+    fn subscribers<'a>(
+        &'a mut self,
+        addrs: &'a [SocketAddr],
+        feed: u8,
+    ) -> impl Iterator<Item = &'a mut Subscription> + 'a {
+        let multicast = self.multicast.is_some();
+        let subscriptions = &mut self.subscriptions;
+        for addr in addrs {
+            subscriptions
+                .entry(*addr)
+                .or_insert_with(|| default_subscriptions(multicast));
+        }
+        subscriptions
+            .iter_mut()
+            .filter(move |(addr, _)| addrs.contains(addr))
+            .filter_map(move |(_, subscriptions)| subscriptions.get_mut(&feed))
+    }
+
+    /// The account update of one client, called every cycle.
+    pub(crate) fn account(&mut self, addr: SocketAddr, msg: &[u8]) {
+        for sub in self.subscribers(&[addr], FEED_ACCOUNT) {
+            sub.latest = vec![msg.to_vec()];
+        }
+    }
+
+    /// Sends whatever is due, called by the engine whenever it gets around to it. Account
+    /// updates go out sequenced, market data unsequenced.
+    pub(crate) fn flush(&mut self, out: &mut SessionTable) {
+        // timed bars close whether something traded or not
+        let bars = self.stats.roll(now());
+        self.bars(bars);
+
+        let now = Instant::now();
+        for (addr, subscriptions) in self.subscriptions.iter_mut() {
+            for (feed, sub) in subscriptions.iter_mut() {
+                if sub.latest.is_empty() && sub.pending.is_empty()
+                    || sub
+                        .last_sent
+                        .is_some_and(|last| now.duration_since(last) < sub.throttle)
+                {
+                    continue;
+                }
+                sub.last_sent = Some(now);
+
+                for msg in sub.pending.drain(..).chain(sub.latest.drain(..)) {
+                    if *feed == FEED_ACCOUNT {
+                        out.send(*addr, &msg);
+                    } else {
+                        out.publish(*addr, &msg);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Called by the engine after everything that can touch the book, publishes the order
+    /// events, the depth changes and the top of book if it moved or something traded.
+    pub(crate) fn update(
+        &mut self,
+        order_book: &mut OrderBook,
+        out: &mut SessionTable,
+        accounts: &Accounts,
+    ) {
+        let addrs: Vec<SocketAddr> = accounts.get().sessions.keys().copied().collect();
+        self.order_events(&mut order_book.events, &addrs);
+        self.trades(&mut order_book.tape, &addrs);
+        self.depth_deltas(order_book, &addrs);
+
+        let top = TopOfBook::of(order_book);
+        if top != self.top {
+            self.top = top;
+            self.seq += 1;
+
+            let msg = self.top.to_bytes(self.seq);
+            self.remember(FEED_L1, self.seq, self.seq, &msg);
+            self.publish_multicast(&[msg.to_vec()]);
+            for sub in self.subscribers(&addrs, FEED_L1) {
+                sub.latest = vec![msg.to_vec()];
+            }
+        }
+
+        self.flush(out);
+    }
+
+    /// Called by the engine at the end of every cycle after the account updates, on top of
+    /// `update` this sends the cycle depth and the periodic depth snapshot and forgets about
+    /// sessions that are gone.
+    pub(crate) fn cycle(
+        &mut self,
+        order_book: &mut OrderBook,
+        out: &mut SessionTable,
+        accounts: &Accounts,
+    ) {
+        let addrs: Vec<SocketAddr> = accounts.get().sessions.keys().copied().collect();
+        self.subscriptions.retain(|addr, _| addrs.contains(addr));
+        self.update(order_book, out, accounts);
+
+        // built once per distinct depth anyone asked for
+        self.depth_snapshots += 1;
+        let mut depths = BTreeMap::new();
+        let timestamp = now();
+        let seq = self.depth_snapshots;
+        if self.multicast.is_some() {
+            let full = depths
+                .entry(0)
+                .or_insert_with(|| depth(order_book, 0, seq, timestamp));
+            self.publish_multicast(full);
+        }
+        for sub in self.subscribers(&addrs, FEED_L2) {
+            sub.latest = depths
+                .entry(sub.depth)
+                .or_insert_with(|| depth(order_book, sub.depth, seq, timestamp))
+                .clone();
+        }
+
+        let bars = self.stats.cycle(timestamp);
+        self.bars(bars);
+
+        self.cycles += 1;
+        if self.cycles.is_multiple_of(SNAPSHOT_CYCLES) {
+            let snapshot = self.snapshot();
+            self.publish_multicast(&snapshot);
+            for sub in self.subscribers(&addrs, FEED_L2) {
+                if sub.depth == 0 {
+                    snapshot.iter().for_each(|msg| sub.queue(msg));
+                }
+            }
+        }
+
+        self.flush(out);
+    }
+
+    fn order_events(&mut self, events: &mut BookEvents, addrs: &[SocketAddr]) {
+        let events = std::mem::take(&mut events.events);
+        let mut msgs = Vec::new();
+        for chunk in events.chunks(EVENTS_PER_MESSAGE) {
+            let mut msg = vec![ORDER_EVENTS];
+            msg.extend_from_slice(&(chunk.len() as u16).to_le_bytes()[..]);
+            let first = self.events_seq + 1;
+            for (timestamp, event) in chunk {
+                self.events_seq += 1;
+                let event = event.to_bytes(self.events_seq, *timestamp);
+                msg.extend_from_slice(&(event.len() as u16).to_le_bytes()[..]);
+                msg.extend_from_slice(&event);
+            }
+            self.remember(FEED_L3, first, self.events_seq, &msg);
+            msgs.push(msg);
+        }
+
+        for sub in self.subscribers(addrs, FEED_L3) {
+            msgs.iter().for_each(|msg| sub.queue(msg));
+        }
+    }
+
+    fn trades(&mut self, tape: &mut Tape, addrs: &[SocketAddr]) {
+        let prints = std::mem::take(&mut tape.prints);
+        let mut bars = Vec::new();
+        for (_, timestamp, price, amount, _) in &prints {
+            bars.extend(self.stats.roll(*timestamp));
+            self.stats.record(*price, *amount);
+        }
+        self.bars(bars);
+
+        let mut msgs = Vec::new();
+        for chunk in prints.chunks(TRADES_PER_MESSAGE) {
+            let mut msg = vec![TRADES];
+            msg.extend_from_slice(&(chunk.len() as u16).to_le_bytes()[..]);
+            for (trade_id, timestamp, price, amount, aggressor) in chunk {
+                let price = *price as f64 / FLOATING_TO_FIXED_OFF;
+                msg.extend_from_slice(&trade_id.to_le_bytes()[..]);
+                msg.extend_from_slice(&timestamp.to_le_bytes()[..]);
+                msg.extend_from_slice(&price.to_le_bytes()[..]);
+                msg.extend_from_slice(&amount.to_le_bytes()[..]);
+                msg.push(*aggressor);
+            }
+            let first = chunk[0].0;
+            self.trade_id = chunk[chunk.len() - 1].0;
+            self.remember(FEED_TRADES, first, self.trade_id, &msg);
+            msgs.push(msg);
+        }
+
+        self.publish_multicast(&msgs);
+        for sub in self.subscribers(addrs, FEED_TRADES) {
+            msgs.iter().for_each(|msg| sub.queue(msg));
+        }
+    }
+
+    /// The incremental depth only goes to full depth subscribers, a delta stream cut off at
+    /// some depth couldn't be kept consistent.
+    fn depth_deltas(&mut self, order_book: &OrderBook, addrs: &[SocketAddr]) {
+        let depth = levels(order_book);
+
+        let mut changes = Vec::new();
+        for (key, volume) in &depth {
+            match self.depth.get(key) {
+                None => changes.push((ADD, *key, *volume)),
+                Some(old) if old != volume => changes.push((UPDATE, *key, *volume)),
+                _ => {}
+            }
+        }
+        for key in self.depth.keys().filter(|key| !depth.contains_key(key)) {
+            changes.push((DELETE, *key, 0));
+        }
+        self.depth = depth;
+
+        let mut msgs = Vec::new();
+        for chunk in changes.chunks(LEVELS_PER_MESSAGE) {
+            self.depth_seq += 1;
+            let mut msg = vec![DEPTH_DELTA];
+            msg.extend_from_slice(&self.depth_seq.to_le_bytes()[..]);
+            msg.extend_from_slice(&(chunk.len() as u16).to_le_bytes()[..]);
+            for (action, key, volume) in chunk {
+                msg.push(*action);
+                push_level(&mut msg, *key, *volume);
+            }
+            self.remember(FEED_L2, self.depth_seq, self.depth_seq, &msg);
+            msgs.push(msg);
+        }
+
+        self.publish_multicast(&msgs);
+        for sub in self.subscribers(addrs, FEED_L2) {
+            if sub.depth == 0 {
+                msgs.iter().for_each(|msg| sub.queue(msg));
+            }
+        }
+    }
+
+    /// The full depth as of the last delta, split into as many messages as it takes.
+    fn snapshot(&self) -> Vec<Vec<u8>> {
+        let levels: Vec<_> = self.depth.iter().collect();
+        let mut chunks: Vec<_> = levels.chunks(LEVELS_PER_MESSAGE).collect();
+        if chunks.is_empty() {
+            // an empty book is still one message
+            chunks.push(&[]);
+        }
+
+        chunks
+            .iter()
+            .enumerate()
+            .map(|(fragment, chunk)| {
+                let mut msg = vec![DEPTH_SNAPSHOT];
+                msg.extend_from_slice(&self.depth_seq.to_le_bytes()[..]);
+                msg.extend_from_slice(&(fragment as u16).to_le_bytes()[..]);
+                msg.extend_from_slice(&(chunks.len() as u16).to_le_bytes()[..]);
+                msg.extend_from_slice(&(chunk.len() as u16).to_le_bytes()[..]);
+                for (key, volume) in chunk.iter() {
+                    push_level(&mut msg, **key, **volume);
+                }
+                msg
+            })
+            .collect()
+    }
+}
+
+/// The cycle depth with at most `max_levels` per side, all of them for 0, see `DEPTH`.
+fn depth(order_book: &OrderBook, max_levels: usize, seq: u64, timestamp: u64) -> Vec<Vec<u8>> {
+    let max_levels = if max_levels == 0 {
+        usize::MAX
+    } else {
+        max_levels
+    };
+    let side = |side: u8, levels: &mut dyn Iterator<Item = (&isize, &Vec<BookEntry>)>| {
+        levels
+            .map(|(price, entries)| {
+                let resting = entries.iter().filter(|entry| entry.amount != 0);
+                let volume = resting.clone().map(|entry| entry.amount).sum();
+                (side, *price, volume, resting.count() as u32)
+            })
+            .filter(|(_, _, volume, _)| *volume != 0)
+            .take(max_levels)
+            .collect::<Vec<(u8, isize, isize, u32)>>()
+    };
+    let mut levels = side(BID, &mut order_book.bids.iter().rev());
+    levels.extend(side(ASK, &mut order_book.asks.iter()));
+
+    let mut chunks: Vec<_> = levels.chunks(LEVELS_PER_FRAGMENT).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(fragment, chunk)| {
+            let bids = chunk.iter().filter(|(side, ..)| *side == BID).count();
+            let mut msg = vec![DEPTH];
+            msg.extend_from_slice(&seq.to_le_bytes()[..]);
+            msg.extend_from_slice(&timestamp.to_le_bytes()[..]);
+            msg.extend_from_slice(&(fragment as u16).to_le_bytes()[..]);
+            msg.extend_from_slice(&(chunks.len() as u16).to_le_bytes()[..]);
+            msg.extend_from_slice(&(bids as u16).to_le_bytes()[..]);
+            msg.extend_from_slice(&((chunk.len() - bids) as u16).to_le_bytes()[..]);
+            for (_, price, volume, orders) in chunk.iter() {
+                let price = *price as f64 / FLOATING_TO_FIXED_OFF;
+                msg.extend_from_slice(&price.to_le_bytes()[..]);
+                msg.extend_from_slice(&volume.to_le_bytes()[..]);
+                msg.extend_from_slice(&orders.to_le_bytes()[..]);
+            }
+            msg
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::StreamMsg;
+    use std::sync::mpsc::{self, Receiver};
+
+    const MM: [u8; 8] = *b"MM\0\0\0\0\0\0";
+
+    fn entry(amount: isize) -> BookEntry {
+        BookEntry {
+            account: "alice".to_string(),
+            amount,
+            id: 0,
+            cycles_present: 0,
+        }
+    }
+
+    /// A book with one order of `volume` per `(price, volume)`, prices as quoted.
+    fn book(bids: &[(isize, isize)], asks: &[(isize, isize)]) -> OrderBook {
+        let mut book = OrderBook::new();
+        let fixed = |price: isize| price * FLOATING_TO_FIXED_OFF as isize;
+        for (price, volume) in bids {
+            book.bids.insert(fixed(*price), vec![entry(*volume)]);
+        }
+        for (price, volume) in asks {
+            book.asks.insert(fixed(*price), vec![entry(*volume)]);
+        }
+        book
+    }
+
+    /// A table handing everything for one stream peer to the returned receiver.
+    fn client() -> (SessionTable, Receiver<StreamMsg>, SocketAddr) {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        let (tx, rx) = mpsc::channel();
+        let mut table = SessionTable::offline();
+        table.attach(addr, tx);
+        (table, rx, addr)
+    }
+
+    fn received(rx: &Receiver<StreamMsg>) -> Vec<Vec<u8>> {
+        rx.try_iter()
+            .filter_map(|msg| match msg {
+                StreamMsg::Data(msg, _) => Some(msg),
+                StreamMsg::Processed(_) => None,
+            })
+            .collect()
+    }
+
+    fn u16_at(msg: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(msg[at..at + 2].try_into().unwrap())
+    }
+
+    fn u64_at(msg: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(msg[at..at + 8].try_into().unwrap())
+    }
+
+    /// The `[side][price][volume]` levels starting at `at`, `with_action` for deltas.
+    fn levels_at(msg: &[u8], at: usize, with_action: bool) -> Vec<(u8, u8, f64, isize)> {
+        let width = if with_action { 18 } else { 17 };
+        msg[at..]
+            .chunks(width)
+            .map(|level| {
+                let (action, level) = if with_action {
+                    (level[0], &level[1..])
+                } else {
+                    (0, level)
+                };
+                let price = f64::from_bits(u64_at(level, 1));
+                (action, level[0], price, u64_at(level, 9) as isize)
+            })
+            .collect()
+    }
+
+    fn request(feed: u8, from: u64, to: u64) -> RecoveryRequest {
+        RecoveryRequest {
+            instrument: MM,
+            feed,
+            from,
+            to,
+            snapshot: false,
+        }
+    }
+
+    /// What `recover` sent before its answer, and the answer's `(status, first, last)`.
+    fn recover(feed: &Feed, req: &RecoveryRequest) -> (Vec<Vec<u8>>, (u8, u64, u64)) {
+        let (mut table, rx, addr) = client();
+        feed.recover(addr, req, &mut table);
+        let mut msgs = received(&rx);
+        let answer = msgs.pop().unwrap();
+        assert_eq!(
+            (answer[0], answer[1], answer.len()),
+            (RECOVERY, req.feed, 19)
+        );
+        (msgs, (answer[2], u64_at(&answer, 3), u64_at(&answer, 11)))
+    }
+
+    #[test]
+    fn deltas_take_the_next_sequence_number() {
+        let mut feed = Feed::default();
+        feed.depth_deltas(&book(&[(100, 5)], &[(101, 3)]), &[]);
+        let mut book = book(&[(100, 5), (99, 1)], &[]);
+        book.bids.get_mut(&100_000).unwrap().push(entry(2));
+        feed.depth_deltas(&book, &[]);
+        // nothing changed, nothing to send
+        feed.depth_deltas(&book, &[]);
+
+        let recent: Vec<_> = feed.recent[&FEED_L2].iter().collect();
+        assert_eq!(recent.len(), 2);
+        for (i, (first, last, msg)) in recent.iter().enumerate() {
+            let seq = i as u64 + 1;
+            assert_eq!((*first, *last), (seq, seq));
+            assert_eq!((msg[0], u64_at(msg, 1)), (DEPTH_DELTA, seq));
+        }
+        let first = &recent[0].2;
+        assert_eq!(u16_at(first, 9), 2);
+        assert_eq!(
+            levels_at(first, 11, true),
+            [(ADD, BID, 100.0, 5), (ADD, ASK, 101.0, 3)]
+        );
+        let second = &recent[1].2;
+        assert_eq!(u16_at(second, 9), 3);
+        assert_eq!(
+            levels_at(second, 11, true),
+            [
+                (UPDATE, BID, 100.0, 7),
+                (ADD, BID, 99.0, 1),
+                (DELETE, ASK, 101.0, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn snapshots_are_split_into_fragments() {
+        let mut feed = Feed::default();
+        let empty = feed.snapshot();
+        assert_eq!(empty.len(), 1);
+        assert_eq!((u16_at(&empty[0], 13), empty[0].len()), (0, 15));
+
+        let bids: Vec<_> = (1..=150).map(|price| (price, 1)).collect();
+        let asks: Vec<_> = (151..=160).map(|price| (price, 2)).collect();
+        feed.depth_deltas(&book(&bids, &asks), &[]);
+        assert_eq!(feed.depth_seq, 3);
+
+        let snapshot = feed.snapshot();
+        assert_eq!(snapshot.len(), 3);
+        let mut levels = Vec::new();
+        for (i, msg) in snapshot.iter().enumerate() {
+            assert_eq!((msg[0], u64_at(msg, 1)), (DEPTH_SNAPSHOT, 3));
+            assert_eq!((u16_at(msg, 9), u16_at(msg, 11)), (i as u16, 3));
+            assert_eq!(u16_at(msg, 13) as usize, (msg.len() - 15) / 17);
+            levels.extend(levels_at(msg, 15, false));
+        }
+        assert_eq!(levels.len(), 160);
+        // bids best first, then asks best first
+        assert_eq!(levels[0], (0, BID, 150.0, 1));
+        assert_eq!(levels[149], (0, BID, 1.0, 1));
+        assert_eq!(levels[150], (0, ASK, 151.0, 2));
+        assert_eq!(levels[159], (0, ASK, 160.0, 2));
+    }
+
+    #[test]
+    fn the_cycle_depth_is_cut_and_fragmented() {
+        let bids: Vec<_> = (1..=150).map(|price| (price, 1)).collect();
+        let asks: Vec<_> = (151..=250).map(|price| (price, 2)).collect();
+        let book = book(&bids, &asks);
+
+        let full = depth(&book, 0, 7, 42);
+        assert_eq!(full.len(), 2);
+        let header = |msg: &[u8]| {
+            (
+                u64_at(msg, 1),
+                u64_at(msg, 9),
+                u16_at(msg, 17),
+                u16_at(msg, 19),
+                u16_at(msg, 21),
+                u16_at(msg, 23),
+            )
+        };
+        assert_eq!(header(&full[0]), (7, 42, 0, 2, 150, 50));
+        assert_eq!(header(&full[1]), (7, 42, 1, 2, 0, 50));
+
+        let top = depth(&book, 5, 7, 42);
+        assert_eq!(top.len(), 1);
+        assert_eq!(header(&top[0]), (7, 42, 0, 1, 5, 5));
+        let best_bid = f64::from_bits(u64_at(&top[0], 25));
+        let best_ask = f64::from_bits(u64_at(&top[0], 25 + 5 * 20));
+        assert_eq!((best_bid, best_ask), (150.0, 151.0));
+    }
+
+    #[test]
+    fn recovery_resends_the_range_asked_for() {
+        let mut feed = Feed::default();
+        for volume in 1..=5 {
+            feed.depth_deltas(&book(&[(100, volume)], &[]), &[]);
+        }
+
+        let (msgs, answer) = recover(&feed, &request(FEED_L2, 2, 3));
+        assert_eq!(answer, (RECOVERED, 2, 3));
+        let seqs: Vec<_> = msgs.iter().map(|msg| u64_at(msg, 1)).collect();
+        assert_eq!(seqs, [2, 3]);
+
+        // whatever there is past the end
+        let (msgs, answer) = recover(&feed, &request(FEED_L2, 4, 10));
+        assert_eq!((msgs.len(), answer), (2, (RECOVERED, 4, 5)));
+
+        let (msgs, answer) = recover(&feed, &request(FEED_L2, 1, MAX_RECOVERY));
+        assert_eq!((msgs.len(), answer), (5, (RECOVERED, 1, 5)));
+
+        for req in [
+            request(FEED_L2, 0, 3),
+            request(FEED_L2, 3, 2),
+            request(FEED_L2, 1, MAX_RECOVERY + 1),
+            request(FEED_ACCOUNT, 1, 1),
+            request(FEED_STATS, 1, 1),
+        ] {
+            assert_eq!(recover(&feed, &req), (Vec::new(), (BAD_PARAMETERS, 0, 0)));
+        }
+        let unknown = RecoveryRequest {
+            instrument: *b"XX\0\0\0\0\0\0",
+            ..request(FEED_L2, 1, 1)
+        };
+        assert_eq!(recover(&feed, &unknown).1, (UNKNOWN_INSTRUMENT, 0, 0));
+        assert_eq!(recover(&feed, &request(7, 1, 1)).1, (UNKNOWN_FEED, 0, 0));
+    }
+
+    #[test]
+    fn recovery_says_when_part_of_the_range_is_gone() {
+        let mut feed = Feed::default();
+        for seq in 1..=RECENT_MESSAGES as u64 + 100 {
+            feed.remember(FEED_TRADES, seq, seq, &seq.to_le_bytes());
+        }
+        feed.trade_id = RECENT_MESSAGES as u64 + 100;
+
+        let (msgs, answer) = recover(&feed, &request(FEED_TRADES, 95, 110));
+        assert_eq!((msgs.len(), answer), (10, (UNAVAILABLE, 101, 110)));
+        assert_eq!(
+            recover(&feed, &request(FEED_TRADES, 101, 110)).1,
+            (RECOVERED, 101, 110)
+        );
+
+        // nothing published yet, anything asked for is still to come
+        let (msgs, answer) = recover(&feed, &request(FEED_L3, 1, 1));
+        assert_eq!((msgs.len(), answer), (0, (RECOVERED, 0, 0)));
+    }
+
+    #[test]
+    fn snapshot_requests_are_answered_with_the_current_state() {
+        let mut feed = Feed::default();
+        feed.depth_deltas(&book(&[(100, 5)], &[(101, 3)]), &[]);
+
+        let req = RecoveryRequest {
+            snapshot: true,
+            ..request(FEED_L2, 0, 0)
+        };
+        let (msgs, answer) = recover(&feed, &req);
+        assert_eq!(msgs, feed.snapshot());
+        assert_eq!(answer, (RECOVERED, 1, 1));
+
+        let req = RecoveryRequest {
+            snapshot: true,
+            ..request(FEED_L3, 0, 0)
+        };
+        assert_eq!(recover(&feed, &req).1, (BAD_PARAMETERS, 0, 0));
+    }
+
+    #[test]
+    fn subscriptions_are_checked() {
+        let mut feed = Feed::default();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        let req = |instrument, feed, throttle_ms| SubscribeRequest {
+            instrument,
+            feed,
+            depth: 0,
+            throttle_ms,
+            subscribe: true,
+        };
+
+        assert_eq!(
+            feed.subscribe(addr, &req(*b"XX\0\0\0\0\0\0", FEED_L1, 0)),
+            UNKNOWN_INSTRUMENT
+        );
+        assert_eq!(feed.subscribe(addr, &req(MM, 0, 0)), UNKNOWN_FEED);
+        assert_eq!(
+            feed.subscribe(addr, &req(MM, FEED_STATS + 1, 0)),
+            UNKNOWN_FEED
+        );
+        assert_eq!(
+            feed.subscribe(addr, &req(MM, FEED_L1, MAX_THROTTLE_MS + 1)),
+            BAD_PARAMETERS
+        );
+        assert!(feed.subscriptions.is_empty());
+
+        assert_eq!(feed.subscribe(addr, &req(MM, FEED_L1, 1000)), SUBSCRIBED);
+        let feeds: Vec<_> = feed.subscriptions[&addr].keys().copied().collect();
+        assert_eq!(feeds, [FEED_L1, FEED_L2, FEED_ACCOUNT]);
+
+        // the current top goes out right away, later ones wait for the throttle
+        let (mut table, rx, addr) = client();
+        feed.flush(&mut table);
+        assert_eq!(received(&rx), [feed.top.to_bytes(0).to_vec()]);
+        feed.subscriptions
+            .get_mut(&addr)
+            .unwrap()
+            .get_mut(&FEED_L1)
+            .unwrap()
+            .latest = vec![feed.top.to_bytes(1).to_vec()];
+        feed.flush(&mut table);
+        assert!(received(&rx).is_empty());
+
+        let unsubscribe = SubscribeRequest {
+            subscribe: false,
+            ..req(MM, FEED_L1, 0)
+        };
+        assert_eq!(feed.subscribe(addr, &unsubscribe), SUBSCRIBED);
+        assert!(!feed.subscriptions[&addr].contains_key(&FEED_L1));
+    }
+
+    #[test]
+    fn the_top_of_book_skips_what_was_filled() {
+        let mut book = book(&[(100, 5), (101, 0)], &[(103, 0), (104, 2)]);
+        assert_eq!(mark(&book), Some(102.0));
+
+        let top = TopOfBook::of(&book).to_bytes(9);
+        assert_eq!((top[0], u64_at(&top, 1)), (TOP_OF_BOOK, 9));
+        let level = |at| {
+            (
+                f64::from_bits(u64_at(&top, at)),
+                u64_at(&top, at + 8) as isize,
+            )
+        };
+        assert_eq!(
+            [level(9), level(25), level(41)],
+            [(100.0, 5), (104.0, 2), (0.0, 0)]
+        );
+
+        // one sided, the last trade is the mark
+        book.asks.clear();
+        assert_eq!(mark(&book), None);
+        book.tape.record(99_500, 1, BUY, None, None);
+        assert_eq!(mark(&book), Some(99.5));
+    }
+}
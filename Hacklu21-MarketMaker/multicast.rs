@@ -0,0 +1,105 @@
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+
+/// Public market data on multicast instead of the order entry socket. Every message goes out
+/// unchanged on each channel, the sequence numbers in the messages themselves let receivers
+/// of both A and B drop the duplicates.
+#[derive(Debug)]
+pub(crate) struct Multicast {
+    socket: UdpSocket,
+    channels: Vec<SocketAddr>,
+}
+
+impl Multicast {
+    /// Reads the `multicast` file, one `key value` per line:
+    ///
+    /// ```text
+    /// a 239.1.1.1:14560
+    /// b 239.1.1.2:14560
+    /// interface 127.0.0.1
+    /// ttl 1
+    /// ```
+    ///
+    /// `a` is required, `b` is optional. The socket is bound to `interface` (the default is
+    /// `0.0.0.0`), `127.0.0.1` keeps everything on loopback for testing.
+    pub(crate) fn from_config(config: &str) -> Option<Self> {
+        let mut channels = [None, None];
+        let mut interface = Ipv4Addr::UNSPECIFIED;
+        let mut ttl = 1;
+
+        for line in config
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+        {
+            let (key, value) = line.split_once(' ')?;
+            match key {
+                "a" => channels[0] = Some(value.trim().parse::<SocketAddr>().ok()?),
+                "b" => channels[1] = Some(value.trim().parse::<SocketAddr>().ok()?),
+                "interface" => interface = value.trim().parse().ok()?,
+                "ttl" => ttl = value.trim().parse().ok()?,
+                _ => return None,
+            }
+        }
+        channels[0]?;
+        if !channels
+            .iter()
+            .flatten()
+            .all(|addr| addr.ip().is_multicast())
+        {
+            return None;
+        }
+
+        let socket = UdpSocket::bind((interface, 0)).ok()?;
+        socket.set_multicast_ttl_v4(ttl).ok()?;
+        socket.set_multicast_loop_v4(true).ok()?;
+        Some(Self {
+            socket,
+            channels: channels.into_iter().flatten().collect(),
+        })
+    }
+
+    pub(crate) fn publish(&self, msg: &[u8]) {
+        for channel in &self.channels {
+            self.socket.send_to(msg, channel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn parses_the_config() {
+        let multicast = Multicast::from_config("a 239.1.1.1:14560\nb 239.1.1.2:14560\n\nttl 2\n");
+        assert_eq!(multicast.unwrap().channels.len(), 2);
+        assert!(Multicast::from_config("b 239.1.1.2:14560").is_none());
+        assert!(Multicast::from_config("a 127.0.0.1:14560").is_none());
+        assert!(Multicast::from_config("a 239.1.1.1:14560\nport 1").is_none());
+        assert!(Multicast::from_config("a 239.1.1.1").is_none());
+    }
+
+    #[test]
+    fn publishes_on_every_channel_over_loopback() {
+        let group = Ipv4Addr::new(239, 1, 1, 3);
+        let receiver = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        let port = receiver.local_addr().unwrap().port();
+        receiver
+            .join_multicast_v4(&group, &Ipv4Addr::LOCALHOST)
+            .unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+
+        let config = format!("a {0}:{1}\nb {0}:{1}\ninterface 127.0.0.1\n", group, port);
+        let multicast = Multicast::from_config(&config).unwrap();
+        multicast.publish(b"\x01market data");
+
+        let mut buf = [0; 64];
+        for _ in 0..2 {
+            let (len, _) = receiver.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"\x01market data");
+        }
+    }
+}
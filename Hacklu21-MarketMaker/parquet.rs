@@ -0,0 +1,421 @@
+use std::fs::File;
+use std::io::Write;
+
+const MAGIC: &[u8; 4] = b"PAR1";
+
+// physical types
+const INT64: i32 = 2;
+const DOUBLE: i32 = 5;
+const BYTE_ARRAY: i32 = 6;
+// the rest of the format the writer uses
+const REQUIRED: i32 = 0;
+const CONVERTED_UTF8: i32 = 0;
+const PLAIN: i32 = 0;
+const RLE: i32 = 3;
+const UNCOMPRESSED: i32 = 0;
+const DATA_PAGE: i32 = 0;
+
+// thrift compact protocol field types
+const T_I32: u8 = 5;
+const T_I64: u8 = 6;
+const T_BINARY: u8 = 8;
+const T_LIST: u8 = 9;
+const T_STRUCT: u8 = 12;
+
+/// The values of one column, every row has one.
+#[derive(Debug)]
+pub(crate) enum Column {
+    Int(Vec<i64>),
+    Double(Vec<f64>),
+    Text(Vec<String>),
+}
+
+impl Column {
+    fn len(&self) -> usize {
+        match self {
+            Column::Int(values) => values.len(),
+            Column::Double(values) => values.len(),
+            Column::Text(values) => values.len(),
+        }
+    }
+
+    fn physical_type(&self) -> i32 {
+        match self {
+            Column::Int(_) => INT64,
+            Column::Double(_) => DOUBLE,
+            Column::Text(_) => BYTE_ARRAY,
+        }
+    }
+
+    /// PLAIN encoding, there are no definition or repetition levels for required columns.
+    fn plain(&self) -> Vec<u8> {
+        let mut res = Vec::new();
+        match self {
+            Column::Int(values) => values
+                .iter()
+                .for_each(|v| res.extend_from_slice(&v.to_le_bytes()[..])),
+            Column::Double(values) => values
+                .iter()
+                .for_each(|v| res.extend_from_slice(&v.to_le_bytes()[..])),
+            Column::Text(values) => values.iter().for_each(|v| {
+                res.extend_from_slice(&(v.len() as u32).to_le_bytes()[..]);
+                res.extend_from_slice(v.as_bytes());
+            }),
+        }
+        res
+    }
+}
+
+/// Just enough of the thrift compact protocol for the page headers and the footer.
+#[derive(Debug, Default)]
+struct Thrift {
+    buf: Vec<u8>,
+    /// the last field id of every struct that is open
+    last: Vec<i16>,
+}
+
+impl Thrift {
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.buf.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        self.buf.push(v as u8);
+    }
+
+    fn zigzag(&mut self, v: i64) {
+        self.varint(((v << 1) ^ (v >> 63)) as u64);
+    }
+
+    fn field(&mut self, id: i16, kind: u8) {
+        // UNWRAP: fields only go into a struct that was begun
+        let last = self.last.last_mut().unwrap();
+        let delta = id - std::mem::replace(last, id);
+        if (1..=15).contains(&delta) {
+            self.buf.push((delta as u8) << 4 | kind);
+        } else {
+            self.buf.push(kind);
+            self.zigzag(id as i64);
+        }
+    }
+
+    fn i32(&mut self, id: i16, v: i32) {
+        self.field(id, T_I32);
+        self.zigzag(v as i64);
+    }
+
+    fn i64(&mut self, id: i16, v: i64) {
+        self.field(id, T_I64);
+        self.zigzag(v);
+    }
+
+    fn string(&mut self, id: i16, v: &str) {
+        self.field(id, T_BINARY);
+        self.varint(v.len() as u64);
+        self.buf.extend_from_slice(v.as_bytes());
+    }
+
+    fn list(&mut self, id: i16, kind: u8, len: usize) {
+        self.field(id, T_LIST);
+        if len < 15 {
+            self.buf.push((len as u8) << 4 | kind);
+        } else {
+            self.buf.push(0xf0 | kind);
+            self.varint(len as u64);
+        }
+    }
+
+    /// Starts a struct on its own or as an element of a list.
+    fn begin(&mut self) {
+        self.last.push(0);
+    }
+
+    /// Starts a struct that is a field of the one around it.
+    fn struct_field(&mut self, id: i16) {
+        self.field(id, T_STRUCT);
+        self.begin();
+    }
+
+    fn end(&mut self) {
+        self.buf.push(0);
+        self.last.pop();
+    }
+}
+
+/// Writes `columns` as a parquet file with one row group and one uncompressed data page per
+/// column. Integers are INT64, text is a UTF8 BYTE_ARRAY, every column is required.
+pub(crate) fn write(path: &str, columns: &[(&str, Column)]) -> std::io::Result<()> {
+    let rows = columns.first().map_or(0, |(_, column)| column.len());
+    let mut file = MAGIC.to_vec();
+
+    // (offset, size) of every column chunk
+    let mut chunks = Vec::new();
+    for (_, column) in columns {
+        let data = column.plain();
+        let mut header = Thrift::default();
+        header.begin();
+        header.i32(1, DATA_PAGE);
+        header.i32(2, data.len() as i32);
+        header.i32(3, data.len() as i32);
+        header.struct_field(5);
+        header.i32(1, column.len() as i32);
+        header.i32(2, PLAIN);
+        header.i32(3, RLE);
+        header.i32(4, RLE);
+        header.end();
+        header.end();
+
+        chunks.push((file.len(), header.buf.len() + data.len()));
+        file.extend_from_slice(&header.buf);
+        file.extend_from_slice(&data);
+    }
+
+    let mut meta = Thrift::default();
+    meta.begin();
+    meta.i32(1, 1);
+    meta.list(2, T_STRUCT, columns.len() + 1);
+    meta.begin();
+    meta.string(4, "schema");
+    meta.i32(5, columns.len() as i32);
+    meta.end();
+    for (name, column) in columns {
+        meta.begin();
+        meta.i32(1, column.physical_type());
+        meta.i32(3, REQUIRED);
+        meta.string(4, name);
+        if let Column::Text(_) = column {
+            meta.i32(6, CONVERTED_UTF8);
+        }
+        meta.end();
+    }
+    meta.i64(3, rows as i64);
+
+    meta.list(4, T_STRUCT, 1);
+    meta.begin();
+    meta.list(1, T_STRUCT, columns.len());
+    for ((name, column), (offset, size)) in columns.iter().zip(&chunks) {
+        meta.begin();
+        meta.i64(2, *offset as i64);
+        meta.struct_field(3);
+        meta.i32(1, column.physical_type());
+        meta.list(2, T_I32, 1);
+        meta.zigzag(PLAIN as i64);
+        meta.list(3, T_BINARY, 1);
+        meta.varint(name.len() as u64);
+        meta.buf.extend_from_slice(name.as_bytes());
+        meta.i32(4, UNCOMPRESSED);
+        meta.i64(5, column.len() as i64);
+        meta.i64(6, *size as i64);
+        meta.i64(7, *size as i64);
+        meta.i64(9, *offset as i64);
+        meta.end();
+        meta.end();
+    }
+    let total: usize = chunks.iter().map(|(_, size)| size).sum();
+    meta.i64(2, total as i64);
+    meta.i64(3, rows as i64);
+    meta.end();
+    meta.string(6, "mm");
+    meta.end();
+
+    file.extend_from_slice(&meta.buf);
+    file.extend_from_slice(&(meta.buf.len() as u32).to_le_bytes()[..]);
+    file.extend_from_slice(MAGIC);
+    File::create(path)?.write_all(&file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    /// A thrift compact value, read back to check what the writer put there.
+    #[derive(Debug, PartialEq)]
+    enum Value {
+        Int(i64),
+        Binary(Vec<u8>),
+        List(Vec<Value>),
+        Struct(BTreeMap<i16, Value>),
+    }
+
+    impl Value {
+        fn field(&self, id: i16) -> &Value {
+            match self {
+                Value::Struct(fields) => &fields[&id],
+                _ => panic!("not a struct: {:?}", self),
+            }
+        }
+
+        fn int(&self, id: i16) -> i64 {
+            match self.field(id) {
+                Value::Int(v) => *v,
+                v => panic!("not an int: {:?}", v),
+            }
+        }
+
+        fn list(&self, id: i16) -> &[Value] {
+            match self.field(id) {
+                Value::List(values) => values,
+                v => panic!("not a list: {:?}", v),
+            }
+        }
+
+        fn string(&self, id: i16) -> &str {
+            match self.field(id) {
+                Value::Binary(v) => std::str::from_utf8(v).unwrap(),
+                v => panic!("not binary: {:?}", v),
+            }
+        }
+    }
+
+    struct Reader<'a>(&'a [u8]);
+
+    impl Reader<'_> {
+        fn byte(&mut self) -> u8 {
+            let b = self.0[0];
+            self.0 = &self.0[1..];
+            b
+        }
+
+        fn varint(&mut self) -> u64 {
+            let mut v = 0;
+            for shift in (0..).step_by(7) {
+                let b = self.byte();
+                v |= ((b & 0x7f) as u64) << shift;
+                if b < 0x80 {
+                    break;
+                }
+            }
+            v
+        }
+
+        fn zigzag(&mut self) -> i64 {
+            let v = self.varint();
+            (v >> 1) as i64 ^ -((v & 1) as i64)
+        }
+
+        fn value(&mut self, kind: u8) -> Value {
+            match kind {
+                T_I32 | T_I64 => Value::Int(self.zigzag()),
+                T_BINARY => {
+                    let len = self.varint() as usize;
+                    let (v, rest) = self.0.split_at(len);
+                    self.0 = rest;
+                    Value::Binary(v.to_vec())
+                }
+                T_LIST => {
+                    let header = self.byte();
+                    let len = match header >> 4 {
+                        0xf => self.varint() as usize,
+                        len => len as usize,
+                    };
+                    Value::List((0..len).map(|_| self.value(header & 0xf)).collect())
+                }
+                T_STRUCT => {
+                    let mut fields = BTreeMap::new();
+                    let mut last = 0;
+                    loop {
+                        let header = self.byte();
+                        if header == 0 {
+                            break Value::Struct(fields);
+                        }
+                        last = match header >> 4 {
+                            0 => self.zigzag() as i16,
+                            delta => last + delta as i16,
+                        };
+                        fields.insert(last, self.value(header & 0xf));
+                    }
+                }
+                _ => panic!("unexpected type {}", kind),
+            }
+        }
+    }
+
+    fn read(buf: &[u8]) -> Value {
+        Reader(buf).value(T_STRUCT)
+    }
+
+    #[test]
+    fn thrift_encodes_long_field_deltas_and_lists() {
+        let mut thrift = Thrift::default();
+        thrift.begin();
+        thrift.i32(1, -3);
+        thrift.i64(20, 1 << 40);
+        thrift.list(21, T_I32, 20);
+        (0..20).for_each(|v| thrift.zigzag(v));
+        thrift.end();
+
+        assert_eq!(&thrift.buf[..2], [0x15, 5]);
+        // a delta of 19 doesn't fit the header, the id follows it
+        assert_eq!(&thrift.buf[2..4], [T_I64, 40]);
+        let value = read(&thrift.buf);
+        assert_eq!((value.int(1), value.int(20)), (-3, 1 << 40));
+        let list: Vec<_> = (0..20).map(Value::Int).collect();
+        assert_eq!(value.list(21), list);
+    }
+
+    #[test]
+    fn columns_read_back_from_their_pages() {
+        let path = std::env::temp_dir().join(format!("mm-parquet-{}", std::process::id()));
+        let columns = [
+            ("id", Column::Int(vec![1, -2, 3])),
+            ("price", Column::Double(vec![1.5, 0.0, -2.25])),
+            (
+                "who",
+                Column::Text(vec!["alice".into(), "".into(), "bob".into()]),
+            ),
+        ];
+        write(path.to_str().unwrap(), &columns).unwrap();
+        let file = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            (&file[..4], &file[file.len() - 4..]),
+            (&MAGIC[..], &MAGIC[..])
+        );
+        let footer_len =
+            u32::from_le_bytes(file[file.len() - 8..file.len() - 4].try_into().unwrap());
+        let footer = &file[file.len() - 8 - footer_len as usize..file.len() - 8];
+        let meta = read(footer);
+        assert_eq!((meta.int(1), meta.int(3), meta.string(6)), (1, 3, "mm"));
+
+        let schema = meta.list(2);
+        assert_eq!((schema[0].string(4), schema[0].int(5)), ("schema", 3));
+        let names: Vec<_> = schema[1..].iter().map(|e| e.string(4)).collect();
+        assert_eq!(names, ["id", "price", "who"]);
+        let types: Vec<_> = schema[1..].iter().map(|e| e.int(1) as i32).collect();
+        assert_eq!(types, [INT64, DOUBLE, BYTE_ARRAY]);
+        assert!(schema[1..].iter().all(|e| e.int(3) == REQUIRED as i64));
+        assert_eq!(schema[3].int(6), CONVERTED_UTF8 as i64);
+
+        let groups = meta.list(4);
+        assert_eq!((groups.len(), groups[0].int(3)), (1, 3));
+        let chunks = groups[0].list(1);
+        let mut end = MAGIC.len();
+        for (chunk, (name, column)) in chunks.iter().zip(&columns) {
+            let chunk_meta = chunk.field(3);
+            let offset = chunk_meta.int(9) as usize;
+            let size = chunk_meta.int(6) as usize;
+            assert_eq!((chunk.int(2) as usize, offset), (end, end));
+            assert_eq!(
+                chunk_meta.list(3),
+                [Value::Binary(name.as_bytes().to_vec())]
+            );
+            assert_eq!(chunk_meta.int(5), 3);
+
+            // the page header is followed by exactly the plain values
+            let mut page = Reader(&file[offset..offset + size]);
+            let header = page.value(T_STRUCT);
+            assert_eq!(header.int(1), DATA_PAGE as i64);
+            assert_eq!(header.int(2) as usize, page.0.len());
+            assert_eq!(header.field(5).int(1), 3);
+            assert_eq!(page.0, column.plain());
+            end = offset + size;
+        }
+        assert_eq!(groups[0].int(2) as usize, end - MAGIC.len());
+        assert_eq!(end, file.len() - 8 - footer_len as usize);
+        // text is length prefixed
+        let who = columns[2].1.plain();
+        assert_eq!(who, b"\x05\0\0\0alice\0\0\0\0\x03\0\0\0bob");
+    }
+}
@@ -0,0 +1,147 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::time::Duration;
+
+use crate::journal::{self, Entry, Restored};
+use crate::session::SessionTable;
+use crate::{marketdata, snapshot, stats, MThread};
+
+/// Where the primary serves its journal to standbys, local only.
+pub(crate) const REPLICATION_ADDR: &str = "127.0.0.1:14555";
+
+/// The journal has a cycle record every 500ms, a standby that heard nothing for this long
+/// considers the primary gone.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a standby may hold up the engine before it's dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Between attempts of a standby to reach a primary that isn't up yet.
+const CONNECT_RETRY: Duration = Duration::from_secs(1);
+/// How often a standby that lost its primary tries to reach it again before taking over.
+const TAKEOVER_ATTEMPTS: usize = 3;
+
+/// The connected standbys, every journal record is written to each of them.
+pub(crate) type Standbys = MThread<Vec<TcpStream>>;
+
+/// Primary side: a standby that connects sends `[offset u64]`, how much of the journal it has
+/// already, and gets the rest of it. From then on the journal writes every new record to it
+/// as well. The engine only waits for the little the journal grew while the rest went out.
+pub(crate) fn listen(listener: TcpListener, standbys: Standbys) {
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        stream.set_nodelay(true);
+        stream.set_read_timeout(Some(WRITE_TIMEOUT));
+        stream.set_write_timeout(Some(WRITE_TIMEOUT));
+
+        let mut offset = [0; 8];
+        if stream.read_exact(&mut offset).is_err() {
+            continue;
+        }
+        let offset = u64::from_le_bytes(offset);
+
+        let mut journal = match File::open(journal::JOURNAL) {
+            Ok(journal) => journal,
+            Err(_) => continue,
+        };
+        // a standby with more than there is follows some other journal
+        if journal.metadata().map_or(true, |m| m.len() < offset)
+            || journal.seek(SeekFrom::Start(offset)).is_err()
+        {
+            continue;
+        }
+        if std::io::copy(&mut journal, &mut stream).is_err() {
+            continue;
+        }
+        // the journal doesn't grow while this is held, the records it got in the meantime go
+        // out before the standby gets the next one
+        let mut standbys = standbys.get();
+        if std::io::copy(&mut journal, &mut stream).is_ok() {
+            standbys.push(stream);
+        }
+    }
+}
+
+/// Standby mode: follows the primary at `primary`, mirrors its journal into the local one and
+/// runs every record through the engine as it comes in. Returns the state the primary had
+/// once it is gone, for the standby to take over with. Runs in a working directory of its
+/// own, the journal and snapshot there are replaced by the primary's.
+///
+/// The primary drops a standby that can't keep up, which looks just like the primary dying.
+/// A standby that lost the connection reconnects and carries on where its mirror ends, it
+/// only takes over once the primary can't be reached and nothing holds the exchange's port.
+pub(crate) fn standby(primary: &str) -> Restored {
+    let mut stream = loop {
+        match TcpStream::connect(primary) {
+            Ok(stream) => break stream,
+            Err(_) => std::thread::sleep(CONNECT_RETRY),
+        }
+    };
+
+    // the primary sends its journal from the start, an old snapshot would point into nothing
+    std::fs::remove_file(snapshot::SNAPSHOT);
+    // UNWRAP: a standby without a journal couldn't be restarted after it took over
+    let mut mirror = File::create(journal::JOURNAL).unwrap();
+    let mut offset = 0u64;
+
+    let mut restored = Restored::new();
+    let mut feed = marketdata::Feed::new(None, stats::Stats::default());
+    let mut out = SessionTable::offline();
+    loop {
+        stream.set_read_timeout(Some(HEARTBEAT_TIMEOUT));
+        if stream.write_all(&offset.to_le_bytes()).is_ok() {
+            loop {
+                // only whole records make it into the mirror, a cut off one is sent again
+                // after a reconnect or died with the primary
+                let mut len = [0; 4];
+                if stream.read_exact(&mut len).is_err() {
+                    break;
+                }
+                let mut record = vec![0; u32::from_le_bytes(len) as usize];
+                if stream.read_exact(&mut record).is_err() {
+                    break;
+                }
+                let entry = match journal::decode(&record) {
+                    Some(entry) => entry,
+                    None => break,
+                };
+
+                // UNWRAP: same as for the primary's journal
+                mirror.write_all(&len).unwrap();
+                mirror.write_all(&record).unwrap();
+                offset += (len.len() + record.len()) as u64;
+                if let Entry::Cycle(_) = entry {
+                    // UNWRAP: same as for the primary's journal
+                    mirror.sync_data().unwrap();
+                }
+                restored.apply(&mut feed, &mut out, entry);
+            }
+        }
+        // UNWRAP: same as for the primary's journal
+        mirror.sync_data().unwrap();
+
+        stream = match reconnect(primary) {
+            Some(stream) => stream,
+            None => return restored,
+        };
+    }
+}
+
+/// A new connection to `primary`, `None` if it is gone for good.
+fn reconnect(primary: &str) -> Option<TcpStream> {
+    loop {
+        for _ in 0..TAKEOVER_ATTEMPTS {
+            if let Ok(stream) = TcpStream::connect(primary) {
+                return Some(stream);
+            }
+            std::thread::sleep(CONNECT_RETRY);
+        }
+        // a primary on the same box that stopped taking standbys still serves clients, the
+        // standby would only die binding the port after it
+        if UdpSocket::bind(crate::UDP_ADDR).is_ok() {
+            return None;
+        }
+    }
+}
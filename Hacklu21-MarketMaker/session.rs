@@ -0,0 +1,472 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::Sender;
+
+use crate::audit::Audit;
+use crate::crypto::{self, MAC_LEN};
+use crate::MThread;
+
+/// server -> client: `[0x30][seq u64][payload]`
+pub(crate) const SEQ_DATA_OUT: u8 = 0x30;
+/// client -> server: `[0x31][seq u64][payload]`
+pub(crate) const SEQ_DATA_IN: u8 = 0x31;
+/// server -> client: `[0x32][seq u64]`, cumulative ack of everything `<= seq`
+/// client -> server: `[0x32][seq u64][ack u64]`, cumulative ack of everything `<= ack`
+pub(crate) const SEQ_ACK: u8 = 0x32;
+/// client -> server: `[0x33][seq u64][from u64][to u64]`, inclusive range to retransmit
+pub(crate) const SEQ_RESEND: u8 = 0x33;
+/// server -> client: `[0x34][from u64][to u64]`, range that can no longer be retransmitted
+pub(crate) const SEQ_GONE: u8 = 0x34;
+
+/// What the engine hands to a stream gateway connection, with the correlation id it goes out
+/// under in the audit trail.
+#[derive(Debug)]
+pub(crate) enum StreamMsg {
+    /// a message in the binary protocol, exactly what a udp client would get
+    Data(Vec<u8>, u64),
+    /// the engine is done with the oldest order the peer submitted, gateways that need to tie
+    /// responses to the order they belong to (e.g. FIX `ClOrdID`s) use this as delimiter
+    Processed(u64),
+}
+
+/// Amount of unacknowledged outbound frames we keep around per client for retransmission.
+const RETRANSMIT_WINDOW: usize = 1024;
+
+#[derive(Debug, Default)]
+struct Session {
+    /// last outbound sequence number handed out, first frame carries 1
+    out_seq: u64,
+    /// last inbound sequence number that was processed
+    in_seq: u64,
+    unacked: VecDeque<(u64, Vec<u8>)>,
+    /// key negotiated at logon, every datagram to the client gets a MAC appended with it
+    key: Option<[u8; MAC_LEN]>,
+}
+
+/// Owns the sending half of the order entry socket and the per client sequencing state.
+///
+/// Sessions are opened at logon, from then on all private traffic to the client (responses,
+/// executions, account updates) is wrapped in `SEQ_DATA_OUT` frames and every datagram,
+/// market data included, carries a MAC under the session key. Every frame the client sends,
+/// acks and resend requests included, takes the next inbound sequence number, so none of them
+/// can be replayed. The client retransmits whatever the server's ack doesn't cover yet.
+/// Since the account update goes out every cycle, a lost tail is noticed one cycle later at
+/// the latest, so gap detection on the client side is enough to drive retransmission.
+#[derive(Debug)]
+pub(crate) struct SessionTable {
+    /// `None` when replaying the journal, nothing goes anywhere then
+    socket: Option<UdpSocket>,
+    sessions: BTreeMap<SocketAddr, Session>,
+    /// peers connected through one of the stream gateways, everything for them is handed to
+    /// the connections writer as is, the stream already takes care of ordering and delivery
+    streams: BTreeMap<SocketAddr, Sender<StreamMsg>>,
+    /// `None` when replaying the journal as well
+    audit: Option<Audit>,
+    /// what everything sent goes out under in the audit trail, set by whoever holds the table
+    correlation: u64,
+}
+
+pub(crate) type Sessions = MThread<SessionTable>;
+
+impl SessionTable {
+    pub(crate) fn new(socket: UdpSocket, audit: Audit) -> Self {
+        Self {
+            socket: Some(socket),
+            sessions: BTreeMap::new(),
+            streams: BTreeMap::new(),
+            audit: Some(audit),
+            correlation: 0,
+        }
+    }
+
+    /// A table that drops everything, for running the engine without any network I/O.
+    pub(crate) fn offline() -> Self {
+        Self {
+            socket: None,
+            sessions: BTreeMap::new(),
+            streams: BTreeMap::new(),
+            audit: None,
+            correlation: 0,
+        }
+    }
+
+    /// Everything sent from now on is caused by the message with correlation id `id`.
+    pub(crate) fn correlate(&mut self, id: u64) {
+        self.correlation = id;
+    }
+
+    fn audit(audit: &Option<Audit>, id: u64, transport: &str, addr: SocketAddr, msg: &[u8]) {
+        if let Some(audit) = audit {
+            audit.get().outbound(id, transport, addr, msg);
+        }
+    }
+
+    /// Routes all traffic for `addr` to a stream gateway connection. Dropping the sender again
+    /// through `forget` is what tells the connection to close.
+    pub(crate) fn attach(&mut self, addr: SocketAddr, stream: Sender<StreamMsg>) {
+        self.sessions.remove(&addr);
+        self.streams.insert(addr, stream);
+    }
+
+    /// Starts a fresh session for a client that just logged on.
+    pub(crate) fn open(&mut self, addr: SocketAddr, key: [u8; MAC_LEN]) {
+        self.sessions.insert(
+            addr,
+            Session {
+                key: Some(key),
+                ..Session::default()
+            },
+        );
+    }
+
+    fn transmit(
+        socket: &Option<UdpSocket>,
+        session: Option<&Session>,
+        addr: SocketAddr,
+        frame: &[u8],
+    ) {
+        let socket = match socket {
+            Some(socket) => socket,
+            None => return,
+        };
+        match session.and_then(|s| s.key.as_ref()) {
+            Some(key) => {
+                let mut frame = frame.to_vec();
+                crypto::seal(key, &mut frame);
+                socket.send_to(&frame, addr);
+            }
+            None => {
+                socket.send_to(frame, addr);
+            }
+        }
+    }
+
+    /// Sends private traffic to `addr`, sequenced if it has a session.
+    pub(crate) fn send(&mut self, addr: SocketAddr, payload: &[u8]) {
+        if let Some(stream) = self.streams.get(&addr) {
+            Self::audit(&self.audit, self.correlation, "stream", addr, payload);
+            stream.send(StreamMsg::Data(payload.to_vec(), self.correlation));
+            return;
+        }
+        Self::audit(&self.audit, self.correlation, "udp", addr, payload);
+
+        let session = match self.sessions.get_mut(&addr) {
+            Some(session) => session,
+            None => {
+                Self::transmit(&self.socket, None, addr, payload);
+                return;
+            }
+        };
+
+        session.out_seq += 1;
+        let mut frame = Vec::with_capacity(9 + payload.len());
+        frame.push(SEQ_DATA_OUT);
+        frame.extend_from_slice(&session.out_seq.to_le_bytes()[..]);
+        frame.extend_from_slice(payload);
+
+        Self::transmit(&self.socket, Some(session), addr, &frame);
+
+        if session.unacked.len() >= RETRANSMIT_WINDOW {
+            session.unacked.pop_front();
+        }
+        session.unacked.push_back((session.out_seq, frame));
+    }
+
+    /// Sends public traffic (market data) and session control messages that are never sequenced.
+    pub(crate) fn publish(&self, addr: SocketAddr, payload: &[u8]) {
+        if let Some(stream) = self.streams.get(&addr) {
+            Self::audit(&self.audit, self.correlation, "stream", addr, payload);
+            stream.send(StreamMsg::Data(payload.to_vec(), self.correlation));
+            return;
+        }
+        Self::audit(&self.audit, self.correlation, "udp", addr, payload);
+
+        Self::transmit(&self.socket, self.sessions.get(&addr), addr, payload);
+    }
+
+    /// Marks the end of the responses to the order `addr` submitted last.
+    pub(crate) fn processed(&self, addr: SocketAddr) {
+        if let Some(stream) = self.streams.get(&addr) {
+            stream.send(StreamMsg::Processed(self.correlation));
+        }
+    }
+
+    /// Drops all sequencing state for `addr`, e.g. after it got kicked or logged out.
+    pub(crate) fn forget(&mut self, addr: &SocketAddr) {
+        self.sessions.remove(addr);
+        self.streams.remove(addr);
+    }
+
+    /// Moves the sequencing state of a peer that changed its address.
+    pub(crate) fn rebind(&mut self, from: &SocketAddr, to: SocketAddr) {
+        if let Some(session) = self.sessions.remove(from) {
+            self.sessions.insert(to, session);
+        }
+    }
+
+    /// Whether `frame` is the next one the session of `addr` expects, a fresh frame and no
+    /// duplicate, replay or frame ahead of a gap.
+    pub(crate) fn in_sequence(&self, addr: &SocketAddr, frame: &[u8]) -> bool {
+        let session = match self.sessions.get(addr) {
+            Some(session) => session,
+            None => return false,
+        };
+        match frame.get(1..9) {
+            Some(seq) if [SEQ_DATA_IN, SEQ_ACK, SEQ_RESEND].contains(&frame[0]) => {
+                u64::from_le_bytes(seq.try_into().unwrap()) == session.in_seq + 1
+            }
+            _ => false,
+        }
+    }
+
+    /// Strips the session layer off an inbound datagram.
+    ///
+    /// Returns the payload that should be handed to the order parser, `None` if the datagram was
+    /// session control traffic, a duplicate or arrived ahead of a gap. Out of order frames are not
+    /// buffered, the cumulative ack tells the client where to resume and it retransmits.
+    pub(crate) fn recv<'a>(&mut self, addr: SocketAddr, buf: &'a [u8]) -> Option<&'a [u8]> {
+        let kind = *buf.first()?;
+        let len = match kind {
+            SEQ_DATA_IN => 10,
+            SEQ_ACK => 17,
+            SEQ_RESEND => 25,
+            _ => return None,
+        };
+        if buf.len() < len {
+            return None;
+        }
+        let seq = u64::from_le_bytes(buf[1..9].try_into().unwrap());
+        let session = self.sessions.get_mut(&addr)?;
+
+        let fresh = seq == session.in_seq + 1;
+        if fresh {
+            session.in_seq = seq;
+        }
+
+        let mut ack = [0u8; 9];
+        ack[0] = SEQ_ACK;
+        ack[1..9].copy_from_slice(&session.in_seq.to_le_bytes()[..]);
+        Self::audit(&self.audit, self.correlation, "udp", addr, &ack);
+        Self::transmit(&self.socket, Some(session), addr, &ack);
+
+        if !fresh {
+            return None;
+        }
+        let field = |at: usize| u64::from_le_bytes(buf[at..at + 8].try_into().unwrap());
+        match kind {
+            SEQ_DATA_IN => Some(&buf[9..]),
+            SEQ_ACK => {
+                let acked = field(9);
+                while session.unacked.front().is_some_and(|(s, _)| *s <= acked) {
+                    session.unacked.pop_front();
+                }
+                None
+            }
+            _ => {
+                self.resend(addr, field(9), field(17));
+                None
+            }
+        }
+    }
+
+    fn resend(&self, addr: SocketAddr, from: u64, to: u64) {
+        let session = match self.sessions.get(&addr) {
+            Some(session) => session,
+            None => return,
+        };
+
+        if from > to || to > session.out_seq {
+            return;
+        }
+
+        let oldest = session
+            .unacked
+            .front()
+            .map_or(session.out_seq + 1, |(s, _)| *s);
+        if from < oldest {
+            let mut gone = [0u8; 17];
+            gone[0] = SEQ_GONE;
+            gone[1..9].copy_from_slice(&from.to_le_bytes()[..]);
+            gone[9..17].copy_from_slice(&(oldest - 1).min(to).to_le_bytes()[..]);
+            Self::audit(&self.audit, self.correlation, "udp", addr, &gone);
+            Self::transmit(&self.socket, Some(session), addr, &gone);
+        }
+
+        for (_, frame) in session
+            .unacked
+            .iter()
+            .filter(|(s, _)| *s >= from && *s <= to)
+        {
+            Self::audit(&self.audit, self.correlation, "udp", addr, frame);
+            Self::transmit(&self.socket, Some(session), addr, frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const KEY: [u8; MAC_LEN] = [7; MAC_LEN];
+
+    /// A table sending from one loopback socket to a client on another, with a session open.
+    fn table() -> (SessionTable, UdpSocket, SocketAddr) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let addr = client.local_addr().unwrap();
+
+        let mut table = SessionTable {
+            socket: Some(socket),
+            ..SessionTable::offline()
+        };
+        table.open(addr, KEY);
+        (table, client, addr)
+    }
+
+    /// The next datagram the client got, with the MAC checked and stripped.
+    fn next(client: &UdpSocket) -> Vec<u8> {
+        let mut buf = [0; 2048];
+        let len = client.recv(&mut buf).unwrap();
+        crypto::open(&KEY, &buf[..len]).unwrap().to_vec()
+    }
+
+    fn frame(kind: u8, seq: u64, fields: &[u64], payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![kind];
+        frame.extend_from_slice(&seq.to_le_bytes()[..]);
+        fields
+            .iter()
+            .for_each(|f| frame.extend_from_slice(&f.to_le_bytes()[..]));
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn ack(seq: u64) -> Vec<u8> {
+        frame(SEQ_ACK, seq, &[], &[])
+    }
+
+    #[test]
+    fn data_is_delivered_once_and_in_order() {
+        let (mut table, client, addr) = table();
+
+        let first = frame(SEQ_DATA_IN, 1, &[], b"one");
+        assert_eq!(table.recv(addr, &first), Some(&b"one"[..]));
+        assert_eq!(next(&client), ack(1));
+
+        // a duplicate is acked again but not delivered
+        assert_eq!(table.recv(addr, &first), None);
+        assert_eq!(next(&client), ack(1));
+
+        // ahead of a gap, the ack tells the client where to resume
+        let third = frame(SEQ_DATA_IN, 3, &[], b"three");
+        assert_eq!(table.recv(addr, &third), None);
+        assert_eq!(next(&client), ack(1));
+
+        let second = frame(SEQ_DATA_IN, 2, &[], b"two");
+        assert_eq!(table.recv(addr, &second), Some(&b"two"[..]));
+        assert_eq!(next(&client), ack(2));
+        assert_eq!(table.recv(addr, &third), Some(&b"three"[..]));
+        assert_eq!(next(&client), ack(3));
+    }
+
+    #[test]
+    fn in_sequence_only_accepts_the_next_frame() {
+        let (mut table, _client, addr) = table();
+        let other = SocketAddr::from(([127, 0, 0, 1], 1));
+
+        assert!(table.in_sequence(&addr, &frame(SEQ_DATA_IN, 1, &[], b"x")));
+        assert!(table.in_sequence(&addr, &frame(SEQ_ACK, 1, &[0], &[])));
+        assert!(!table.in_sequence(&addr, &frame(SEQ_DATA_IN, 2, &[], b"x")));
+        assert!(!table.in_sequence(&addr, &frame(SEQ_DATA_OUT, 1, &[], b"x")));
+        assert!(!table.in_sequence(&other, &frame(SEQ_DATA_IN, 1, &[], b"x")));
+
+        table.recv(addr, &frame(SEQ_DATA_IN, 1, &[], b"x"));
+        assert!(!table.in_sequence(&addr, &frame(SEQ_DATA_IN, 1, &[], b"x")));
+        assert!(table.in_sequence(&addr, &frame(SEQ_DATA_IN, 2, &[], b"x")));
+    }
+
+    #[test]
+    fn acks_and_resends_take_a_sequence_number_too() {
+        let (mut table, client, addr) = table();
+        table.send(addr, b"a");
+        next(&client);
+
+        let ack_frame = frame(SEQ_ACK, 1, &[1], &[]);
+        assert_eq!(table.recv(addr, &ack_frame), None);
+        assert_eq!(next(&client), ack(1));
+        assert!(table.sessions[&addr].unacked.is_empty());
+
+        // a replayed resend request is acked, nothing is sent again
+        let resend = frame(SEQ_RESEND, 1, &[1, 1], &[]);
+        assert_eq!(table.recv(addr, &resend), None);
+        assert_eq!(next(&client), ack(1));
+        client
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        assert!(client.recv(&mut [0; 64]).is_err());
+    }
+
+    #[test]
+    fn outbound_frames_are_numbered_and_kept_until_acked() {
+        let (mut table, client, addr) = table();
+        for payload in [b"a", b"b", b"c"] {
+            table.send(addr, payload);
+        }
+        assert_eq!(next(&client), frame(SEQ_DATA_OUT, 1, &[], b"a"));
+        assert_eq!(next(&client), frame(SEQ_DATA_OUT, 2, &[], b"b"));
+        assert_eq!(next(&client), frame(SEQ_DATA_OUT, 3, &[], b"c"));
+
+        table.recv(addr, &frame(SEQ_ACK, 1, &[2], &[]));
+        next(&client);
+        let unacked: Vec<u64> = table.sessions[&addr]
+            .unacked
+            .iter()
+            .map(|(s, _)| *s)
+            .collect();
+        assert_eq!(unacked, [3]);
+    }
+
+    #[test]
+    fn resend_retransmits_the_range() {
+        let (mut table, client, addr) = table();
+        for payload in [b"a", b"b", b"c"] {
+            table.send(addr, payload);
+            next(&client);
+        }
+
+        table.recv(addr, &frame(SEQ_RESEND, 1, &[2, 3], &[]));
+        assert_eq!(next(&client), ack(1));
+        assert_eq!(next(&client), frame(SEQ_DATA_OUT, 2, &[], b"b"));
+        assert_eq!(next(&client), frame(SEQ_DATA_OUT, 3, &[], b"c"));
+    }
+
+    #[test]
+    fn resend_beyond_the_window_reports_what_is_gone() {
+        let (mut table, client, addr) = table();
+        client
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        for _ in 0..RETRANSMIT_WINDOW + 2 {
+            table.send(addr, b"x");
+        }
+        while client.recv(&mut [0; 64]).is_ok() {}
+
+        table.recv(addr, &frame(SEQ_RESEND, 1, &[1, 3], &[]));
+        assert_eq!(next(&client), ack(1));
+        assert_eq!(next(&client), frame(SEQ_GONE, 1, &[2], &[]));
+        assert_eq!(next(&client), frame(SEQ_DATA_OUT, 3, &[], b"x"));
+    }
+
+    #[test]
+    fn frames_without_a_session_are_dropped() {
+        let (mut table, _client, addr) = table();
+        let other = SocketAddr::from(([127, 0, 0, 1], 1));
+        assert_eq!(table.recv(other, &frame(SEQ_DATA_IN, 1, &[], b"x")), None);
+        // too short for its kind
+        assert_eq!(table.recv(addr, &frame(SEQ_ACK, 1, &[], &[])), None);
+        assert_eq!(table.sessions[&addr].in_seq, 0);
+    }
+}
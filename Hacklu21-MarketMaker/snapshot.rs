@@ -0,0 +1,320 @@
+use std::fs::File;
+use std::io::Write;
+use std::net::SocketAddr;
+
+use crate::account::{Account, AccountTable, Policy, Role};
+use crate::journal::{push_addr, push_str, read_addr, read_str};
+use crate::{marketdata, BookEntry, OrderBook};
+
+/// Where the engine keeps its latest snapshot, next to the journal.
+pub(crate) const SNAPSHOT: &str = "snapshot";
+/// A snapshot every this many cycles, about once a minute.
+pub(crate) const SNAPSHOT_CYCLES: u64 = 120;
+
+const MAGIC: &[u8; 4] = b"MMSS";
+/// Bumped with every change to the layout, a snapshot of another version is ignored and the
+/// whole journal replayed instead.
+const VERSION: u16 = 6;
+
+/// The engine state at the end of a cycle and how much of the journal it covers.
+///
+/// `[MMSS][version u16][journal offset u64][halted u8][inc_id isize][trades u64]
+/// [last price isize][last amount isize]`, then `[policies u32]` times `[name][money f64]
+/// [position isize]`, `[limits u32]` times `[kind][max f64]`, `[accounts u32]` times
+/// `[id][policy][role u8][entitlements u8][money f64][is_market_maker u8]
+/// [net_liquidity_contribution isize][position isize][cost_basis f64][realized_pnl f64]
+/// [cycles_present isize][closed u8]`, `[sessions u32]` times `[addr][account id]` and
+/// `[levels u32]` times `[side u8][price isize][entries u32]` followed by `entries` times
+/// `[order id isize][account id][amount isize][cycles_present isize]`. Addresses and account
+/// ids are written like in the journal.
+///
+/// The sessions are only there for the journal records after the snapshot, orders from them
+/// would be refused without. They never outlive a restart: the START record the engine writes
+/// before serving again ends all of them.
+#[derive(Debug)]
+pub(crate) struct Snapshot {
+    /// the journal records from here on came after the snapshot
+    pub offset: u64,
+    pub halted: bool,
+    pub order_book: OrderBook,
+    pub accounts: AccountTable,
+}
+
+/// Writes the snapshot next to `path` first and moves it over, a crash halfway through
+/// leaves the previous one alone.
+pub(crate) fn save(
+    path: &str,
+    offset: u64,
+    order_book: &OrderBook,
+    accounts: &AccountTable,
+    halted: bool,
+) -> std::io::Result<()> {
+    let mut buf = MAGIC.to_vec();
+    buf.extend_from_slice(&VERSION.to_le_bytes()[..]);
+    buf.extend_from_slice(&offset.to_le_bytes()[..]);
+    buf.push(halted as u8);
+    buf.extend_from_slice(&order_book.inc_id.to_le_bytes()[..]);
+    buf.extend_from_slice(&order_book.tape.trades.to_le_bytes()[..]);
+    buf.extend_from_slice(&order_book.tape.last.0.to_le_bytes()[..]);
+    buf.extend_from_slice(&order_book.tape.last.1.to_le_bytes()[..]);
+
+    buf.extend_from_slice(&(accounts.policies.len() as u32).to_le_bytes()[..]);
+    for (name, policy) in &accounts.policies {
+        push_str(&mut buf, name);
+        buf.extend_from_slice(&policy.money.to_le_bytes()[..]);
+        buf.extend_from_slice(&policy.position.to_le_bytes()[..]);
+    }
+    buf.extend_from_slice(&(accounts.limits.len() as u32).to_le_bytes()[..]);
+    for (kind, max) in &accounts.limits {
+        push_str(&mut buf, kind);
+        buf.extend_from_slice(&max.to_le_bytes()[..]);
+    }
+    buf.extend_from_slice(&(accounts.accounts.len() as u32).to_le_bytes()[..]);
+    for account in accounts.accounts.values() {
+        push_str(&mut buf, &account.id);
+        push_str(&mut buf, &account.policy);
+        buf.push(account.role.to_u8());
+        buf.push(account.entitlements);
+        buf.extend_from_slice(&account.money.to_le_bytes()[..]);
+        buf.push(account.is_market_maker as u8);
+        buf.extend_from_slice(&account.net_liquidity_contribution.to_le_bytes()[..]);
+        buf.extend_from_slice(&account.position.to_le_bytes()[..]);
+        buf.extend_from_slice(&account.cost_basis.to_le_bytes()[..]);
+        buf.extend_from_slice(&account.realized_pnl.to_le_bytes()[..]);
+        buf.extend_from_slice(&account.cycles_present.to_le_bytes()[..]);
+        buf.push(account.closed as u8);
+    }
+    buf.extend_from_slice(&(accounts.sessions.len() as u32).to_le_bytes()[..]);
+    for (addr, id) in &accounts.sessions {
+        push_addr(&mut buf, addr);
+        push_str(&mut buf, id);
+    }
+
+    let levels = order_book.bids.len() + order_book.asks.len();
+    buf.extend_from_slice(&(levels as u32).to_le_bytes()[..]);
+    let bids = order_book.bids.iter().map(|level| (marketdata::BID, level));
+    let asks = order_book.asks.iter().map(|level| (marketdata::ASK, level));
+    for (side, (price, entries)) in bids.chain(asks) {
+        buf.push(side);
+        buf.extend_from_slice(&price.to_le_bytes()[..]);
+        buf.extend_from_slice(&(entries.len() as u32).to_le_bytes()[..]);
+        for entry in entries {
+            buf.extend_from_slice(&entry.id.to_le_bytes()[..]);
+            push_str(&mut buf, &entry.account);
+            buf.extend_from_slice(&entry.amount.to_le_bytes()[..]);
+            buf.extend_from_slice(&entry.cycles_present.to_le_bytes()[..]);
+        }
+    }
+
+    let tmp = format!("{}.tmp", path);
+    let mut file = File::create(&tmp)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    std::fs::rename(tmp, path)
+}
+
+/// Moves through a snapshot, every read is `None` past its end.
+struct Reader<'a> {
+    buf: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.buf.get(self.at..self.at + N)?;
+        self.at += N;
+        Some(bytes.try_into().unwrap())
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        Some(self.take::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take()?))
+    }
+
+    fn isize(&mut self) -> Option<isize> {
+        Some(isize::from_le_bytes(self.take()?))
+    }
+
+    fn f64(&mut self) -> Option<f64> {
+        Some(f64::from_le_bytes(self.take()?))
+    }
+
+    fn str(&mut self) -> Option<String> {
+        read_str(self.buf, &mut self.at)
+    }
+
+    fn addr(&mut self) -> Option<SocketAddr> {
+        read_addr(self.buf, &mut self.at)
+    }
+}
+
+/// Reads the snapshot at `path`, `None` if there is none or it can't be used.
+pub(crate) fn load(path: &str) -> Option<Snapshot> {
+    let buf = std::fs::read(path).ok()?;
+    let mut r = Reader { buf: &buf, at: 0 };
+
+    if &r.take::<4>()? != MAGIC || u16::from_le_bytes(r.take()?) != VERSION {
+        return None;
+    }
+    let offset = r.u64()?;
+    let halted = r.byte()? != 0;
+
+    let mut order_book = OrderBook::new();
+    order_book.inc_id = r.isize()?;
+    order_book.tape.trades = r.u64()?;
+    order_book.tape.last = (r.isize()?, r.isize()?);
+
+    let mut accounts = AccountTable::default();
+    for _ in 0..r.u32()? {
+        let name = r.str()?;
+        let policy = Policy {
+            money: r.f64()?,
+            position: r.isize()?,
+        };
+        accounts.policies.insert(name, policy);
+    }
+    for _ in 0..r.u32()? {
+        let kind = r.str()?;
+        accounts.limits.insert(kind, r.f64()?);
+    }
+    for _ in 0..r.u32()? {
+        let account = Account {
+            id: r.str()?,
+            policy: r.str()?,
+            role: Role::from_u8(r.byte()?)?,
+            entitlements: r.byte()?,
+            money: r.f64()?,
+            is_market_maker: r.byte()? != 0,
+            net_liquidity_contribution: r.isize()?,
+            position: r.isize()?,
+            cost_basis: r.f64()?,
+            realized_pnl: r.f64()?,
+            cycles_present: r.isize()?,
+            closed: r.byte()? != 0,
+        };
+        accounts.accounts.insert(account.id.clone(), account);
+    }
+    for _ in 0..r.u32()? {
+        let addr = r.addr()?;
+        accounts.sessions.insert(addr, r.str()?);
+    }
+
+    for _ in 0..r.u32()? {
+        let side = r.byte()?;
+        let price = r.isize()?;
+        let mut entries = Vec::new();
+        for _ in 0..r.u32()? {
+            entries.push(BookEntry {
+                id: r.isize()?,
+                account: r.str()?,
+                amount: r.isize()?,
+                cycles_present: r.isize()?,
+            });
+        }
+        match side {
+            marketdata::BID => order_book.bids.insert(price, entries),
+            marketdata::ASK => order_book.asks.insert(price, entries),
+            _ => return None,
+        };
+    }
+
+    // the mark isn't stored, it follows from the book
+    accounts.mark = marketdata::mark(&order_book);
+
+    Some(Snapshot {
+        offset,
+        halted,
+        order_book,
+        accounts,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin;
+
+    fn path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("mm-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    fn entry(id: isize, account: &str, amount: isize) -> BookEntry {
+        BookEntry {
+            id,
+            account: account.to_string(),
+            amount,
+            cycles_present: id % 3,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = path("snapshot-round-trip");
+
+        let mut accounts = AccountTable::default();
+        let house = Policy {
+            money: 1e6,
+            position: 500,
+        };
+        accounts.policies.insert("house".to_string(), house);
+        accounts.limits.insert("withdrawal".to_string(), 250.5);
+        accounts.logon(SocketAddr::from(([127, 0, 0, 1], 4000)), "alice");
+        accounts.logon(SocketAddr::from(([127, 0, 0, 1], 4001)), "alice");
+        accounts
+            .provision("mm", "house", Role::MarketMaker, 0)
+            .unwrap();
+        let alice = accounts.accounts.get_mut("alice").unwrap();
+        alice.fill(7, 99.25);
+        alice.fill(-3, 101.0);
+        alice.cycles_present = 12;
+        accounts.close("mm");
+
+        let mut order_book = OrderBook::new();
+        order_book.inc_id = 42;
+        order_book.tape.trades = 2;
+        order_book.tape.last = (101_000, 3);
+        order_book
+            .bids
+            .insert(99_000, vec![entry(40, "alice", 2), entry(41, "mm", 10)]);
+        order_book.asks.insert(102_500, vec![entry(39, "mm", 5)]);
+        accounts.mark = marketdata::mark(&order_book);
+
+        save(&path, 1234, &order_book, &accounts, true).unwrap();
+        assert!(std::fs::metadata(format!("{}.tmp", path)).is_err());
+
+        let snapshot = load(&path).unwrap();
+        assert_eq!(snapshot.offset, 1234);
+        assert!(snapshot.halted);
+        assert_eq!(
+            format!("{:?}", snapshot.accounts),
+            format!("{:?}", accounts)
+        );
+        let restored = &snapshot.order_book;
+        assert_eq!(restored.inc_id, 42);
+        assert_eq!(restored.tape.trades, 2);
+        assert_eq!(restored.tape.last, (101_000, 3));
+        assert_eq!(
+            admin::book_json(restored, true).to_string(),
+            admin::book_json(&order_book, true).to_string()
+        );
+
+        // cut short or of another version, the journal is replayed from the start instead
+        let mut buf = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &buf[..buf.len() - 1]).unwrap();
+        assert!(load(&path).is_none());
+        buf[4] += 1;
+        std::fs::write(&path, &buf).unwrap();
+        assert!(load(&path).is_none());
+        let _ = std::fs::remove_file(&path);
+        assert!(load(&path).is_none());
+    }
+}
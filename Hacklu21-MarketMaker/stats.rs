@@ -0,0 +1,308 @@
+use std::time::Duration;
+
+use crate::FLOATING_TO_FIXED_OFF;
+
+/// `[0xb8][interval ms u32][start u64][end u64][open f64][high f64][low f64][close f64]
+/// [volume isize][vwap f64][trades u64][turnover f64][closed u8]`, one bar of the traded
+/// prices. The interval is 0 for the bar of a cycle, start and end are ns since the epoch.
+/// A bar without trades has open, high, low and close at the last price (0 before the first
+/// trade) and a vwap of 0. `closed` is 0 for a bar that is still going, the answer to a query.
+pub(crate) const BAR: u8 = 0xb8;
+
+/// What bars there are without a `bars` file.
+const DEFAULT_INTERVALS: &str = "cycle\n1s\n1m\n";
+
+#[derive(Debug)]
+struct Bar {
+    /// `None` for a bar per cycle
+    interval: Option<Duration>,
+    start: u64,
+    end: u64,
+    open: isize,
+    high: isize,
+    low: isize,
+    close: isize,
+    volume: isize,
+    trades: u64,
+    turnover: f64,
+}
+
+impl Bar {
+    /// An empty bar from `start`, a timed one is aligned to its interval.
+    fn new(interval: Option<Duration>, start: u64, last: isize) -> Self {
+        let (start, end) = match interval {
+            Some(interval) => {
+                let interval = interval.as_nanos() as u64;
+                let start = start - start % interval;
+                (start, start + interval)
+            }
+            None => (start, start),
+        };
+        Self {
+            interval,
+            start,
+            end,
+            open: last,
+            high: last,
+            low: last,
+            close: last,
+            volume: 0,
+            trades: 0,
+            turnover: 0.0,
+        }
+    }
+
+    fn record(&mut self, price: isize, amount: isize) {
+        if self.trades == 0 {
+            self.open = price;
+            self.high = price;
+            self.low = price;
+        }
+        self.high = self.high.max(price);
+        self.low = self.low.min(price);
+        self.close = price;
+        self.volume += amount;
+        self.trades += 1;
+        self.turnover += amount as f64 * price as f64 / FLOATING_TO_FIXED_OFF;
+    }
+
+    fn to_bytes(&self, end: u64, closed: bool) -> Vec<u8> {
+        let price = |price: isize| (price as f64 / FLOATING_TO_FIXED_OFF).to_le_bytes();
+        let interval = self
+            .interval
+            .map_or(0, |interval| interval.as_millis() as u32);
+        let vwap = if self.volume == 0 {
+            0.0
+        } else {
+            self.turnover / self.volume as f64
+        };
+
+        let mut res = vec![BAR];
+        res.extend_from_slice(&interval.to_le_bytes()[..]);
+        res.extend_from_slice(&self.start.to_le_bytes()[..]);
+        res.extend_from_slice(&end.to_le_bytes()[..]);
+        for p in [self.open, self.high, self.low, self.close] {
+            res.extend_from_slice(&price(p)[..]);
+        }
+        res.extend_from_slice(&self.volume.to_le_bytes()[..]);
+        res.extend_from_slice(&vwap.to_le_bytes()[..]);
+        res.extend_from_slice(&self.trades.to_le_bytes()[..]);
+        res.extend_from_slice(&self.turnover.to_le_bytes()[..]);
+        res.push(closed as u8);
+        res
+    }
+}
+
+/// Open, high, low, close, volume, vwap, trade count and turnover of the one instrument over
+/// each configured interval, fed by every fill. Prices are fixed point.
+#[derive(Debug)]
+pub(crate) struct Stats {
+    bars: Vec<Bar>,
+    last: isize,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::from_config(DEFAULT_INTERVALS).unwrap()
+    }
+}
+
+impl Stats {
+    /// Reads the `bars` file, one interval per line, `cycle` or a number followed by `ms`,
+    /// `s` or `m`:
+    ///
+    /// ```text
+    /// cycle
+    /// 1s
+    /// 1m
+    /// ```
+    pub(crate) fn from_config(config: &str) -> Option<Self> {
+        let mut bars = Vec::new();
+        for line in config
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+        {
+            let interval = match line {
+                "cycle" => None,
+                _ => {
+                    let at = line.find(|c: char| !c.is_ascii_digit())?;
+                    let n: u64 = line[..at].parse().ok()?;
+                    let interval = match &line[at..] {
+                        "ms" => Duration::from_millis(n),
+                        "s" => Duration::from_secs(n),
+                        "m" => Duration::from_secs(n * 60),
+                        _ => return None,
+                    };
+                    // the interval goes out as ms in a u32
+                    if interval.is_zero() || interval.as_millis() > u32::MAX as u128 {
+                        return None;
+                    }
+                    Some(interval)
+                }
+            };
+            bars.push(Bar::new(interval, crate::marketdata::now(), 0));
+        }
+
+        Some(Self { bars, last: 0 })
+    }
+
+    /// Closes every timed bar that ended by `now`, returns the `BAR` messages of them. Every
+    /// interval since that went by without a roll gets its flat bar as well. Runs before every
+    /// trade is recorded, so the trade lands in the bar of its timestamp.
+    pub(crate) fn roll(&mut self, now: u64) -> Vec<Vec<u8>> {
+        let mut msgs = Vec::new();
+        for bar in self.bars.iter_mut() {
+            while bar.interval.is_some() && now >= bar.end {
+                msgs.push(bar.to_bytes(bar.end, true));
+                *bar = Bar::new(bar.interval, bar.end, self.last);
+            }
+        }
+        msgs
+    }
+
+    /// Closes the bars per cycle, called at the end of every cycle.
+    pub(crate) fn cycle(&mut self, now: u64) -> Vec<Vec<u8>> {
+        let mut msgs = Vec::new();
+        for bar in self.bars.iter_mut() {
+            if bar.interval.is_none() {
+                msgs.push(bar.to_bytes(now, true));
+                *bar = Bar::new(None, now, self.last);
+            }
+        }
+        msgs
+    }
+
+    pub(crate) fn record(&mut self, price: isize, amount: isize) {
+        self.last = price;
+        self.bars
+            .iter_mut()
+            .for_each(|bar| bar.record(price, amount));
+    }
+
+    /// The bars as they are right now, without closing them.
+    pub(crate) fn current(&self, now: u64) -> Vec<Vec<u8>> {
+        self.bars
+            .iter()
+            .map(|bar| bar.to_bytes(now, false))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    /// A `BAR` message read back.
+    #[derive(Debug)]
+    struct Sent {
+        interval: u32,
+        start: u64,
+        end: u64,
+        ohlc: [f64; 4],
+        volume: isize,
+        vwap: f64,
+        trades: u64,
+        closed: bool,
+    }
+
+    fn sent(msg: &[u8]) -> Sent {
+        assert_eq!((msg[0], msg.len()), (BAR, 86));
+        let u64_at = |at: usize| u64::from_le_bytes(msg[at..at + 8].try_into().unwrap());
+        let f64_at = |at: usize| f64::from_bits(u64_at(at));
+        Sent {
+            interval: u32::from_le_bytes(msg[1..5].try_into().unwrap()),
+            start: u64_at(5),
+            end: u64_at(13),
+            ohlc: [f64_at(21), f64_at(29), f64_at(37), f64_at(45)],
+            volume: u64_at(53) as isize,
+            vwap: f64_at(61),
+            trades: u64_at(69),
+            closed: msg[85] != 0,
+        }
+    }
+
+    fn stats(intervals: &[Option<Duration>]) -> Stats {
+        Stats {
+            bars: intervals
+                .iter()
+                .map(|&interval| Bar::new(interval, 0, 0))
+                .collect(),
+            last: 0,
+        }
+    }
+
+    #[test]
+    fn reads_the_intervals() {
+        let stats = Stats::from_config("cycle\n250ms\n\n2s\n1m\n").unwrap();
+        let intervals: Vec<_> = stats.bars.iter().map(|bar| bar.interval).collect();
+        assert_eq!(
+            intervals,
+            [
+                None,
+                Some(Duration::from_millis(250)),
+                Some(Duration::from_secs(2)),
+                Some(Duration::from_secs(60)),
+            ]
+        );
+        for config in ["1h", "0s", "s", "5", "hourly", "5000000m"] {
+            assert!(Stats::from_config(config).is_none(), "{}", config);
+        }
+        assert_eq!(Stats::default().bars.len(), 3);
+    }
+
+    #[test]
+    fn trades_make_the_bar() {
+        let mut stats = stats(&[None]);
+        for (price, amount) in [(100_000, 2), (105_000, 1), (95_000, 1), (101_000, 4)] {
+            stats.record(price, amount);
+        }
+        let current = sent(&stats.current(SECOND)[0]);
+        assert_eq!(current.ohlc, [100.0, 105.0, 95.0, 101.0]);
+        assert_eq!(
+            (current.volume, current.vwap, current.trades),
+            (8, 100.5, 4)
+        );
+        assert!(!current.closed);
+
+        // the cycle's bar closes and the next one starts flat at the last price
+        let closed = sent(&stats.cycle(2 * SECOND)[0]);
+        assert_eq!(
+            (closed.interval, closed.end, closed.trades),
+            (0, 2 * SECOND, 4)
+        );
+        assert!(closed.closed);
+        let next = sent(&stats.current(3 * SECOND)[0]);
+        assert_eq!(
+            (next.start, next.ohlc, next.volume),
+            (2 * SECOND, [101.0; 4], 0)
+        );
+    }
+
+    #[test]
+    fn rolling_closes_every_interval() {
+        let mut stats = stats(&[None, Some(Duration::from_secs(1))]);
+        stats.record(100_000, 3);
+        assert!(stats.roll(SECOND - 1).is_empty());
+
+        // nothing rolled for a while, the quiet second still gets its bar
+        let closed: Vec<Sent> = stats.roll(5 * SECOND / 2).iter().map(|m| sent(m)).collect();
+        assert_eq!(closed.len(), 2);
+        assert_eq!(
+            (closed[0].interval, closed[0].start, closed[0].end),
+            (1000, 0, SECOND)
+        );
+        assert_eq!((closed[0].volume, closed[0].trades), (3, 1));
+        assert_eq!((closed[1].start, closed[1].end), (SECOND, 2 * SECOND));
+        assert_eq!((closed[1].ohlc, closed[1].trades), ([100.0; 4], 0));
+        assert!(closed.iter().all(|bar| bar.closed));
+
+        // the cycle's bar isn't timed
+        let current: Vec<Sent> = stats.current(3 * SECOND).iter().map(|m| sent(m)).collect();
+        assert_eq!((current[0].start, current[0].trades), (0, 1));
+        assert_eq!(current[1].start, 2 * SECOND);
+        assert_eq!(stats.cycle(3 * SECOND).len(), 1);
+    }
+}
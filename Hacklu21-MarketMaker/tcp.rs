@@ -0,0 +1,204 @@
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+
+use crate::account::Accounts;
+use crate::audit::Audit;
+use crate::logon::{self, LogonRequest, Logons};
+use crate::session::{Sessions, StreamMsg};
+use crate::{accept_logon, parse_order, MThread, Order};
+
+pub(crate) const TCP_ADDR: &str = "0.0.0.0:14551";
+
+/// Same limit as a datagram on the udp side.
+pub(crate) const MAX_FRAME: usize = 2048;
+
+/// Most connections a gateway serves at once, every one of them holds a thread.
+pub(crate) const MAX_CONNECTIONS: usize = 256;
+/// How long a fresh connection has to get its logon in.
+pub(crate) const LOGON_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One of the `MAX_CONNECTIONS` of a gateway, given back when the connection is done.
+pub(crate) struct Slot(MThread<usize>);
+
+impl Slot {
+    /// `None` if the gateway is full, the connection is closed right away then.
+    pub(crate) fn take(open: &MThread<usize>) -> Option<Self> {
+        let mut count = open.get();
+        if *count >= MAX_CONNECTIONS {
+            return None;
+        }
+        *count += 1;
+        Some(Self(open.clone()))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        *self.0.get() -= 1;
+    }
+}
+
+/// Reads one `[len u32][message]` frame into `buffer`, zeroing everything behind the message.
+pub(crate) fn read_frame(stream: &mut impl Read, buffer: &mut [u8; MAX_FRAME]) -> Option<usize> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).ok()?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len == 0 || len >= MAX_FRAME {
+        return None;
+    }
+
+    buffer.iter_mut().for_each(|b| *b = 0);
+    stream.read_exact(&mut buffer[..len]).ok()?;
+    Some(len)
+}
+
+pub(crate) fn write_frame(stream: &mut impl Write, msg: &[u8]) -> std::io::Result<()> {
+    stream.write_all(&(msg.len() as u32).to_le_bytes()[..])?;
+    stream.write_all(msg)
+}
+
+/// Order entry over tcp, every message is framed as `[len u32][message]` and uses the same
+/// layout as on the udp side. The first message has to be a logon, after that orders,
+/// heartbeats and the logout are sent without the `AUTHENTICATED` envelope: the connection
+/// identifies the peer and orders delivery, so neither token, MAC nor sequence numbers are
+/// needed. Closing the connection logs the account out, so does a connection that stays silent
+/// for as long as a logon would expire.
+pub(crate) fn listen(
+    listener: TcpListener,
+    logons: Logons,
+    sessions: Sessions,
+    accounts: Accounts,
+    audit: Audit,
+    order_sender: Sender<(SocketAddr, Order, u64)>,
+) {
+    let open = MThread::new(0);
+    for stream in listener.incoming().flatten() {
+        let slot = match Slot::take(&open) {
+            Some(slot) => slot,
+            None => continue,
+        };
+        let logons = logons.clone();
+        let sessions = sessions.clone();
+        let accounts = accounts.clone();
+        let (audit, order_sender) = (audit.clone(), order_sender.clone());
+        std::thread::spawn(move || {
+            let _slot = slot;
+            connection(stream, logons, sessions, accounts, audit, order_sender)
+        });
+    }
+}
+
+fn connection(
+    mut stream: TcpStream,
+    logons: Logons,
+    sessions: Sessions,
+    accounts: Accounts,
+    audit: Audit,
+    order_sender: Sender<(SocketAddr, Order, u64)>,
+) {
+    let addr = match stream.peer_addr() {
+        Ok(addr) => addr,
+        Err(_) => return,
+    };
+    if stream.set_read_timeout(Some(LOGON_TIMEOUT)).is_err() {
+        return;
+    }
+
+    let mut buffer = [0u8; MAX_FRAME];
+    let len = match read_frame(&mut stream, &mut buffer) {
+        Some(len) => len,
+        None => return,
+    };
+    let id = audit.get().inbound("tcp", addr, &buffer[..len]);
+
+    if buffer[0] != logon::LOGON {
+        let mut audit = audit.get();
+        audit.dropped(id, "not logged on");
+        audit.outbound(id, "tcp", addr, &[logon::NOT_LOGGED_ON]);
+        write_frame(&mut stream, &[logon::NOT_LOGGED_ON]);
+        return;
+    }
+
+    let grant = match LogonRequest::from_bytes(&buffer[1..])
+        .and_then(|req| logons.get().logon(addr, &req))
+    {
+        Some(grant) => grant,
+        None => {
+            let mut audit = audit.get();
+            audit.dropped(id, "logon refused");
+            audit.outbound(id, "tcp", addr, &[logon::LOGON_RESPONSE, 1]);
+            write_frame(&mut stream, &[logon::LOGON_RESPONSE, 1]);
+            return;
+        }
+    };
+
+    let wstream = match stream.try_clone() {
+        Ok(wstream) => wstream,
+        Err(_) => {
+            logons.get().logout(grant.token);
+            return;
+        }
+    };
+    let (tx, rx) = channel();
+    audit.get().outbound(id, "tcp", addr, &grant.to_bytes());
+    tx.send(StreamMsg::Data(grant.to_bytes().to_vec(), id));
+    std::thread::spawn(move || writer(wstream, rx));
+
+    accept_logon(addr, &grant, &order_sender, id);
+    sessions.get().attach(addr, tx);
+
+    // heartbeats keep the connection going, the logon wouldn't outlive the silence anyway
+    stream.set_read_timeout(Some(logon::HEARTBEAT_TIMEOUT));
+    while let Some(len) = read_frame(&mut stream, &mut buffer) {
+        let id = audit.get().inbound("tcp", addr, &buffer[..len]);
+        if !logons.get().touch(grant.token) {
+            audit.get().dropped(id, "logon expired");
+            break;
+        }
+
+        match buffer[0] {
+            logon::LOGOUT => break,
+            logon::HEARTBEAT => {
+                let mut sessions = sessions.get();
+                sessions.correlate(id);
+                sessions.publish(addr, &[logon::HEARTBEAT]);
+                continue;
+            }
+            _ => {}
+        }
+
+        let is_mm = accounts
+            .get()
+            .of(&addr)
+            .is_some_and(|account| account.may_hide());
+
+        match parse_order(&buffer, is_mm) {
+            Some(order) => {
+                order_sender.send((addr, order, id));
+            }
+            None => audit.get().dropped(id, "unparseable order"),
+        }
+    }
+
+    let id = audit.get().closed("tcp", addr);
+    // a logon the exchange ended in the meantime took the session with it already
+    if logons.get().logout(grant.token).is_some() {
+        order_sender.send((addr, Order::Logout, id));
+        sessions.get().forget(&addr);
+    }
+}
+
+fn writer(mut stream: TcpStream, rx: Receiver<StreamMsg>) {
+    for msg in rx {
+        let msg = match msg {
+            StreamMsg::Data(msg, _) => msg,
+            StreamMsg::Processed(_) => continue,
+        };
+        if write_frame(&mut stream, &msg).is_err() {
+            break;
+        }
+    }
+    stream.shutdown(Shutdown::Both);
+}
@@ -0,0 +1,940 @@
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+use crate::account::Accounts;
+use crate::audit::Audit;
+use crate::crypto;
+use crate::http::{self, Request};
+use crate::json::Json;
+use crate::logon::Logons;
+use crate::marketdata;
+use crate::session::{Sessions, StreamMsg};
+use crate::tcp::{Slot, LOGON_TIMEOUT};
+use crate::{accept_logon, parse_order, MThread, Order};
+
+pub(crate) const WS_ADDR: &str = "0.0.0.0:14553";
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// browsers answer pings on their own, the pongs keep the logon alive
+const PING_INTERVAL: Duration = Duration::from_secs(3);
+const MAX_MESSAGE: usize = 64 * 1024;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Reads one frame, client frames have to be masked.
+fn read_frame(stream: &mut impl Read) -> std::io::Result<(bool, u8, Vec<u8>)> {
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head)?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0f;
+    if head[1] & 0x80 == 0 {
+        return Err(ErrorKind::InvalidData.into());
+    }
+
+    let len = match head[1] & 0x7f {
+        126 => {
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as usize
+        }
+        127 => {
+            let mut len = [0u8; 8];
+            stream.read_exact(&mut len)?;
+            u64::from_be_bytes(len) as usize
+        }
+        len => len as usize,
+    };
+    if len > MAX_MESSAGE {
+        return Err(ErrorKind::InvalidData.into());
+    }
+
+    let mut mask = [0u8; 4];
+    stream.read_exact(&mut mask)?;
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload)?;
+    payload
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, b)| *b ^= mask[i % 4]);
+
+    Ok((fin, opcode, payload))
+}
+
+fn write_frame(stream: &mut impl Write, opcode: u8, payload: &[u8]) -> std::io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes()[..]);
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes()[..]);
+        }
+    }
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame)
+}
+
+/// Sends `msg` to `addr` as a text message, caused by the message with correlation id `id`.
+fn send_json(
+    stream: &MThread<TcpStream>,
+    audit: &Audit,
+    id: u64,
+    addr: SocketAddr,
+    msg: &Json,
+) -> std::io::Result<()> {
+    let text = msg.to_string();
+    audit.get().outbound(id, "ws", addr, text.as_bytes());
+    write_frame(&mut *stream.get(), OP_TEXT, text.as_bytes())
+}
+
+/// `count` levels of the cycle depth, `[price f64][volume isize][orders u32]` each.
+fn levels(buf: &[u8], count: usize) -> Json {
+    Json::Array(
+        buf.chunks_exact(20)
+            .take(count)
+            .map(|level| {
+                Json::Array(vec![
+                    f64::from_le_bytes(level[0..8].try_into().unwrap()).into(),
+                    isize::from_le_bytes(level[8..16].try_into().unwrap()).into(),
+                    (u32::from_le_bytes(level[16..20].try_into().unwrap()) as isize).into(),
+                ])
+            })
+            .collect(),
+    )
+}
+
+const FEEDS: [(u8, &str); 6] = [
+    (marketdata::FEED_L1, "l1"),
+    (marketdata::FEED_L2, "l2"),
+    (marketdata::FEED_L3, "l3"),
+    (marketdata::FEED_TRADES, "trades"),
+    (marketdata::FEED_ACCOUNT, "account"),
+    (marketdata::FEED_STATS, "stats"),
+];
+
+/// One `[side u8][price f64][volume isize]` level of the depth feed.
+fn level(level: &[u8]) -> Json {
+    Json::object()
+        .with(
+            "side",
+            if level[0] == marketdata::BID {
+                "bid"
+            } else {
+                "ask"
+            },
+        )
+        .with("price", f64::from_le_bytes(level[1..9].try_into().unwrap()))
+        .with(
+            "volume",
+            isize::from_le_bytes(level[9..17].try_into().unwrap()),
+        )
+}
+
+/// One event of the order by order feed.
+fn order_event(event: &[u8]) -> Option<Json> {
+    let num = |at: usize| {
+        Some(isize::from_le_bytes(
+            event.get(at..at + 8)?.try_into().unwrap(),
+        ))
+    };
+    let float = |at: usize| {
+        Some(f64::from_le_bytes(
+            event.get(at..at + 8)?.try_into().unwrap(),
+        ))
+    };
+    let side = |at: usize| {
+        Some(match *event.get(at)? {
+            marketdata::BUY => "buy",
+            _ => "sell",
+        })
+    };
+
+    let event_json = Json::object()
+        .with("seq", num(1)? as u64)
+        .with("timestamp", num(9)? as u64);
+    Some(match *event.first()? {
+        b'A' => event_json
+            .with("type", "add")
+            .with("order_id", num(17)?)
+            .with("side", side(25)?)
+            .with("amount", num(26)?)
+            .with("price", float(34)?),
+        b'E' => event_json
+            .with("type", "executed")
+            .with("order_id", num(17)?)
+            .with("amount", num(25)?)
+            .with("match_id", num(33)? as u64),
+        b'X' => event_json
+            .with("type", "cancel")
+            .with("order_id", num(17)?)
+            .with("amount", num(25)?),
+        b'U' => event_json
+            .with("type", "replace")
+            .with("order_id", num(17)?)
+            .with("new_order_id", num(25)?)
+            .with("amount", num(33)?)
+            .with("price", float(41)?),
+        b'P' => event_json
+            .with("type", "trade")
+            .with("side", side(17)?)
+            .with("amount", num(18)?)
+            .with("price", float(26)?)
+            .with("match_id", num(34)? as u64),
+        _ => None?,
+    })
+}
+
+/// One `[trade id u64][timestamp u64][price f64][amount isize][aggressor u8]` of the trades
+/// feed.
+fn trade(trade: &[u8]) -> Json {
+    let num = |at: usize| u64::from_le_bytes(trade[at..at + 8].try_into().unwrap());
+    Json::object()
+        .with("trade_id", num(0))
+        .with("timestamp", num(8))
+        .with(
+            "price",
+            f64::from_le_bytes(trade[16..24].try_into().unwrap()),
+        )
+        .with("amount", num(24) as isize)
+        .with(
+            "aggressor",
+            match trade[32] {
+                marketdata::BUY => "buy",
+                marketdata::SELL => "sell",
+                _ => "auction",
+            },
+        )
+}
+
+/// Translates one message of the binary protocol into its JSON form.
+fn to_json(data: &[u8]) -> Option<Json> {
+    let num = |at: usize| isize::from_le_bytes(data[at..at + 8].try_into().unwrap());
+    let float = |at: usize| f64::from_le_bytes(data[at..at + 8].try_into().unwrap());
+
+    Some(match *data.first()? {
+        0x01 if data.len() >= 18 => match data[1] {
+            0 => Json::object()
+                .with("type", "accepted")
+                .with("order_id", num(2)),
+            // only sent unsolicited, when the admin pulled the order
+            1 => Json::object()
+                .with("type", "canceled")
+                .with("order_id", num(3)),
+            2 => Json::object()
+                .with("type", "execution")
+                .with("order_id", Json::Null)
+                .with("amount", num(2))
+                .with("price", float(10)),
+            _ => None?,
+        },
+        0x20 if data.len() >= 25 => Json::object()
+            .with("type", "execution")
+            .with("order_id", num(1))
+            .with("amount", num(9))
+            .with("price", float(17)),
+        0x21 if data.len() >= 57 => Json::object()
+            .with("type", "account")
+            .with("money", float(1))
+            .with("net_liquidity_contribution", num(9))
+            .with("position", num(17))
+            .with("cost_basis", float(25))
+            .with("realized_pnl", float(33))
+            .with("unrealized_pnl", float(41))
+            .with("equity", float(49)),
+        0x22 if data.len() >= 10 => Json::object()
+            .with("type", "transfer")
+            .with("amount", float(1))
+            .with(
+                "counterparty",
+                std::str::from_utf8(data.get(10..10 + data[9] as usize)?).ok()?,
+            ),
+        marketdata::TOP_OF_BOOK if data.len() >= 57 => Json::object()
+            .with("type", "top_of_book")
+            .with("seq", num(1))
+            .with("bid", float(9))
+            .with("bid_size", num(17))
+            .with("ask", float(25))
+            .with("ask_size", num(33))
+            .with("last", float(41))
+            .with("last_size", num(49)),
+        marketdata::DEPTH_DELTA if data.len() >= 11 => Json::object()
+            .with("type", "depth_delta")
+            .with("seq", num(1))
+            .with(
+                "changes",
+                data[11..]
+                    .chunks_exact(18)
+                    .map(|change| {
+                        level(&change[1..]).with(
+                            "action",
+                            match change[0] {
+                                marketdata::ADD => "add",
+                                marketdata::UPDATE => "update",
+                                _ => "delete",
+                            },
+                        )
+                    })
+                    .collect::<Vec<_>>(),
+            ),
+        marketdata::DEPTH_SNAPSHOT if data.len() >= 15 => Json::object()
+            .with("type", "depth_snapshot")
+            .with("seq", num(1))
+            .with("fragment", u16::from_le_bytes([data[9], data[10]]) as isize)
+            .with(
+                "fragments",
+                u16::from_le_bytes([data[11], data[12]]) as isize,
+            )
+            .with(
+                "levels",
+                data[15..].chunks_exact(17).map(level).collect::<Vec<_>>(),
+            ),
+        marketdata::ORDER_EVENTS if data.len() >= 3 => {
+            let mut events = Vec::new();
+            let mut at = 3;
+            while at + 2 <= data.len() {
+                let len = u16::from_le_bytes([data[at], data[at + 1]]) as usize;
+                events.push(order_event(data.get(at + 2..at + 2 + len)?)?);
+                at += 2 + len;
+            }
+            Json::object()
+                .with("type", "order_events")
+                .with("events", events)
+        }
+        marketdata::TRADES if data.len() >= 3 => Json::object().with("type", "trades").with(
+            "trades",
+            data[3..].chunks_exact(33).map(trade).collect::<Vec<_>>(),
+        ),
+        crate::stats::BAR if data.len() >= 86 => Json::object()
+            .with("type", "bar")
+            .with(
+                "interval_ms",
+                u32::from_le_bytes(data[1..5].try_into().unwrap()) as isize,
+            )
+            .with("start", num(5) as u64)
+            .with("end", num(13) as u64)
+            .with("open", float(21))
+            .with("high", float(29))
+            .with("low", float(37))
+            .with("close", float(45))
+            .with("volume", num(53))
+            .with("vwap", float(61))
+            .with("trades", num(69) as u64)
+            .with("turnover", float(77))
+            .with("closed", data[85] != 0),
+        marketdata::DEPTH if data.len() >= 25 => {
+            let field = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]) as usize;
+            let (bids, asks) = (field(21), field(23));
+            Json::object()
+                .with("type", "depth")
+                .with("seq", num(1))
+                .with("timestamp", num(9))
+                .with("fragment", field(17) as isize)
+                .with("fragments", field(19) as isize)
+                .with("bids", levels(&data[25..], bids))
+                .with("asks", levels(data.get(25 + bids * 20..)?, asks))
+        }
+        marketdata::SUBSCRIPTION if data.len() >= 3 => Json::object()
+            .with("type", "subscription")
+            .with(
+                "feed",
+                FEEDS
+                    .iter()
+                    .find(|(feed, _)| *feed == data[1])
+                    .map(|(_, name)| *name),
+            )
+            .with(
+                "status",
+                match data[2] {
+                    marketdata::SUBSCRIBED => "ok",
+                    marketdata::UNKNOWN_INSTRUMENT => "unknown instrument",
+                    marketdata::UNKNOWN_FEED => "unknown feed",
+                    marketdata::MULTICAST_ONLY => "multicast only",
+                    _ => "bad parameters",
+                },
+            ),
+        marketdata::RECOVERY if data.len() >= 19 => Json::object()
+            .with("type", "recovery")
+            .with(
+                "feed",
+                FEEDS
+                    .iter()
+                    .find(|(feed, _)| *feed == data[1])
+                    .map(|(_, name)| *name),
+            )
+            .with(
+                "status",
+                match data[2] {
+                    marketdata::RECOVERED => "ok",
+                    marketdata::UNKNOWN_INSTRUMENT => "unknown instrument",
+                    marketdata::UNKNOWN_FEED => "unknown feed",
+                    marketdata::UNAVAILABLE => "unavailable",
+                    _ => "bad parameters",
+                },
+            )
+            .with("first", num(3))
+            .with("last", num(11)),
+        0xe0 => Json::object().with("type", "canceled"),
+        reject @ 0xfa..=0xff => Json::object().with("type", "rejected").with(
+            "order",
+            match reject {
+                0xff => "limit",
+                0xfe => "market",
+                0xfd => "cancel",
+                0xfc => "hidden",
+                0xfb => "replace",
+                _ => "transfer",
+            },
+        ),
+        crate::logon::HEARTBEAT => Json::object().with("type", "heartbeat"),
+        0x69 => Json::object().with("type", "dropped"),
+        _ => Json::object()
+            .with("type", "message")
+            .with("text", std::str::from_utf8(data).ok()?),
+    })
+}
+
+/// Builds the binary form of a JSON order so it goes through the same parsers:
+/// `{"type": "limit", "price": 1.5, "amount": -10}`, `{"type": "market", "amount": 10}`,
+/// `{"type": "cancel", "order_id": 42}`, `{"type": "hidden", "price": 1.5, "amount": 10}` and
+/// `{"type": "replace", "order_id": 42, "price": 1.4, "amount": -5}`. Money goes to another
+/// account with `{"type": "transfer", "to": "bob", "amount": 12.5}`. Subscriptions are
+/// `{"type": "subscribe", "feed": "l2", "depth": 10, "throttle_ms": 250}` with an optional
+/// `"instrument"` and `{"type": "unsubscribe", "feed": "l3"}`. Missed market data is
+/// `{"type": "recover", "feed": "l2", "from": 120, "to": 125}`, a fresh snapshot
+/// `{"type": "snapshot", "feed": "l2"}`, both with an optional `"instrument"` as well.
+fn order_from_json(msg: &Json, is_mm: bool) -> Option<Order> {
+    let mut buffer = [0u8; crate::tcp::MAX_FRAME];
+    let amount = msg.get("amount").and_then(Json::as_isize);
+    let price = msg.get("price").and_then(Json::as_f64);
+
+    let instrument = match msg.get("instrument") {
+        Some(instrument) => instrument.as_str()?,
+        None => marketdata::INSTRUMENT,
+    };
+    if instrument.len() > 8 {
+        return None;
+    }
+    let feed = msg
+        .get("feed")
+        .and_then(Json::as_str)
+        .and_then(|feed| FEEDS.iter().find(|(_, name)| *name == feed))
+        .map(|(feed, _)| *feed);
+
+    match msg.get("type")?.as_str()? {
+        "limit" => {
+            buffer[0] = 0;
+            buffer[1..9].copy_from_slice(&price?.to_le_bytes()[..]);
+            buffer[9..17].copy_from_slice(&amount?.to_le_bytes()[..]);
+        }
+        "market" => {
+            buffer[0] = 1;
+            buffer[1..9].copy_from_slice(&amount?.to_le_bytes()[..]);
+        }
+        "cancel" => {
+            buffer[0] = 2;
+            let order_id = msg.get("order_id")?.as_isize()?;
+            buffer[1..9].copy_from_slice(&order_id.to_le_bytes()[..]);
+        }
+        "hidden" => {
+            buffer[0] = 3;
+            buffer[1..9].copy_from_slice(&amount?.to_le_bytes()[..]);
+            buffer[9..17].copy_from_slice(&price?.to_le_bytes()[..]);
+        }
+        "replace" => {
+            buffer[0] = 4;
+            let order_id = msg.get("order_id")?.as_isize()?;
+            buffer[1..9].copy_from_slice(&order_id.to_le_bytes()[..]);
+            buffer[9..17].copy_from_slice(&price?.to_le_bytes()[..]);
+            buffer[17..25].copy_from_slice(&amount?.to_le_bytes()[..]);
+        }
+        "transfer" => {
+            buffer[0] = 9;
+            let amount = msg.get("amount")?.as_f64()?;
+            let to = msg.get("to")?.as_str()?;
+            buffer[1..9].copy_from_slice(&amount.to_le_bytes()[..]);
+            buffer[9] = u8::try_from(to.len()).ok()?;
+            buffer[10..10 + to.len()].copy_from_slice(to.as_bytes());
+        }
+        kind @ ("subscribe" | "unsubscribe") => {
+            buffer[0] = if kind == "subscribe" { 5 } else { 6 };
+            buffer[1..1 + instrument.len()].copy_from_slice(instrument.as_bytes());
+            buffer[9] = feed?;
+            let depth = msg.get("depth").map_or(Some(0), Json::as_isize)?;
+            let throttle = msg.get("throttle_ms").map_or(Some(0), Json::as_isize)?;
+            buffer[10..12].copy_from_slice(&u16::try_from(depth).ok()?.to_le_bytes()[..]);
+            buffer[12..16].copy_from_slice(&u32::try_from(throttle).ok()?.to_le_bytes()[..]);
+        }
+        kind @ ("recover" | "snapshot") => {
+            buffer[0] = if kind == "recover" { 7 } else { 8 };
+            buffer[1..1 + instrument.len()].copy_from_slice(instrument.as_bytes());
+            buffer[9] = feed?;
+            if kind == "recover" {
+                let from = u64::try_from(msg.get("from")?.as_isize()?).ok()?;
+                let to = u64::try_from(msg.get("to")?.as_isize()?).ok()?;
+                buffer[10..18].copy_from_slice(&from.to_le_bytes()[..]);
+                buffer[18..26].copy_from_slice(&to.to_le_bytes()[..]);
+            }
+        }
+        _ => None?,
+    }
+
+    parse_order(&buffer, is_mm)
+}
+
+/// Websocket gateway for browsers. After the handshake the first message has to be
+/// `{"type": "logon", "account": ..., "password": ...}`, from then on the connection gets
+/// everything a udp client would get (depth, executions, account updates) as JSON text
+/// messages and can submit orders as JSON.
+pub(crate) fn listen(
+    listener: TcpListener,
+    logons: Logons,
+    sessions: Sessions,
+    accounts: Accounts,
+    audit: Audit,
+    order_sender: Sender<(SocketAddr, Order, u64)>,
+) {
+    let open = MThread::new(0);
+    for stream in listener.incoming().flatten() {
+        let slot = match Slot::take(&open) {
+            Some(slot) => slot,
+            None => continue,
+        };
+        let logons = logons.clone();
+        let sessions = sessions.clone();
+        let accounts = accounts.clone();
+        let (audit, order_sender) = (audit.clone(), order_sender.clone());
+        std::thread::spawn(move || {
+            let _slot = slot;
+            connection(stream, logons, sessions, accounts, audit, order_sender)
+        });
+    }
+}
+
+fn handshake(reader: &mut BufReader<TcpStream>) -> std::io::Result<bool> {
+    let req = match Request::read(reader) {
+        Some(req) => req,
+        None => return Ok(false),
+    };
+
+    let key = match req.header("sec-websocket-key") {
+        Some(key)
+            if req.method == "GET"
+                && req
+                    .header("upgrade")
+                    .is_some_and(|u| u.eq_ignore_ascii_case("websocket")) =>
+        {
+            key
+        }
+        _ => {
+            http::respond(
+                reader.get_mut(),
+                400,
+                "Bad Request",
+                "text/plain",
+                b"websocket only\n",
+            )?;
+            return Ok(false);
+        }
+    };
+
+    let accept = base64(&crypto::sha1(format!("{}{}", key, GUID).as_bytes()));
+    write!(
+        reader.get_mut(),
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept
+    )?;
+    Ok(true)
+}
+
+/// Reads the next complete data message, answering pings on the way. Returns it with the
+/// correlation id it got in the audit trail.
+fn read_message(
+    reader: &mut BufReader<TcpStream>,
+    wstream: &MThread<TcpStream>,
+    logons: &Logons,
+    token: Option<u64>,
+    audit: &Audit,
+    addr: SocketAddr,
+) -> Option<(u64, Json)> {
+    let mut message = Vec::new();
+    loop {
+        let (fin, opcode, payload) = read_frame(reader).ok()?;
+        if token.is_some_and(|token| !logons.get().touch(token)) {
+            return None;
+        }
+
+        match opcode {
+            OP_PING => {
+                write_frame(&mut *wstream.get(), OP_PONG, &payload).ok()?;
+                continue;
+            }
+            OP_PONG => continue,
+            OP_CLOSE => {
+                write_frame(&mut *wstream.get(), OP_CLOSE, &[]);
+                return None;
+            }
+            OP_TEXT | OP_BINARY | OP_CONTINUATION => {}
+            _ => return None,
+        }
+
+        message.extend_from_slice(&payload);
+        if message.len() > MAX_MESSAGE {
+            let id = audit.get().inbound("ws", addr, &message);
+            audit.get().dropped(id, "message too long");
+            return None;
+        }
+        if fin {
+            // anything that isn't valid JSON is answered with a null, so the caller can reject it
+            let msg = std::str::from_utf8(&message)
+                .ok()
+                .and_then(Json::parse)
+                .unwrap_or(Json::Null);
+            let id = match redacted(&msg) {
+                Some(logged) => audit
+                    .get()
+                    .inbound("ws", addr, logged.to_string().as_bytes()),
+                None => audit.get().inbound("ws", addr, &message),
+            };
+            return Some((id, msg));
+        }
+    }
+}
+
+/// What of a message with a `"password"` goes into the audit trail, `None` if there is none.
+fn redacted(msg: &Json) -> Option<Json> {
+    match msg {
+        Json::Object(members) if members.iter().any(|(key, _)| key == "password") => {
+            let members = members
+                .iter()
+                .map(|(key, value)| match key.as_str() {
+                    "password" => (key.clone(), Json::from("***")),
+                    _ => (key.clone(), value.clone()),
+                })
+                .collect();
+            Some(Json::Object(members))
+        }
+        _ => None,
+    }
+}
+
+fn connection(
+    stream: TcpStream,
+    logons: Logons,
+    sessions: Sessions,
+    accounts: Accounts,
+    audit: Audit,
+    order_sender: Sender<(SocketAddr, Order, u64)>,
+) {
+    let addr = match stream.peer_addr() {
+        Ok(addr) => addr,
+        Err(_) => return,
+    };
+    let wstream = match stream.try_clone() {
+        Ok(wstream) => MThread::new(wstream),
+        Err(_) => return,
+    };
+    // the handshake and the logon together
+    if stream.set_read_timeout(Some(LOGON_TIMEOUT)).is_err() {
+        return;
+    }
+    let mut reader = BufReader::new(stream);
+
+    if !handshake(&mut reader).unwrap_or(false) {
+        return;
+    }
+
+    let (id, logon) = match read_message(&mut reader, &wstream, &logons, None, &audit, addr) {
+        Some(logon) => logon,
+        None => return,
+    };
+    let grant = match (
+        logon.get("type").and_then(Json::as_str),
+        logon.get("account").and_then(Json::as_str),
+        logon.get("password").and_then(Json::as_str),
+    ) {
+        (Some("logon"), Some(account), Some(password)) => {
+            logons.get().logon_password(addr, account, password)
+        }
+        _ => None,
+    };
+    let grant = match grant {
+        Some(grant) => grant,
+        None => {
+            audit.get().dropped(id, "logon refused");
+            send_json(
+                &wstream,
+                &audit,
+                id,
+                addr,
+                &Json::object()
+                    .with("type", "logon")
+                    .with("status", "rejected"),
+            );
+            write_frame(&mut *wstream.get(), OP_CLOSE, &[]);
+            return;
+        }
+    };
+
+    if send_json(
+        &wstream,
+        &audit,
+        id,
+        addr,
+        &Json::object().with("type", "logon").with("status", "ok"),
+    )
+    .is_err()
+    {
+        logons.get().logout(grant.token);
+        return;
+    }
+
+    let (tx, rx) = channel();
+    let (writer_stream, writer_audit) = (wstream.clone(), audit.clone());
+    std::thread::spawn(move || writer(writer_stream, writer_audit, addr, rx));
+
+    accept_logon(addr, &grant, &order_sender, id);
+    sessions.get().attach(addr, tx);
+
+    // the pongs to the writer's pings come in well before this
+    reader
+        .get_ref()
+        .set_read_timeout(Some(crate::logon::HEARTBEAT_TIMEOUT));
+    let token = Some(grant.token);
+    while let Some((id, msg)) = read_message(&mut reader, &wstream, &logons, token, &audit, addr) {
+        match msg.get("type").and_then(Json::as_str) {
+            Some("logout") => break,
+            Some("heartbeat") => {
+                let mut sessions = sessions.get();
+                sessions.correlate(id);
+                sessions.publish(addr, &[crate::logon::HEARTBEAT]);
+                continue;
+            }
+            _ => {}
+        }
+
+        let is_mm = accounts
+            .get()
+            .of(&addr)
+            .is_some_and(|account| account.may_hide());
+
+        match order_from_json(&msg, is_mm) {
+            Some(order) => {
+                order_sender.send((addr, order, id));
+            }
+            None => {
+                audit.get().dropped(id, "invalid order");
+                send_json(
+                    &wstream,
+                    &audit,
+                    id,
+                    addr,
+                    &Json::object()
+                        .with("type", "rejected")
+                        .with("reason", "invalid order"),
+                );
+            }
+        }
+    }
+
+    let id = audit.get().closed("ws", addr);
+    // a logon the exchange ended in the meantime took the session with it already
+    if logons.get().logout(grant.token).is_some() {
+        order_sender.send((addr, Order::Logout, id));
+    }
+    // dropping the writers sender makes it close the connection
+    sessions.get().forget(&addr);
+}
+
+fn writer(stream: MThread<TcpStream>, audit: Audit, addr: SocketAddr, rx: Receiver<StreamMsg>) {
+    // pings go out on their own clock, a dashboard that only watches gets pushed to every
+    // cycle but still has to pong
+    let mut last_ping = Instant::now();
+    loop {
+        let wait = PING_INTERVAL.saturating_sub(last_ping.elapsed());
+        let sent = match rx.recv_timeout(wait) {
+            Ok(StreamMsg::Data(data, id)) => match to_json(&data) {
+                Some(msg) => send_json(&stream, &audit, id, addr, &msg),
+                None => Ok(()),
+            },
+            Ok(StreamMsg::Processed(_)) => Ok(()),
+            Err(RecvTimeoutError::Timeout) => Ok(()),
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let sent = sent.and_then(|_| {
+            if last_ping.elapsed() >= PING_INTERVAL {
+                last_ping = Instant::now();
+                write_frame(&mut *stream.get(), OP_PING, &[])?;
+            }
+            Ok(())
+        });
+        if sent.is_err() {
+            break;
+        }
+    }
+
+    let stream = stream.get();
+    write_frame(&mut &*stream, OP_CLOSE, &[]);
+    stream.shutdown(Shutdown::Both);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A client frame with `payload` masked under `mask`.
+    fn masked(head: u8, mask: [u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![head];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes()[..]);
+            }
+        }
+        frame.extend_from_slice(&mask[..]);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    // RFC 6455, 5.7
+    #[test]
+    fn unmasks_client_frames() {
+        let hello = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let (fin, opcode, payload) = read_frame(&mut &hello[..]).unwrap();
+        assert!(fin);
+        assert_eq!(opcode, OP_TEXT);
+        assert_eq!(payload, b"Hello");
+
+        let long = vec![0x5a; 300];
+        let frame = masked(OP_BINARY, [1, 2, 3, 4], &long);
+        let (fin, opcode, payload) = read_frame(&mut &frame[..]).unwrap();
+        assert!(!fin);
+        assert_eq!(opcode, OP_BINARY);
+        assert_eq!(payload, long);
+    }
+
+    #[test]
+    fn refuses_unmasked_and_oversized_frames() {
+        let hello = [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+        assert_eq!(
+            read_frame(&mut &hello[..]).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+
+        let mut huge = vec![0x82, 0x80 | 127];
+        huge.extend_from_slice(&(MAX_MESSAGE as u64 + 1).to_be_bytes()[..]);
+        huge.extend_from_slice(&[0; 4]);
+        assert_eq!(
+            read_frame(&mut &huge[..]).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+
+        let cut = masked(0x81, [9, 9, 9, 9], b"Hello");
+        assert!(read_frame(&mut &cut[..cut.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn server_frames_are_unmasked() {
+        let mut frame = Vec::new();
+        write_frame(&mut frame, OP_TEXT, b"Hello").unwrap();
+        assert_eq!(frame, [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f]);
+
+        let mut frame = Vec::new();
+        write_frame(&mut frame, OP_BINARY, &[0; 256]).unwrap();
+        assert_eq!(frame[..4], [0x82, 0x7e, 0x01, 0x00]);
+        assert_eq!(frame.len(), 4 + 256);
+
+        let mut frame = Vec::new();
+        write_frame(&mut frame, OP_BINARY, &[0; 65536]).unwrap();
+        assert_eq!(frame[..10], [0x82, 0x7f, 0, 0, 0, 0, 0, 1, 0, 0]);
+    }
+
+    // RFC 6455, 1.3
+    #[test]
+    fn accept_key() {
+        let key = "dGhlIHNhbXBsZSBub25jZQ==";
+        assert_eq!(
+            base64(&crypto::sha1(format!("{}{}", key, GUID).as_bytes())),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    // RFC 4648, 10 and FIPS 180-2, appendix A
+    #[test]
+    fn base64_and_sha1_vectors() {
+        let encoded: Vec<String> = ["", "f", "fo", "foo", "foob", "fooba", "foobar"]
+            .iter()
+            .map(|s| base64(s.as_bytes()))
+            .collect();
+        assert_eq!(
+            encoded,
+            ["", "Zg==", "Zm8=", "Zm9v", "Zm9vYg==", "Zm9vYmE=", "Zm9vYmFy"]
+        );
+
+        let hex: String = crypto::sha1(b"abc")
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(hex, "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+
+    #[test]
+    fn redacts_the_password() {
+        let logon = Json::parse(r#"{"type": "logon", "account": "bob", "password": "pw"}"#);
+        assert_eq!(
+            redacted(&logon.unwrap()).unwrap().to_string(),
+            r#"{"type":"logon","account":"bob","password":"***"}"#
+        );
+        let order = Json::parse(r#"{"type": "market", "amount": 10}"#).unwrap();
+        assert_eq!(redacted(&order), None);
+    }
+
+    #[test]
+    fn translates_json_orders() {
+        let order = |text: &str| order_from_json(&Json::parse(text).unwrap(), false);
+        assert!(matches!(
+            order(r#"{"type": "limit", "price": 1.5, "amount": -10}"#),
+            Some(Order::Lmt(_))
+        ));
+        assert!(matches!(
+            order(r#"{"type": "transfer", "to": "bob", "amount": 12.5}"#),
+            Some(Order::Transfer(_))
+        ));
+        assert!(order(r#"{"type": "limit", "amount": -10}"#).is_none());
+        assert!(order(r#"{"type": "teleport"}"#).is_none());
+        let to = "b".repeat(256);
+        let transfer = format!(r#"{{"type": "transfer", "to": "{}", "amount": 1}}"#, to);
+        assert!(order(&transfer).is_none());
+    }
+}